@@ -0,0 +1,115 @@
+//! Allocation counting, compiled in with `--features alloc-stats`.
+//!
+//! A global allocator wrapping the system one counts allocations and bytes
+//! per role. Threads of the server runtime mark themselves as server threads,
+//! every other thread counts as client.
+
+use std::{
+    cell::Cell,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+const CLIENT: usize = 0;
+const SERVER: usize = 1;
+
+static ALLOCATIONS: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+static BYTES: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+thread_local! {
+    static ROLE: Cell<usize> = const { Cell::new(CLIENT) };
+}
+
+#[cfg(feature = "alloc-stats")]
+mod counting {
+    use {
+        super::*,
+        std::alloc::{GlobalAlloc, Layout, System},
+    };
+
+    struct CountingAllocator;
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn count(size: usize) {
+        // The thread local is gone while the thread is torn down.
+        let role = ROLE.try_with(|role| role.get()).unwrap_or(CLIENT);
+        ALLOCATIONS[role].fetch_add(1, Ordering::Relaxed);
+        BYTES[role].fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count(layout.size());
+            System.alloc(layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            count(layout.size());
+            System.alloc_zeroed(layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            count(new_size);
+            System.realloc(ptr, layout, new_size)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+}
+
+/// Account the allocations of the calling thread to the server.
+pub(crate) fn mark_server_thread() {
+    ROLE.with(|role| role.set(SERVER));
+}
+
+/// Allocation counters at one point in time.
+#[derive(Clone, Copy)]
+pub(crate) struct AllocSnapshot {
+    allocations: [u64; 2],
+    bytes: [u64; 2],
+}
+
+impl AllocSnapshot {
+    /// Current counters, `None` unless built with the counting allocator.
+    pub(crate) fn take() -> Option<Self> {
+        if !cfg!(feature = "alloc-stats") {
+            return None;
+        }
+        let load =
+            |counters: &[AtomicU64; 2]| counters.each_ref().map(|c| c.load(Ordering::Relaxed));
+        Some(Self {
+            allocations: load(&ALLOCATIONS),
+            bytes: load(&BYTES),
+        })
+    }
+
+    /// Allocations made between `earlier` and `self`.
+    pub(crate) fn since(&self, earlier: &Self) -> AllocDelta {
+        AllocDelta {
+            allocations: [0, 1].map(|i| self.allocations[i] - earlier.allocations[i]),
+            bytes: [0, 1].map(|i| self.bytes[i] - earlier.bytes[i]),
+        }
+    }
+}
+
+pub(crate) struct AllocDelta {
+    allocations: [u64; 2],
+    bytes: [u64; 2],
+}
+
+impl fmt::Display for AllocDelta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "client {} allocations ({} bytes), server {} allocations ({} bytes)",
+            self.allocations[CLIENT],
+            self.bytes[CLIENT],
+            self.allocations[SERVER],
+            self.bytes[SERVER],
+        )
+    }
+}
@@ -0,0 +1,102 @@
+//! Packet capture triggered by anomalies with `--pcap-on-anomaly`. Capturing
+//! a multi-hour run in full is infeasible, so a capture command runs only for
+//! a few seconds after a response takes longer than the latency threshold or
+//! a request fails. Anomalies during a capture do not start another one.
+
+use {
+    std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+    tokio::{process::Command, sync::mpsc, time},
+    tracing::*,
+};
+
+pub(crate) struct AnomalyTrigger {
+    latency_threshold: Duration,
+    /// Set while a capture runs.
+    capturing: Arc<AtomicBool>,
+    triggers: mpsc::Sender<String>,
+}
+
+impl AnomalyTrigger {
+    /// Run `command` through the shell for `capture` after every anomaly.
+    /// `{time}` in the command is replaced by the Unix time of the anomaly,
+    /// to name the capture files.
+    pub(crate) fn spawn(
+        command: String,
+        latency_threshold: Duration,
+        capture: Duration,
+    ) -> Arc<Self> {
+        let (triggers, mut receiver) = mpsc::channel::<String>(1);
+        let capturing = Arc::new(AtomicBool::new(false));
+        let finished = capturing.clone();
+        tokio::spawn(async move {
+            while let Some(reason) = receiver.recv().await {
+                run_capture(&command, &reason, capture).await;
+                finished.store(false, Ordering::Relaxed);
+            }
+        });
+        Arc::new(Self {
+            latency_threshold,
+            capturing,
+            triggers,
+        })
+    }
+
+    pub(crate) fn latency(&self, latency: Duration) {
+        if latency > self.latency_threshold {
+            self.fire(|| format!("response latency {latency:?}"));
+        }
+    }
+
+    pub(crate) fn error(&self, reason: &str) {
+        self.fire(|| reason.to_string());
+    }
+
+    fn fire(&self, reason: impl FnOnce() -> String) {
+        if !self.capturing.swap(true, Ordering::Relaxed) {
+            let _ = self.triggers.try_send(reason());
+        }
+    }
+}
+
+async fn run_capture(command: &str, reason: &str, capture: Duration) {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let command = command.replace("{time}", &time.to_string());
+    warn!("Anomaly: {reason}, capturing for {capture:?} with {command:?}");
+    // exec, so the signal ending the capture reaches the capture tool.
+    let mut child = match Command::new("sh")
+        .arg("-c")
+        .arg(format!("exec {command}"))
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) => {
+            error!("Failed to start the capture command: {err}");
+            return;
+        }
+    };
+    if time::timeout(capture, child.wait()).await.is_ok() {
+        warn!("Capture command exited before the capture time");
+        return;
+    }
+    // Capture tools flush their output on SIGTERM, not on SIGKILL.
+    if let Some(pid) = child.id() {
+        // SAFETY: plain signal delivery to our own child process.
+        unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+    }
+    if time::timeout(Duration::from_secs(5), child.wait())
+        .await
+        .is_err()
+    {
+        let _ = child.kill().await;
+    }
+    info!("Capture for {reason} finished");
+}
@@ -0,0 +1,87 @@
+//! Startup calibration of the latency measurement floor: what taking the two
+//! timestamps of a request and handing a response from one task to another
+//! cost on this machine, with no network involved. Reported latencies are
+//! annotated with this floor, and given net of it, so the tool is honest
+//! about how small a latency it can measure.
+
+use {
+    crate::clock::Clock,
+    std::{fmt, time::Duration},
+    tokio::sync::mpsc,
+};
+
+/// Samples taken of every overhead, the median is used.
+const SAMPLES: usize = 1000;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct MeasurementFloor {
+    /// A start timestamp followed by the elapsed time since it.
+    timestamps: Duration,
+    /// Waking a task with a message sent from another task, as the
+    /// connection driver wakes the response reader.
+    handoff: Duration,
+}
+
+fn median(samples: &mut [Duration]) -> Duration {
+    samples.sort_unstable();
+    samples[samples.len() / 2]
+}
+
+impl MeasurementFloor {
+    pub(crate) async fn measure(clock: Clock) -> Self {
+        let mut timestamps: Vec<_> = (0..SAMPLES).map(|_| clock.since(clock.now())).collect();
+
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let (done_sender, mut done) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(async move {
+            while let Some(sent) = receiver.recv().await {
+                if done_sender.send(clock.since(sent)).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut handoff = Vec::with_capacity(SAMPLES);
+        for _ in 0..SAMPLES {
+            if sender.send(clock.now()).is_err() {
+                break;
+            }
+            match done.recv().await {
+                Some(latency) => handoff.push(latency),
+                None => break,
+            }
+        }
+        drop(sender);
+        let _ = reader.await;
+
+        Self {
+            timestamps: median(&mut timestamps),
+            handoff: if handoff.is_empty() {
+                Duration::ZERO
+            } else {
+                median(&mut handoff)
+            },
+        }
+    }
+
+    /// Latency every request is measured with at least.
+    pub(crate) fn total(&self) -> Duration {
+        self.timestamps + self.handoff
+    }
+
+    /// `latency_us` less the floor, in microseconds.
+    pub(crate) fn net_us(&self, latency_us: u64) -> f64 {
+        (latency_us as f64 - self.total().as_nanos() as f64 / 1000.0).max(0.0)
+    }
+}
+
+impl fmt::Display for MeasurementFloor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.2}us ({}ns timestamps, {:.2}us task handoff)",
+            self.total().as_nanos() as f64 / 1000.0,
+            self.timestamps.as_nanos(),
+            self.handoff.as_nanos() as f64 / 1000.0,
+        )
+    }
+}
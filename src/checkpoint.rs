@@ -0,0 +1,78 @@
+//! Checkpoints of long runs with `--checkpoint`: the cumulative totals and
+//! latency histogram of the streams workload are written to a file
+//! periodically and at the end of the run, and `--resume` continues from the
+//! checkpoint of an interrupted run. The summary of a resumed run covers all
+//! of its segments; the interval reports, rates and the Little's law check
+//! cover the current segment only.
+
+use {
+    crate::histogram::Histogram,
+    anyhow::{Context, Result},
+    serde::{Deserialize, Serialize},
+    std::{fs, path::Path},
+    tracing::*,
+};
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Checkpoint {
+    /// Segments of the run covered, one more with every resume.
+    pub(crate) segments: usize,
+    pub(crate) sent: usize,
+    pub(crate) received: usize,
+    pub(crate) expired: usize,
+    pub(crate) late: usize,
+    pub(crate) corrupted: usize,
+    pub(crate) bytes_sent: usize,
+    pub(crate) bytes_received: usize,
+    pub(crate) duration_secs: f64,
+    pub(crate) latency: Histogram,
+}
+
+impl Checkpoint {
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let contents =
+            fs::read(path).with_context(|| format!("reading checkpoint {}", path.display()))?;
+        let checkpoint: Self = serde_json::from_slice(&contents)
+            .with_context(|| format!("parsing checkpoint {}", path.display()))?;
+        info!(
+            "Resuming from {} after {} segments: {} sent, {} received in {:.0}s, latency {}",
+            path.display(),
+            checkpoint.segments,
+            checkpoint.sent,
+            checkpoint.received,
+            checkpoint.duration_secs,
+            checkpoint.latency,
+        );
+        Ok(checkpoint)
+    }
+
+    /// Write to `path` through a temporary file, so an interruption leaves
+    /// the previous checkpoint intact.
+    pub(crate) fn write(&self, path: &Path) -> Result<()> {
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        fs::write(&temporary, serde_json::to_vec(self)?)
+            .with_context(|| format!("writing checkpoint {}", path.display()))?;
+        fs::rename(&temporary, path)
+            .with_context(|| format!("writing checkpoint {}", path.display()))?;
+        Ok(())
+    }
+
+    /// The totals of this checkpoint followed by those of `segment`.
+    pub(crate) fn then(&self, segment: &Checkpoint) -> Checkpoint {
+        let mut latency = self.latency.clone();
+        latency.merge(&segment.latency);
+        Checkpoint {
+            segments: self.segments + 1,
+            sent: self.sent + segment.sent,
+            received: self.received + segment.received,
+            expired: self.expired + segment.expired,
+            late: self.late + segment.late,
+            corrupted: self.corrupted + segment.corrupted,
+            bytes_sent: self.bytes_sent + segment.bytes_sent,
+            bytes_received: self.bytes_received + segment.bytes_received,
+            duration_secs: self.duration_secs + segment.duration_secs,
+            latency,
+        }
+    }
+}
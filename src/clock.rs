@@ -0,0 +1,188 @@
+//! Timestamp sources for request latency, selected with `--clock`. At
+//! latencies under 100us the cost and granularity of taking timestamps is a
+//! noticeable part of what is measured, so both are measured and reported
+//! when the clock is set up.
+
+use {
+    anyhow::{bail, Error, Result},
+    serde::Serialize,
+    std::{
+        fmt,
+        str::FromStr,
+        time::{Duration, Instant},
+    },
+    tracing::*,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ClockSource {
+    /// `std::time::Instant`, CLOCK_MONOTONIC on Linux.
+    Instant,
+    /// CLOCK_MONOTONIC_RAW, not slewed by NTP.
+    MonotonicRaw,
+    /// The x86 time stamp counter, scaled with a frequency calibrated at
+    /// startup. Needs a build with --features tsc.
+    Tsc,
+}
+
+impl FromStr for ClockSource {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "instant" => Ok(ClockSource::Instant),
+            "monotonic-raw" => Ok(ClockSource::MonotonicRaw),
+            "tsc" => Ok(ClockSource::Tsc),
+            _ => bail!("unknown clock {s:?}, expected \"instant\", \"monotonic-raw\" or \"tsc\""),
+        }
+    }
+}
+
+impl fmt::Display for ClockSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ClockSource::Instant => "instant",
+            ClockSource::MonotonicRaw => "monotonic-raw",
+            ClockSource::Tsc => "tsc",
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn read_monotonic_raw() -> u64 {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `time` is a valid timespec to write to.
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC_RAW, &mut time) };
+    time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_monotonic_raw() -> u64 {
+    unreachable!("CLOCK_MONOTONIC_RAW is rejected by Clock::new on this platform")
+}
+
+#[cfg(all(feature = "tsc", target_arch = "x86_64"))]
+fn read_tsc() -> u64 {
+    #[allow(unused_unsafe)]
+    // SAFETY: rdtsc is available on every x86_64 CPU.
+    unsafe {
+        std::arch::x86_64::_rdtsc()
+    }
+}
+
+#[cfg(not(all(feature = "tsc", target_arch = "x86_64")))]
+fn read_tsc() -> u64 {
+    unreachable!("the TSC clock is rejected by Clock::new in this build")
+}
+
+/// TSC ticks per nanosecond, measured against `Instant` over a short sleep.
+fn calibrate_tsc() -> Result<f64> {
+    if !cfg!(all(feature = "tsc", target_arch = "x86_64")) {
+        bail!("the TSC clock needs an x86_64 build with --features tsc");
+    }
+    let constant = std::fs::read_to_string("/proc/cpuinfo")
+        .is_ok_and(|cpuinfo| cpuinfo.contains(" constant_tsc"));
+    if !constant {
+        warn!("The CPU does not report a constant TSC, latencies may drift with frequency");
+    }
+    let (start, start_ticks) = (Instant::now(), read_tsc());
+    std::thread::sleep(Duration::from_millis(20));
+    let (elapsed, ticks) = (start.elapsed(), read_tsc() - start_ticks);
+    Ok(ticks as f64 / elapsed.as_nanos() as f64)
+}
+
+/// A latency clock, cheap to copy into every task taking timestamps.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Clock {
+    source: ClockSource,
+    origin: Instant,
+    tsc_ticks_per_ns: f64,
+}
+
+impl Clock {
+    pub(crate) fn new(source: ClockSource) -> Result<Self> {
+        if source == ClockSource::MonotonicRaw && !cfg!(target_os = "linux") {
+            bail!("CLOCK_MONOTONIC_RAW is only available on Linux");
+        }
+        let tsc_ticks_per_ns = match source {
+            ClockSource::Tsc => calibrate_tsc()?,
+            _ => 0.0,
+        };
+        Ok(Self {
+            source,
+            origin: Instant::now(),
+            tsc_ticks_per_ns,
+        })
+    }
+
+    /// Nanoseconds since an arbitrary origin fixed for this clock.
+    pub(crate) fn now(&self) -> u64 {
+        match self.source {
+            ClockSource::Instant => self.origin.elapsed().as_nanos() as u64,
+            ClockSource::MonotonicRaw => read_monotonic_raw(),
+            ClockSource::Tsc => (read_tsc() as f64 / self.tsc_ticks_per_ns) as u64,
+        }
+    }
+
+    /// Time since the timestamp `start` taken with `now`.
+    pub(crate) fn since(&self, start: u64) -> Duration {
+        Duration::from_nanos(self.now().saturating_sub(start))
+    }
+
+    /// Measure and log the smallest step between distinct readings and the
+    /// average cost of one reading.
+    pub(crate) fn report(&self) {
+        const READINGS: u32 = 100_000;
+
+        let mut resolution = u64::MAX;
+        for _ in 0..100 {
+            let first = self.now();
+            let mut next = self.now();
+            while next == first {
+                next = self.now();
+            }
+            resolution = resolution.min(next.saturating_sub(first));
+        }
+
+        let start = Instant::now();
+        for _ in 0..READINGS {
+            std::hint::black_box(self.now());
+        }
+        let overhead = start.elapsed().as_nanos() as f64 / READINGS as f64;
+
+        info!(
+            "Clock {}: resolution {resolution}ns, {overhead:.1}ns per reading",
+            self.source
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_clock_source() {
+        for source in [
+            ClockSource::Instant,
+            ClockSource::MonotonicRaw,
+            ClockSource::Tsc,
+        ] {
+            assert_eq!(source.to_string().parse::<ClockSource>().unwrap(), source);
+        }
+        assert!("rdtsc".parse::<ClockSource>().is_err());
+        assert!("".parse::<ClockSource>().is_err());
+    }
+
+    #[test]
+    fn instant_clock_is_monotonic() {
+        let clock = Clock::new(ClockSource::Instant).unwrap();
+        let start = clock.now();
+        assert!(clock.now() >= start);
+        assert!(clock.since(u64::MAX).is_zero());
+    }
+}
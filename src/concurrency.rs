@@ -0,0 +1,73 @@
+//! Adaptive client concurrency: limit the requests awaiting a response per
+//! connection and steer the limit so that p99 latency stays under a target,
+//! answering how many parallel sends a forwarder should use.
+//!
+//! The limit doubles until the target is first exceeded, then grows by one
+//! per interval within the target and shrinks by a quarter above it.
+
+use {
+    crate::{histogram::Histogram, requests::ResponseStats},
+    std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    },
+    tokio::time,
+    tracing::*,
+};
+
+const INTERVAL: Duration = Duration::from_millis(500);
+/// Fewer responses do not say enough about p99.
+const MIN_SAMPLES: u64 = 100;
+
+pub(crate) struct ConcurrencyController {
+    limit: AtomicUsize,
+    target_p99: Duration,
+}
+
+impl ConcurrencyController {
+    pub(crate) fn new(target_p99: Duration) -> Self {
+        Self {
+            limit: AtomicUsize::new(1),
+            target_p99,
+        }
+    }
+
+    /// Requests allowed in flight per connection.
+    pub(crate) fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    /// Adjust the limit every interval from the latency of the responses
+    /// received in it. Runs until aborted.
+    pub(crate) async fn run(self: Arc<Self>, responses: Arc<ResponseStats>) {
+        let mut interval = time::interval(INTERVAL);
+        interval.tick().await;
+        let mut slow_start = true;
+        let mut latency = Histogram::default();
+        loop {
+            interval.tick().await;
+            // At low limits and high RTT an interval has few responses, they
+            // are then judged together with the next intervals'.
+            latency.merge(&responses.take_recent_latency());
+            if latency.count() < MIN_SAMPLES {
+                continue;
+            }
+            let p99 = Duration::from_micros(latency.percentile(99.0));
+            let limit = self.limit();
+            let new_limit = if p99 > self.target_p99 {
+                slow_start = false;
+                (limit - limit / 4).min(limit - 1).max(1)
+            } else if slow_start {
+                limit * 2
+            } else {
+                limit + 1
+            };
+            debug!("p99 {p99:?} at concurrency {limit}, next {new_limit}");
+            self.limit.store(new_limit, Ordering::Relaxed);
+            latency = Histogram::default();
+        }
+    }
+}
@@ -0,0 +1,432 @@
+//! Control channel carried on the first bidirectional stream of a connection.
+//!
+//! Messages are single text lines of the form `<COMMAND> [args...]` so that
+//! the exchange stays readable in packet captures and easy to extend.
+//!
+//! Control streams are sent ahead of the request streams, so heartbeats and
+//! round trip probes are not starved when the connection is saturated. With
+//! `--control-connection separate` the heartbeats of the workload move to a
+//! connection of their own, which shares no congestion window with the
+//! requests either.
+
+use {
+    crate::histogram::Histogram,
+    anyhow::{anyhow, bail, Error, Result},
+    quinn::{Connection, Endpoint, RecvStream, SendStream},
+    serde::Serialize,
+    std::{
+        fmt,
+        net::SocketAddr,
+        str::FromStr,
+        time::{Duration, Instant},
+    },
+    tokio::io::{AsyncBufReadExt, BufReader, Lines},
+};
+
+/// Send priority of the control streams, above the default 0 of the request
+/// streams.
+const CONTROL_PRIORITY: i32 = 1 << 16;
+
+/// Interval of the heartbeats sent while the workload runs.
+pub(crate) const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where the client runs the heartbeats of its control streams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ControlConnection {
+    /// On the request connection, at a higher stream priority.
+    Shared,
+    /// On a connection of their own per request connection.
+    Separate,
+}
+
+impl FromStr for ControlConnection {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "shared" => Ok(ControlConnection::Shared),
+            "separate" => Ok(ControlConnection::Separate),
+            _ => bail!("unknown control connection {s:?}, expected \"shared\" or \"separate\""),
+        }
+    }
+}
+
+impl fmt::Display for ControlConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ControlConnection::Shared => "shared",
+            ControlConnection::Separate => "separate",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ControlMessage {
+    /// Sent by the client right after connecting to tag the connection with
+    /// the run it belongs to. `connection_id` is the client's stable id of the
+    /// connection so both sides' logs can be matched.
+    Hello {
+        run_id: String,
+        connection_id: usize,
+    },
+    /// The server's reply to `Hello`: the ports of all its endpoints, so the
+    /// client can spread further connections across them.
+    Endpoints {
+        ports: Vec<u16>,
+    },
+    /// Round trip probe, answered with a `Pong` carrying the same nonce.
+    Ping {
+        nonce: u64,
+    },
+    Pong {
+        nonce: u64,
+    },
+    /// Asks the server to answer requests with their full content instead of
+    /// just the request id.
+    Echo,
+    /// Asks the server to answer requests with their first `len` bytes.
+    EchoPrefix {
+        len: usize,
+    },
+    /// Asks the server to answer requests with `size` bytes, on a stream
+    /// when the size exceeds the datagram limit.
+    ResponseSize {
+        size: usize,
+    },
+    /// Asks the server to alternate between datagram and stream responses by
+    /// request id.
+    AbResponses,
+    /// Announces requests framed by their length, several per stream, each
+    /// answered as soon as it is complete.
+    Framed,
+    /// Asks the server to flood datagrams of `size` bytes for `duration`
+    /// while counting the client's.
+    Flood {
+        size: usize,
+        duration: Duration,
+    },
+    /// The server's datagram counts once a flood is over.
+    FloodResult {
+        sent: u64,
+        received: u64,
+    },
+    /// Asks the server how many edge case streams and datagrams it received.
+    EdgeCases,
+    EdgeCasesResult {
+        zero_byte_streams: usize,
+        short_streams: usize,
+        datagrams: usize,
+    },
+    /// Sent by a draining server: no further requests on this connection.
+    GoAway,
+    /// Sent by the client once its workload is over, so the server can
+    /// account the run without waiting for the connection to close.
+    RunEnd,
+}
+
+impl ControlMessage {
+    fn encode(&self) -> String {
+        match self {
+            ControlMessage::Hello {
+                run_id,
+                connection_id,
+            } => format!("HELLO {run_id} {connection_id}\n"),
+            ControlMessage::Endpoints { ports } => {
+                let ports: Vec<_> = ports.iter().map(u16::to_string).collect();
+                format!("ENDPOINTS {}\n", ports.join(" "))
+            }
+            ControlMessage::Ping { nonce } => format!("PING {nonce}\n"),
+            ControlMessage::Pong { nonce } => format!("PONG {nonce}\n"),
+            ControlMessage::Echo => "ECHO\n".to_string(),
+            ControlMessage::EchoPrefix { len } => format!("ECHO_PREFIX {len}\n"),
+            ControlMessage::ResponseSize { size } => format!("RESPONSE_SIZE {size}\n"),
+            ControlMessage::AbResponses => "AB_RESPONSES\n".to_string(),
+            ControlMessage::Framed => "FRAMED\n".to_string(),
+            ControlMessage::Flood { size, duration } => {
+                format!("FLOOD {size} {}\n", duration.as_millis())
+            }
+            ControlMessage::FloodResult { sent, received } => {
+                format!("FLOOD_RESULT {sent} {received}\n")
+            }
+            ControlMessage::EdgeCases => "EDGE_CASES\n".to_string(),
+            ControlMessage::EdgeCasesResult {
+                zero_byte_streams,
+                short_streams,
+                datagrams,
+            } => format!("EDGE_CASES_RESULT {zero_byte_streams} {short_streams} {datagrams}\n"),
+            ControlMessage::GoAway => "GOAWAY\n".to_string(),
+            ControlMessage::RunEnd => "RUN_END\n".to_string(),
+        }
+    }
+
+    fn parse(line: &str) -> Result<Self> {
+        let mut parts = line.split_whitespace();
+        let command = parts
+            .next()
+            .ok_or_else(|| anyhow!("empty control message"))?;
+        let message = match command {
+            "HELLO" => {
+                let run_id = parts
+                    .next()
+                    .ok_or_else(|| anyhow!("HELLO without run id"))?;
+                validate_run_id(run_id)?;
+                let connection_id = parts
+                    .next()
+                    .ok_or_else(|| anyhow!("HELLO without connection id"))?
+                    .parse()?;
+                ControlMessage::Hello {
+                    run_id: run_id.to_string(),
+                    connection_id,
+                }
+            }
+            "ENDPOINTS" => ControlMessage::Endpoints {
+                ports: parts.map(str::parse).collect::<Result<_, _>>()?,
+            },
+            "PING" | "PONG" => {
+                let nonce = parts
+                    .next()
+                    .ok_or_else(|| anyhow!("{command} without nonce"))?
+                    .parse()?;
+                if command == "PING" {
+                    ControlMessage::Ping { nonce }
+                } else {
+                    ControlMessage::Pong { nonce }
+                }
+            }
+            "ECHO" => ControlMessage::Echo,
+            "ECHO_PREFIX" => ControlMessage::EchoPrefix {
+                len: parts
+                    .next()
+                    .ok_or_else(|| anyhow!("ECHO_PREFIX without length"))?
+                    .parse()?,
+            },
+            "RESPONSE_SIZE" => ControlMessage::ResponseSize {
+                size: parts
+                    .next()
+                    .ok_or_else(|| anyhow!("RESPONSE_SIZE without size"))?
+                    .parse()?,
+            },
+            "AB_RESPONSES" => ControlMessage::AbResponses,
+            "FRAMED" => ControlMessage::Framed,
+            "FLOOD" => {
+                let size = parts
+                    .next()
+                    .ok_or_else(|| anyhow!("FLOOD without size"))?
+                    .parse()?;
+                let millis = parts
+                    .next()
+                    .ok_or_else(|| anyhow!("FLOOD without duration"))?
+                    .parse()?;
+                ControlMessage::Flood {
+                    size,
+                    duration: Duration::from_millis(millis),
+                }
+            }
+            "FLOOD_RESULT" => {
+                let sent = parts
+                    .next()
+                    .ok_or_else(|| anyhow!("FLOOD_RESULT without sent count"))?
+                    .parse()?;
+                let received = parts
+                    .next()
+                    .ok_or_else(|| anyhow!("FLOOD_RESULT without received count"))?
+                    .parse()?;
+                ControlMessage::FloodResult { sent, received }
+            }
+            "EDGE_CASES" => ControlMessage::EdgeCases,
+            "EDGE_CASES_RESULT" => {
+                let mut count = |what| {
+                    parts
+                        .next()
+                        .ok_or_else(|| anyhow!("EDGE_CASES_RESULT without {what}"))?
+                        .parse::<usize>()
+                        .map_err(anyhow::Error::from)
+                };
+                ControlMessage::EdgeCasesResult {
+                    zero_byte_streams: count("zero byte streams")?,
+                    short_streams: count("short streams")?,
+                    datagrams: count("datagrams")?,
+                }
+            }
+            "GOAWAY" => ControlMessage::GoAway,
+            "RUN_END" => ControlMessage::RunEnd,
+            _ => bail!("unknown control message {line:?}"),
+        };
+        Ok(message)
+    }
+}
+
+/// Run ids end up in file names and log lines, so keep them to a safe set of
+/// characters.
+pub(crate) fn validate_run_id(run_id: &str) -> Result<()> {
+    if run_id.is_empty()
+        || run_id.len() > 64
+        || !run_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        bail!("invalid run id {run_id:?}");
+    }
+    Ok(())
+}
+
+pub(crate) struct ControlStream {
+    send: SendStream,
+    lines: Lines<BufReader<RecvStream>>,
+    /// Control stream of the separate connection carrying the heartbeats.
+    heartbeats: Option<Box<ControlStream>>,
+    /// Nonce and send time of the heartbeat waiting for its pong.
+    probe: Option<(u64, Instant)>,
+    /// Round trip times of pings and heartbeats.
+    latency: Histogram,
+}
+
+impl ControlStream {
+    /// Open the control stream, called by the client once per connection.
+    pub(crate) async fn open(connection: &Connection) -> Result<Self> {
+        let (send, recv) = connection.open_bi().await?;
+        Ok(Self::new(send, recv))
+    }
+
+    /// Accept the control stream opened by the peer.
+    pub(crate) async fn accept(connection: &Connection) -> Result<Self> {
+        let (send, recv) = connection.accept_bi().await?;
+        Ok(Self::new(send, recv))
+    }
+
+    fn new(send: SendStream, recv: RecvStream) -> Self {
+        // Only fails once the stream is closed, which the first write reports.
+        let _ = send.set_priority(CONTROL_PRIORITY);
+        Self {
+            send,
+            lines: BufReader::new(recv).lines(),
+            heartbeats: None,
+            probe: None,
+            latency: Histogram::default(),
+        }
+    }
+
+    /// Move the heartbeats to a new connection from `endpoint` to
+    /// `server_addr`. The connection joins no run, the server only answers
+    /// its pings.
+    pub(crate) async fn separate_heartbeats(
+        &mut self,
+        endpoint: &Endpoint,
+        server_addr: SocketAddr,
+        server_name: &str,
+    ) -> Result<()> {
+        let connection = endpoint.connect(server_addr, server_name)?.await?;
+        self.heartbeats = Some(Box::new(Self::open(&connection).await?));
+        Ok(())
+    }
+
+    /// Send a heartbeat unless the last one is still waiting for its pong,
+    /// its round trip time is recorded once `recv` sees the pong.
+    pub(crate) async fn heartbeat(&mut self) -> Result<()> {
+        if self.probe.is_some() {
+            return Ok(());
+        }
+        let nonce = rand::random::<u64>();
+        self.probe = Some((nonce, Instant::now()));
+        let ping = ControlMessage::Ping { nonce };
+        match &mut self.heartbeats {
+            Some(heartbeats) => heartbeats.send(&ping).await,
+            None => self.send(&ping).await,
+        }
+    }
+
+    /// Round trip times of the pings and heartbeats so far.
+    pub(crate) fn latency(&self) -> &Histogram {
+        &self.latency
+    }
+
+    pub(crate) async fn send(&mut self, message: &ControlMessage) -> Result<()> {
+        self.send.write_all(message.encode().as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Send a `Ping` and wait for the matching `Pong`, returning the round
+    /// trip time.
+    pub(crate) async fn ping(&mut self) -> Result<Duration> {
+        let nonce = rand::random::<u64>();
+        let start = Instant::now();
+        self.send(&ControlMessage::Ping { nonce }).await?;
+        loop {
+            match self.recv().await? {
+                Some(ControlMessage::Pong { nonce: n }) if n == nonce => {
+                    let rtt = start.elapsed();
+                    self.latency.record_duration(rtt);
+                    return Ok(rtt);
+                }
+                Some(_) => continue,
+                None => bail!("control stream finished while waiting for pong"),
+            }
+        }
+    }
+
+    /// Receive the next message, `None` once the peer finished the stream.
+    /// Messages of the heartbeat connection are passed on as well, so a
+    /// draining server is noticed on either.
+    pub(crate) async fn recv(&mut self) -> Result<Option<ControlMessage>> {
+        loop {
+            let (line, separate) = match &mut self.heartbeats {
+                Some(heartbeats) => tokio::select! {
+                    line = self.lines.next_line() => (line?, false),
+                    line = heartbeats.lines.next_line() => (line?, true),
+                },
+                None => (self.lines.next_line().await?, false),
+            };
+            let message = match line {
+                Some(line) => ControlMessage::parse(&line)?,
+                // The request connection's stream decides the end.
+                None if separate => {
+                    self.heartbeats = None;
+                    continue;
+                }
+                None => return Ok(None),
+            };
+            if let ControlMessage::Pong { nonce } = message {
+                if let Some((_, sent)) = self.probe.filter(|(probe, _)| *probe == nonce) {
+                    self.latency.record_duration(sent.elapsed());
+                    self.probe = None;
+                }
+            }
+            return Ok(Some(message));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_round_trip() {
+        let hello = ControlMessage::Hello {
+            run_id: "run-1.a_b".to_string(),
+            connection_id: 3,
+        };
+        let line = hello.encode();
+        assert_eq!(line, "HELLO run-1.a_b 3\n");
+        assert_eq!(ControlMessage::parse(line.trim_end()).unwrap(), hello);
+    }
+
+    #[test]
+    fn parse_rejects_invalid_messages() {
+        assert!(ControlMessage::parse("").is_err());
+        assert!(ControlMessage::parse("HELLO").is_err());
+        assert!(ControlMessage::parse("HELLO run-1").is_err());
+        assert!(ControlMessage::parse("HELLO run-1 x").is_err());
+        assert!(ControlMessage::parse("HELLO run/1 0").is_err());
+        assert!(ControlMessage::parse("BYE run-1").is_err());
+    }
+
+    #[test]
+    fn run_id_validation() {
+        assert!(validate_run_id("0123456789abcdef").is_ok());
+        assert!(validate_run_id("").is_err());
+        assert!(validate_run_id(&"a".repeat(65)).is_err());
+        assert!(validate_run_id("../etc").is_err());
+    }
+}
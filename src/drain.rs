@@ -0,0 +1,222 @@
+//! Graceful drain of the server on shutdown. On SIGINT/SIGTERM or
+//! `POST /drain` on the stats API the server stops accepting connections and
+//! sends `GOAWAY` on every control stream, upon which clients stop sending
+//! requests. Each connection is closed once the client closed it or its
+//! streams went quiet, at the latest after `--drain-timeout`, with an
+//! application close code telling which. The outcome of every connection is
+//! counted, so a shutdown mid-run does not show up as loss on the client.
+
+use {
+    crate::{
+        control::{ControlMessage, ControlStream, HEARTBEAT_INTERVAL},
+        join_all,
+        service::ConnectionCounters,
+    },
+    quinn::Connection,
+    std::{
+        fmt, future,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    },
+    tokio::{signal, sync::watch, time},
+    tracing::*,
+};
+
+/// Application close code of a connection drained in time.
+const CLOSE_DRAINED: u32 = 0x10;
+/// Application close code of a connection cut at the drain timeout.
+const CLOSE_DRAIN_TIMEOUT: u32 = 0x11;
+
+/// A connection counts as quiet once no stream arrived for this long.
+const QUIET_PERIOD: Duration = Duration::from_millis(200);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DrainOutcome {
+    /// The client closed the connection after the go away.
+    ClosedByClient,
+    /// No streams arrived for the quiet period, closed by the server.
+    Quiesced,
+    /// Still busy at the drain timeout, cut by the server.
+    TimedOut,
+}
+
+impl fmt::Display for DrainOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            DrainOutcome::ClosedByClient => "closed by the client",
+            DrainOutcome::Quiesced => "quiesced",
+            DrainOutcome::TimedOut => "cut at the drain timeout",
+        })
+    }
+}
+
+/// The drain state of the server and the outcomes of the drained
+/// connections.
+pub(crate) struct Drain {
+    draining: watch::Sender<bool>,
+    timeout: Duration,
+    closed_by_client: AtomicUsize,
+    quiesced: AtomicUsize,
+    timed_out: AtomicUsize,
+}
+
+impl Drain {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Self {
+            draining: watch::Sender::new(false),
+            timeout,
+            closed_by_client: AtomicUsize::new(0),
+            quiesced: AtomicUsize::new(0),
+            timed_out: AtomicUsize::new(0),
+        }
+    }
+
+    /// Start draining, once.
+    pub(crate) fn start(&self, reason: &str) {
+        if !self.draining.send_replace(true) {
+            info!(
+                "Draining the server on {reason}, for up to {:?}",
+                self.timeout
+            );
+        }
+    }
+
+    pub(crate) fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    /// Wait until the server starts draining.
+    pub(crate) async fn started(&self) {
+        let mut draining = self.draining.subscribe();
+        let _ = draining.wait_for(|draining| *draining).await;
+    }
+
+    /// Drain `connection` once the server starts draining, returning when the
+    /// connection closed either way.
+    pub(crate) async fn drain_connection(
+        &self,
+        connection: &Connection,
+        counters: &ConnectionCounters,
+    ) {
+        tokio::select! {
+            _ = connection.closed() => return,
+            () = self.started() => {}
+        }
+        let start = Instant::now();
+        let streams_before = counters.streams_received.load(Ordering::Relaxed);
+        let outcome = self.wait_quiet(connection, counters).await;
+        match outcome {
+            DrainOutcome::ClosedByClient => {}
+            DrainOutcome::Quiesced => connection.close(CLOSE_DRAINED.into(), b"drained"),
+            DrainOutcome::TimedOut => {
+                connection.close(CLOSE_DRAIN_TIMEOUT.into(), b"drain timeout")
+            }
+        }
+        let counter = match outcome {
+            DrainOutcome::ClosedByClient => &self.closed_by_client,
+            DrainOutcome::Quiesced => &self.quiesced,
+            DrainOutcome::TimedOut => &self.timed_out,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        info!(
+            "Drained {} in {:?}: {outcome}, {} streams completed while draining",
+            connection.remote_address(),
+            start.elapsed(),
+            counters.streams_received.load(Ordering::Relaxed) - streams_before,
+        );
+    }
+
+    async fn wait_quiet(
+        &self,
+        connection: &Connection,
+        counters: &ConnectionCounters,
+    ) -> DrainOutcome {
+        let deadline = Instant::now() + self.timeout;
+        let mut streams = counters.streams_received.load(Ordering::Relaxed);
+        let mut quiet_since = Instant::now();
+        while Instant::now() < deadline {
+            if connection.close_reason().is_some() {
+                return DrainOutcome::ClosedByClient;
+            }
+            let now_streams = counters.streams_received.load(Ordering::Relaxed);
+            if now_streams != streams {
+                streams = now_streams;
+                quiet_since = Instant::now();
+            } else if quiet_since.elapsed() >= QUIET_PERIOD {
+                return DrainOutcome::Quiesced;
+            }
+            time::sleep(POLL_INTERVAL).await;
+        }
+        DrainOutcome::TimedOut
+    }
+
+    /// Log the outcomes of all drained connections.
+    pub(crate) fn report(&self) {
+        if self.is_draining() {
+            info!(
+                "Drain finished: {} connections closed by the client, {} quiesced, {} cut at \
+                 the drain timeout",
+                self.closed_by_client.load(Ordering::Relaxed),
+                self.quiesced.load(Ordering::Relaxed),
+                self.timed_out.load(Ordering::Relaxed),
+            );
+        }
+    }
+}
+
+/// Start draining `drain` on SIGINT or SIGTERM.
+pub(crate) async fn drain_on_signal(drain: Arc<Drain>) {
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                warn!("Failed to listen for SIGTERM: {err}");
+                future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = signal::ctrl_c() => drain.start("SIGINT"),
+        () = terminate => drain.start("SIGTERM"),
+    }
+}
+
+/// Client side: watch the control streams for the server going away while
+/// the workload runs, setting the flag of the connection to stop its sender,
+/// and send their heartbeats. Never returns, the caller drops it once the
+/// workload is over.
+pub(crate) async fn watch_go_away(controls: &mut [ControlStream], go_away: &[Arc<AtomicBool>]) {
+    let watchers = controls
+        .iter_mut()
+        .zip(go_away)
+        .map(|(control, go_away)| async move {
+            let mut heartbeat = time::interval(HEARTBEAT_INTERVAL);
+            loop {
+                tokio::select! {
+                    message = control.recv() => match message {
+                        Ok(Some(ControlMessage::GoAway)) => {
+                            info!("Server is draining, no more requests on this connection");
+                            go_away.store(true, Ordering::Relaxed);
+                        }
+                        Ok(Some(_)) => {}
+                        Ok(None) | Err(_) => break,
+                    },
+                    _ = heartbeat.tick() => {
+                        if let Err(err) = control.heartbeat().await {
+                            debug!("Failed to send a heartbeat: {err:#}");
+                            break;
+                        }
+                    }
+                }
+            }
+        })
+        .collect();
+    join_all(watchers).await;
+    future::pending().await
+}
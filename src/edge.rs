@@ -0,0 +1,88 @@
+//! Edge case traffic mixed into the stream workload with `--edge-cases`:
+//! zero byte streams, one byte streams and datagrams of the largest size the
+//! server accepts. At the end of the run the client compares what it sent
+//! with what the server counted.
+
+use {
+    crate::control::{ControlMessage, ControlStream},
+    anyhow::{bail, Result},
+    bytes::Bytes,
+    quinn::Connection,
+    std::sync::atomic::{AtomicUsize, Ordering},
+    tracing::*,
+};
+
+/// Edge case traffic is sent along with every this many requests.
+pub(crate) const EDGE_CASE_INTERVAL: usize = 16;
+
+#[derive(Default)]
+pub(crate) struct EdgeCaseCounts {
+    zero_byte_streams: AtomicUsize,
+    short_streams: AtomicUsize,
+    datagrams: AtomicUsize,
+}
+
+impl EdgeCaseCounts {
+    /// Send one zero byte stream, one 1 byte stream and one maximum size
+    /// datagram on `connection`.
+    pub(crate) async fn send(&self, connection: &Connection) -> Result<()> {
+        let mut stream = connection.open_uni().await?;
+        stream.finish()?;
+        self.zero_byte_streams.fetch_add(1, Ordering::Relaxed);
+
+        let mut stream = connection.open_uni().await?;
+        stream.write_all(&[0]).await?;
+        stream.finish()?;
+        self.short_streams.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(size) = connection.max_datagram_size() {
+            connection
+                .send_datagram_wait(Bytes::from(vec![0u8; size]))
+                .await?;
+            self.datagrams.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Collect the server's counts over every control stream and compare
+    /// them with ours. Streams are reliable and must match exactly, datagrams
+    /// may be lost.
+    pub(crate) async fn verify(&self, controls: &mut [ControlStream]) -> Result<()> {
+        let (mut zero_byte_streams, mut short_streams, mut datagrams) = (0, 0, 0);
+        for control in controls.iter_mut() {
+            control.send(&ControlMessage::EdgeCases).await?;
+            loop {
+                match control.recv().await? {
+                    Some(ControlMessage::EdgeCasesResult {
+                        zero_byte_streams: zero,
+                        short_streams: short,
+                        datagrams: received,
+                    }) => {
+                        zero_byte_streams += zero;
+                        short_streams += short;
+                        datagrams += received;
+                        break;
+                    }
+                    Some(_) => continue,
+                    None => bail!("control stream finished before the edge case counts"),
+                }
+            }
+        }
+
+        let sent_zero = self.zero_byte_streams.load(Ordering::Relaxed);
+        let sent_short = self.short_streams.load(Ordering::Relaxed);
+        let sent_datagrams = self.datagrams.load(Ordering::Relaxed);
+        info!(
+            "Edge cases: zero byte streams {zero_byte_streams}/{sent_zero}, one byte streams \
+             {short_streams}/{sent_short}, maximum size datagrams {datagrams}/{sent_datagrams} \
+             counted by the server"
+        );
+        if zero_byte_streams != sent_zero || short_streams != sent_short {
+            error!("Server stream counts do not match the edge case streams sent");
+        }
+        if datagrams > sent_datagrams {
+            error!("Server counted more datagrams than were sent");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+//! Endpoint level failures, told apart from the failures of single
+//! connections: a socket which cannot be bound, a local address range
+//! exhausted, an endpoint which stopped. With several endpoints the run goes
+//! on with the others, but its results are marked degraded instead of
+//! silently covering fewer endpoints than asked for.
+
+use {
+    quinn::ConnectError,
+    serde::Serialize,
+    std::{fmt, io, net::SocketAddr, sync::Mutex},
+    tracing::*,
+};
+
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct EndpointFailure {
+    /// Index of the endpoint in the order they were set up.
+    endpoint: usize,
+    local: Option<SocketAddr>,
+    error: String,
+}
+
+#[derive(Default)]
+pub(crate) struct EndpointFailures {
+    failures: Mutex<Vec<EndpointFailure>>,
+}
+
+impl EndpointFailures {
+    pub(crate) fn record(
+        &self,
+        endpoint: usize,
+        local: Option<SocketAddr>,
+        error: impl fmt::Display,
+    ) {
+        let local_addr = local.map_or_else(|| "unbound".to_string(), |local| local.to_string());
+        let error = format!("{error:#}");
+        error!("Endpoint {endpoint} ({local_addr}) failed: {error}, continuing without it");
+        self.failures.lock().unwrap().push(EndpointFailure {
+            endpoint,
+            local,
+            error,
+        });
+    }
+
+    pub(crate) fn is_degraded(&self) -> bool {
+        !self.failures.lock().unwrap().is_empty()
+    }
+
+    pub(crate) fn list(&self) -> Vec<EndpointFailure> {
+        self.failures.lock().unwrap().clone()
+    }
+
+    /// Log the failures of the `total` endpoints, nothing if none failed.
+    pub(crate) fn report(&self, total: usize) {
+        let failures = self.failures.lock().unwrap();
+        if failures.is_empty() {
+            return;
+        }
+        warn!(
+            "Degraded: {} of {total} endpoints failed, results cover the others only",
+            failures.len()
+        );
+        for failure in failures.iter() {
+            warn!("  endpoint {}: {}", failure.endpoint, failure.error);
+        }
+    }
+}
+
+/// Whether binding failed for lack of local addresses or ports rather than
+/// a configuration error, which would fail every endpoint alike.
+pub(crate) fn is_address_exhausted(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable
+    )
+}
+
+/// Whether a connect attempt failed because of its endpoint rather than the
+/// connection or its configuration.
+pub(crate) fn is_endpoint_error(err: &ConnectError) -> bool {
+    matches!(
+        err,
+        ConnectError::EndpointStopping | ConnectError::CidsExhausted
+    )
+}
@@ -0,0 +1,192 @@
+//! Datagram flood: both sides send datagrams as fast as the connection takes
+//! them for a fixed duration and count what arrives, giving the throughput
+//! ceiling stream based modes can be compared against.
+//!
+//! The client starts the flood with a `Flood` control message. Once its own
+//! flood is over and late datagrams had time to arrive, the server answers
+//! with a `FloodResult` carrying its counts, from which the client derives
+//! the drops in both directions.
+
+use {
+    crate::control::{ControlMessage, ControlStream},
+    anyhow::{anyhow, bail, Result},
+    bytes::Bytes,
+    quinn::Connection,
+    std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, Instant},
+    },
+    tokio::{
+        sync::oneshot,
+        task::{JoinHandle, JoinSet},
+        time,
+    },
+    tracing::*,
+};
+
+/// How long the server keeps counting after its flood, for datagrams still
+/// in flight.
+const DRAIN: Duration = Duration::from_millis(500);
+
+/// Count the datagrams received on `connection` until `stop` fires.
+fn count_datagrams(connection: Connection) -> (oneshot::Sender<()>, JoinHandle<(u64, u64)>) {
+    let (stop, mut stopped) = oneshot::channel();
+    let counter = tokio::spawn(async move {
+        let (mut datagrams, mut bytes) = (0, 0);
+        loop {
+            tokio::select! {
+                result = connection.read_datagram() => match result {
+                    Ok(datagram) => {
+                        datagrams += 1;
+                        bytes += datagram.len() as u64;
+                    }
+                    Err(err) => {
+                        debug!("Datagram flood receive ended: {err}");
+                        break;
+                    }
+                },
+                _ = &mut stopped => break,
+            }
+        }
+        (datagrams, bytes)
+    });
+    (stop, counter)
+}
+
+/// Datagram size to flood with: `requested`, capped to what the peer accepts,
+/// or the largest the peer accepts when not given.
+fn flood_size(connection: &Connection, requested: Option<usize>) -> Result<usize> {
+    let max = connection
+        .max_datagram_size()
+        .ok_or_else(|| anyhow!("peer does not accept datagrams"))?;
+    Ok(requested.map_or(max, |size| size.min(max)))
+}
+
+/// Send datagrams of `size` bytes until `duration` elapsed, returning how
+/// many were sent.
+async fn send_datagrams(connection: &Connection, size: usize, duration: Duration) -> u64 {
+    let datagram = Bytes::from(vec![0u8; size]);
+    let deadline = Instant::now() + duration;
+    let mut sent = 0;
+    while Instant::now() < deadline {
+        if let Err(err) = connection.send_datagram_wait(datagram.clone()).await {
+            warn!("Datagram flood send error: {err}");
+            break;
+        }
+        sent += 1;
+    }
+    sent
+}
+
+/// Server side of a flood requested by the client, returning the datagrams
+/// sent and received. The server counts every datagram of a connection in
+/// `datagrams_received`.
+pub(crate) async fn serve_flood(
+    connection: &Connection,
+    datagrams_received: &AtomicUsize,
+    size: usize,
+    duration: Duration,
+) -> Result<(u64, u64)> {
+    let size = flood_size(connection, Some(size))?;
+    let received_before = datagrams_received.load(Ordering::Relaxed);
+    let sent = send_datagrams(connection, size, duration).await;
+    time::sleep(DRAIN).await;
+    let received = (datagrams_received.load(Ordering::Relaxed) - received_before) as u64;
+    info!("Datagram flood of {size} bytes: {sent} sent, {received} received");
+    Ok((sent, received))
+}
+
+struct FloodResult {
+    size: usize,
+    sent: u64,
+    received: u64,
+    received_bytes: u64,
+    server_sent: u64,
+    server_received: u64,
+}
+
+/// Flood every connection for `duration` with datagrams of `size` bytes, or
+/// of the largest size the server accepts, and report rates and drops.
+pub(crate) async fn run_datagram_flood(
+    conns: &[(Connection, Span)],
+    controls: &mut Vec<ControlStream>,
+    size: Option<usize>,
+    duration: Duration,
+) -> Result<()> {
+    let mut tasks = JoinSet::new();
+    for ((conn, span), mut control) in conns.iter().zip(controls.drain(..)) {
+        let conn = conn.clone();
+        tasks.spawn(
+            async move {
+                let result = async {
+                    let size = flood_size(&conn, size)?;
+                    let (stop, counter) = count_datagrams(conn.clone());
+                    control
+                        .send(&ControlMessage::Flood { size, duration })
+                        .await?;
+                    let sent = send_datagrams(&conn, size, duration).await;
+                    let (server_sent, server_received) = loop {
+                        match control.recv().await? {
+                            Some(ControlMessage::FloodResult { sent, received }) => {
+                                break (sent, received)
+                            }
+                            Some(_) => continue,
+                            None => bail!("control stream finished during the flood"),
+                        }
+                    };
+                    let _ = stop.send(());
+                    let (received, received_bytes) = counter.await?;
+                    Ok::<_, anyhow::Error>(FloodResult {
+                        size,
+                        sent,
+                        received,
+                        received_bytes,
+                        server_sent,
+                        server_received,
+                    })
+                }
+                .await;
+                (control, result)
+            }
+            .instrument(span.clone()),
+        );
+    }
+
+    let (mut sent, mut received, mut received_bytes) = (0, 0, 0);
+    let (mut sent_bytes, mut server_sent, mut server_received) = (0, 0, 0);
+    while let Some(joined) = tasks.join_next().await {
+        let (control, result) = joined?;
+        controls.push(control);
+        let result = match result {
+            Ok(result) => result,
+            Err(err) => {
+                error!("Datagram flood failed: {err:#}");
+                continue;
+            }
+        };
+        debug!(
+            "Flooded with {} byte datagrams: {} sent, server received {}",
+            result.size, result.sent, result.server_received
+        );
+        sent += result.sent;
+        sent_bytes += result.sent * result.size as u64;
+        received += result.received;
+        received_bytes += result.received_bytes;
+        server_sent += result.server_sent;
+        server_received += result.server_received;
+    }
+    let mbps = |bytes: u64| bytes as f64 * 8.0 / duration.as_secs_f64() / 1_000_000.0;
+    info!(
+        "client->server datagrams: {sent} sent ({:.2} Mbit/s), {server_received} received, \
+         {} dropped",
+        mbps(sent_bytes),
+        sent.saturating_sub(server_received),
+    );
+    info!(
+        "server->client datagrams: {server_sent} sent, {received} received ({:.2} Mbit/s), \
+         {} dropped",
+        mbps(received_bytes),
+        server_sent.saturating_sub(received),
+    );
+    Ok(())
+}
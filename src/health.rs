@@ -0,0 +1,59 @@
+//! Minimal HTTP health check endpoint for the server, so that orchestration
+//! can gate client start on the server being ready.
+
+use {
+    crate::ServerStats,
+    anyhow::Result,
+    std::{net::SocketAddr, sync::atomic::Ordering, sync::Arc},
+    tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    },
+    tracing::*,
+};
+
+/// Serve `GET` requests on `addr`, answering every request with the server
+/// status regardless of the requested path.
+pub(crate) async fn run_health_server(addr: SocketAddr, stats: Arc<ServerStats>) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Health check listening on {}", listener.local_addr()?);
+
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_request(socket, &stats).await {
+                debug!("Health check request from {peer} failed: {err:?}");
+            }
+        });
+    }
+}
+
+async fn handle_request(mut socket: TcpStream, stats: &ServerStats) -> Result<()> {
+    // The request itself is not interpreted, read it only so the client does
+    // not see a reset when we close the socket.
+    let mut buf = [0u8; 1024];
+    let _ = socket.read(&mut buf).await?;
+
+    // A draining server fails the check so no new clients are sent its way.
+    // One which lost endpoints still serves on the others.
+    let (status, state) = if stats.drain.is_draining() {
+        ("503 Service Unavailable", "draining")
+    } else if stats.endpoint_failures.is_degraded() {
+        ("200 OK", "degraded")
+    } else {
+        ("200 OK", "listening")
+    };
+    let body = format!(
+        "{state}\nconnections: {}\nuptime_secs: {}\n",
+        stats.active_connections.load(Ordering::Relaxed),
+        stats.start_time.elapsed().as_secs(),
+    );
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len(),
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
@@ -0,0 +1,241 @@
+//! Log-linear latency histogram with bounded memory and roughly 1.5%
+//! relative precision, recording values in microseconds.
+
+use {
+    serde::{Deserialize, Serialize},
+    std::{fmt, time::Duration},
+};
+
+/// Each power of two range is split into `1 << SUB_BUCKET_BITS` buckets.
+const SUB_BUCKET_BITS: u32 = 6;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+const NUM_BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(into = "SparseHistogram", try_from = "SparseHistogram")]
+pub(crate) struct Histogram {
+    counts: Vec<u64>,
+    count: u64,
+    sum: u128,
+    min: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; NUM_BUCKETS],
+            count: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+}
+
+/// Serialized form of a histogram, listing the non-empty buckets only.
+#[derive(Serialize, Deserialize)]
+struct SparseHistogram {
+    buckets: Vec<(usize, u64)>,
+    count: u64,
+    sum: u128,
+    min: u64,
+    max: u64,
+}
+
+impl From<Histogram> for SparseHistogram {
+    fn from(histogram: Histogram) -> Self {
+        Self {
+            buckets: histogram
+                .counts
+                .iter()
+                .enumerate()
+                .filter(|(_, count)| **count > 0)
+                .map(|(index, count)| (index, *count))
+                .collect(),
+            count: histogram.count,
+            sum: histogram.sum,
+            min: histogram.min,
+            max: histogram.max,
+        }
+    }
+}
+
+impl TryFrom<SparseHistogram> for Histogram {
+    type Error = String;
+
+    fn try_from(sparse: SparseHistogram) -> Result<Self, String> {
+        let mut histogram = Histogram {
+            count: sparse.count,
+            sum: sparse.sum,
+            min: sparse.min,
+            max: sparse.max,
+            ..Histogram::default()
+        };
+        for (index, count) in sparse.buckets {
+            *histogram
+                .counts
+                .get_mut(index)
+                .ok_or_else(|| format!("histogram bucket {index} out of range"))? = count;
+        }
+        Ok(histogram)
+    }
+}
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let exponent = 63 - value.leading_zeros();
+    let shift = exponent - SUB_BUCKET_BITS;
+    let mantissa = (value >> shift) as usize & (SUB_BUCKETS - 1);
+    ((shift as usize + 1) << SUB_BUCKET_BITS) | mantissa
+}
+
+/// Lowest value which maps to bucket `index`.
+fn bucket_value(index: usize) -> u64 {
+    let group = index >> SUB_BUCKET_BITS;
+    let mantissa = (index & (SUB_BUCKETS - 1)) as u64;
+    if group == 0 {
+        mantissa
+    } else {
+        (SUB_BUCKETS as u64 | mantissa) << (group - 1)
+    }
+}
+
+impl Histogram {
+    pub(crate) fn record(&mut self, value: u64) {
+        self.counts[bucket_index(value)] += 1;
+        self.count += 1;
+        self.sum += value as u128;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    pub(crate) fn record_duration(&mut self, duration: Duration) {
+        self.record(duration.as_micros().min(u64::MAX as u128) as u64);
+    }
+
+    /// Add the values recorded by `other`.
+    pub(crate) fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    pub(crate) fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub(crate) fn min(&self) -> u64 {
+        if self.count == 0 {
+            0
+        } else {
+            self.min
+        }
+    }
+
+    pub(crate) fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub(crate) fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum as f64 / self.count as f64
+        }
+    }
+
+    /// Value at `percentile` (0-100), precise to the bucket width.
+    pub(crate) fn percentile(&self, percentile: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let rank = ((percentile / 100.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return bucket_value(index).clamp(self.min, self.max);
+            }
+        }
+        self.max
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "n={} min={}us p50={}us p90={}us p99={}us max={}us mean={:.1}us",
+            self.count,
+            self.min(),
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.max(),
+            self.mean(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_index_is_exact_for_small_values() {
+        for value in 0..SUB_BUCKETS as u64 {
+            assert_eq!(bucket_index(value), value as usize);
+            assert_eq!(bucket_value(value as usize), value);
+        }
+    }
+
+    #[test]
+    fn bucket_value_bounds_the_bucket() {
+        for value in [64, 65, 100, 1_000, 123_456, u64::MAX / 3, u64::MAX] {
+            let index = bucket_index(value);
+            assert!(index < NUM_BUCKETS);
+            let lowest = bucket_value(index);
+            assert!(lowest <= value);
+            assert!(value - lowest <= lowest / SUB_BUCKETS as u64);
+            assert_eq!(bucket_index(lowest), index);
+        }
+    }
+
+    #[test]
+    fn percentile() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.percentile(50.0), 0);
+        for value in 1..=1000 {
+            histogram.record(value);
+        }
+        assert_eq!(histogram.count, 1000);
+        assert_eq!(histogram.min(), 1);
+        assert_eq!(histogram.max(), 1000);
+        assert!((histogram.mean() - 500.5).abs() < 1e-9);
+        let p50 = histogram.percentile(50.0);
+        assert!((492..=500).contains(&p50), "p50 {p50}");
+        assert_eq!(histogram.percentile(100.0), 1000);
+        assert_eq!(histogram.percentile(0.0), 1);
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let mut histogram = Histogram::default();
+        for value in [3, 70, 70, 9_000] {
+            histogram.record(value);
+        }
+        let json = serde_json::to_string(&histogram).unwrap();
+        let decoded: Histogram = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.counts, histogram.counts);
+        assert_eq!(decoded.count(), 4);
+        assert_eq!(decoded.percentile(99.0), histogram.percentile(99.0));
+        assert_eq!(decoded.max(), 9_000);
+    }
+}
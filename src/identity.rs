@@ -0,0 +1,273 @@
+//! Client identities with `--identity`: logical clients can bind a random
+//! source port instead of the next ephemeral one and present a freshly
+//! generated client certificate, so the per-IP/per-identity policies of the
+//! server see a realistic mix of clients. The outcome of the instances is
+//! reported per identity class. With `--request-client-certs` the server asks
+//! for the certificates, accepting any, and shows their fingerprints in the
+//! stats API.
+
+use {
+    crate::{histogram::Histogram, StreamSummary},
+    anyhow::{bail, Error, Result},
+    quinn::Connection,
+    rustls::{
+        client::danger::HandshakeSignatureValid,
+        crypto::CryptoProvider,
+        pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, UnixTime},
+        server::danger::{ClientCertVerified, ClientCertVerifier},
+        DigitallySignedStruct, DistinguishedName, SignatureScheme,
+    },
+    serde::Serialize,
+    std::{
+        collections::hash_map::DefaultHasher,
+        fmt,
+        hash::{Hash, Hasher},
+        io,
+        net::{IpAddr, SocketAddr, UdpSocket},
+        str::FromStr,
+        sync::Arc,
+    },
+    tracing::*,
+};
+
+/// Attempts to find a free random source port before giving up.
+const PORT_ATTEMPTS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum IdentityClass {
+    /// Ephemeral source ports, no client certificate.
+    Shared,
+    /// Random source ports, no client certificate.
+    RandomPort,
+    /// Ephemeral source ports and a certificate of the instance's own.
+    RandomCert,
+    /// Random source ports and a certificate of the instance's own.
+    Random,
+}
+
+impl IdentityClass {
+    pub(crate) fn random_port(self) -> bool {
+        matches!(self, IdentityClass::RandomPort | IdentityClass::Random)
+    }
+
+    fn random_cert(self) -> bool {
+        matches!(self, IdentityClass::RandomCert | IdentityClass::Random)
+    }
+
+    /// Generate the client certificate of an instance of this class.
+    pub(crate) fn certificate(
+        self,
+    ) -> Result<Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>> {
+        if !self.random_cert() {
+            return Ok(None);
+        }
+        let name = format!("client-{:016x}", rand::random::<u64>());
+        let cert = rcgen::generate_simple_self_signed(vec![name.clone()])?;
+        debug!("Generated client certificate for {name}");
+        Ok(Some((
+            vec![CertificateDer::from(cert.cert)],
+            PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()).into(),
+        )))
+    }
+}
+
+impl fmt::Display for IdentityClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            IdentityClass::Shared => "shared",
+            IdentityClass::RandomPort => "random-port",
+            IdentityClass::RandomCert => "random-cert",
+            IdentityClass::Random => "random",
+        })
+    }
+}
+
+impl FromStr for IdentityClass {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "shared" => Ok(IdentityClass::Shared),
+            "random-port" => Ok(IdentityClass::RandomPort),
+            "random-cert" => Ok(IdentityClass::RandomCert),
+            "random" => Ok(IdentityClass::Random),
+            _ => bail!(
+                "unknown identity class {s:?}, expected \"shared\", \"random-port\", \
+                 \"random-cert\" or \"random\""
+            ),
+        }
+    }
+}
+
+/// Bind a socket on `bind_ip` with a random port rather than the next
+/// ephemeral one.
+pub(crate) fn bind_random_port(bind_ip: IpAddr) -> io::Result<UdpSocket> {
+    for _ in 0..PORT_ATTEMPTS {
+        let port = rand::random_range(1024..=u16::MAX);
+        match UdpSocket::bind(SocketAddr::new(bind_ip, port)) {
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse => continue,
+            result => return result,
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        "no free random source port found",
+    ))
+}
+
+/// Server side verifier asking for a client certificate without requiring
+/// one. Any certificate is accepted, it only tells the clients apart.
+#[derive(Debug)]
+pub(crate) struct AcceptAnyClientCert(Arc<CryptoProvider>);
+
+impl AcceptAnyClientCert {
+    pub(crate) fn new(provider: Arc<CryptoProvider>) -> Arc<Self> {
+        Arc::new(Self(provider))
+    }
+}
+
+impl ClientCertVerifier for AcceptAnyClientCert {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Short fingerprint of the certificate the peer of `connection` presented.
+pub(crate) fn client_fingerprint(connection: &Connection) -> Option<String> {
+    let certs = connection
+        .peer_identity()?
+        .downcast::<Vec<CertificateDer<'static>>>()
+        .ok()?;
+    let mut hasher = DefaultHasher::new();
+    certs.first()?.as_ref().hash(&mut hasher);
+    Some(format!("{:016x}", hasher.finish()))
+}
+
+/// Outcome of the client instances of one identity class.
+#[derive(Default)]
+struct ClassOutcome {
+    instances: usize,
+    failed: usize,
+    sent: usize,
+    received: usize,
+    expired: usize,
+    goodput_mbps: f64,
+    latency: Histogram,
+}
+
+/// Log how the server treated the instances of every identity class, from the
+/// class and the result of each instance.
+pub(crate) fn report_identity_classes(
+    outcomes: &[(IdentityClass, &Result<Option<StreamSummary>>)],
+) {
+    let mut classes: Vec<(IdentityClass, ClassOutcome)> = Vec::new();
+    for (class, result) in outcomes {
+        let index = match classes.iter().position(|(known, _)| known == class) {
+            Some(index) => index,
+            None => {
+                classes.push((*class, ClassOutcome::default()));
+                classes.len() - 1
+            }
+        };
+        let outcome = &mut classes[index].1;
+        outcome.instances += 1;
+        match result {
+            Ok(Some(summary)) => {
+                outcome.sent += summary.sent;
+                outcome.received += summary.received;
+                outcome.expired += summary.expired;
+                outcome.goodput_mbps += summary.goodput_mbps;
+                outcome.latency.merge(&summary.latency);
+            }
+            Ok(None) => {}
+            Err(_) => outcome.failed += 1,
+        }
+    }
+    for (class, outcome) in classes {
+        info!(
+            "Identity class {class}: {} instances, {} failed, {} sent, {} received, {} \
+             expired, goodput {:.2} Mbit/s, latency {}",
+            outcome.instances,
+            outcome.failed,
+            outcome.sent,
+            outcome.received,
+            outcome.expired,
+            outcome.goodput_mbps,
+            outcome.latency,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_identity_class() {
+        for class in [
+            IdentityClass::Shared,
+            IdentityClass::RandomPort,
+            IdentityClass::RandomCert,
+            IdentityClass::Random,
+        ] {
+            assert_eq!(class.to_string().parse::<IdentityClass>().unwrap(), class);
+        }
+        assert!("random_port".parse::<IdentityClass>().is_err());
+        assert!("".parse::<IdentityClass>().is_err());
+    }
+
+    #[test]
+    fn certificate_only_for_random_cert_classes() {
+        assert!(IdentityClass::Shared.certificate().unwrap().is_none());
+        assert!(IdentityClass::RandomPort.certificate().unwrap().is_none());
+        assert!(IdentityClass::RandomCert.certificate().unwrap().is_some());
+        assert!(IdentityClass::Random.random_port());
+        assert!(!IdentityClass::RandomCert.random_port());
+    }
+}
@@ -0,0 +1,109 @@
+//! Idle connection monitoring: hold connections open with nothing but
+//! keep-alives and watch whether they survive NAT and idle timeouts and how
+//! their RTT drifts.
+
+use {
+    quinn::{Connection, ConnectionError},
+    std::time::Duration,
+    tokio::time::{self, Instant, MissedTickBehavior},
+    tracing::*,
+};
+
+struct IdleConnection<'a> {
+    connection: &'a Connection,
+    span: &'a Span,
+    initial_rtt: Duration,
+    min_rtt: Duration,
+    max_rtt: Duration,
+    last_rtt: Duration,
+    closed: Option<(Duration, ConnectionError)>,
+}
+
+impl IdleConnection<'_> {
+    fn sample(&mut self, elapsed: Duration) {
+        if self.closed.is_some() {
+            return;
+        }
+        if let Some(reason) = self.connection.close_reason() {
+            self.span.in_scope(|| {
+                warn!("Idle connection closed unexpectedly after {elapsed:?}: {reason}")
+            });
+            self.closed = Some((elapsed, reason));
+            return;
+        }
+        let rtt = self.connection.rtt();
+        self.min_rtt = self.min_rtt.min(rtt);
+        self.max_rtt = self.max_rtt.max(rtt);
+        self.last_rtt = rtt;
+        self.span
+            .in_scope(|| debug!("Idle connection rtt {rtt:?} after {elapsed:?}"));
+    }
+}
+
+/// Keep `connections` idle for `duration`, sampling their RTT every
+/// `sample_interval`. Returns the number of connections which closed before
+/// the end of the monitoring window.
+pub(crate) async fn monitor_idle_connections(
+    connections: &[(Connection, Span)],
+    duration: Duration,
+    sample_interval: Duration,
+) -> usize {
+    let mut idle: Vec<_> = connections
+        .iter()
+        .map(|(connection, span)| {
+            let rtt = connection.rtt();
+            IdleConnection {
+                connection,
+                span,
+                initial_rtt: rtt,
+                min_rtt: rtt,
+                max_rtt: rtt,
+                last_rtt: rtt,
+                closed: None,
+            }
+        })
+        .collect();
+
+    info!(
+        "Monitoring {} idle connections for {duration:?}",
+        idle.len()
+    );
+    let start = Instant::now();
+    let mut interval = time::interval(sample_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    while start.elapsed() < duration {
+        interval.tick().await;
+        let elapsed = start.elapsed();
+        idle.iter_mut().for_each(|conn| conn.sample(elapsed));
+        let alive = idle.iter().filter(|conn| conn.closed.is_none()).count();
+        info!(
+            "Idle for {:.0}s: {alive}/{} connections alive",
+            elapsed.as_secs_f64(),
+            idle.len()
+        );
+    }
+
+    for conn in &idle {
+        conn.span.in_scope(|| match &conn.closed {
+            Some((after, reason)) => {
+                warn!("Idle connection lost after {after:?}: {reason}")
+            }
+            None => info!(
+                "Idle connection survived: rtt initial {:?}, min {:?}, max {:?}, final {:?}, drift {:?}",
+                conn.initial_rtt,
+                conn.min_rtt,
+                conn.max_rtt,
+                conn.last_rtt,
+                conn.last_rtt.abs_diff(conn.initial_rtt),
+            ),
+        });
+    }
+    let closed = idle.iter().filter(|conn| conn.closed.is_some()).count();
+    if closed > 0 {
+        warn!(
+            "{closed}/{} idle connections closed unexpectedly",
+            idle.len()
+        );
+    }
+    closed
+}
@@ -0,0 +1,487 @@
+//! Network impairment layer: an in-process UDP relay placed between client
+//! and server which can drop packets according to the configured impairments.
+//!
+//! Besides the path MTU, the relay can drop packets following a loss model.
+//! The two state Gilbert-Elliott model produces loss in bursts, like real
+//! paths do, where uniform random loss would spread it evenly. Each direction
+//! runs its own chain.
+//!
+//! Loss, delay and bandwidth are configured per direction, so a congested
+//! reverse path can be emulated under a clean forward path. A bandwidth
+//! limit serializes the packets of each peer at that rate, dropping them once
+//! more than `MAX_QUEUE_DELAY` is queued, like a drop-tail bottleneck.
+//!
+//! The client connects to the relay instead of the server. Every client
+//! source address gets its own upstream socket so that the server sees one
+//! peer per client endpoint, like it would without the relay.
+
+use {
+    anyhow::{bail, Error, Result},
+    serde::Serialize,
+    std::{
+        collections::{hash_map::Entry, HashMap},
+        fmt,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+        str::FromStr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    },
+    tokio::{
+        net::UdpSocket,
+        sync::mpsc,
+        time::{self, Duration, Instant},
+    },
+    tracing::*,
+};
+
+/// Largest UDP payload we ever expect to relay.
+const MAX_DATAGRAM: usize = 65536;
+/// Packets queued for longer than this behind a bandwidth limit are dropped.
+const MAX_QUEUE_DELAY: Duration = Duration::from_millis(200);
+
+/// Packet loss model of the relay, with --emulate-loss-model for both
+/// directions or --loss-to-server and --loss-to-client for one.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum LossModel {
+    /// Two state Gilbert-Elliott model: per packet, `p` is the chance of
+    /// moving from the good to the bad state and `r` of moving back, `k` and
+    /// `h` are the chances of delivering the packet in the good and in the
+    /// bad state.
+    GilbertElliott { p: f64, r: f64, k: f64, h: f64 },
+}
+
+impl LossModel {
+    /// Long run share of packets lost.
+    fn average_loss(&self) -> f64 {
+        match *self {
+            LossModel::GilbertElliott { p, r, k, h } => {
+                let bad = if p + r > 0.0 { p / (p + r) } else { 0.0 };
+                (1.0 - bad) * (1.0 - k) + bad * (1.0 - h)
+            }
+        }
+    }
+}
+
+impl fmt::Display for LossModel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LossModel::GilbertElliott { p, r, k, h } => write!(f, "ge:{p},{r},{k},{h}"),
+        }
+    }
+}
+
+impl FromStr for LossModel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some(params) = s.strip_prefix("ge:") else {
+            bail!("unknown loss model {s:?}, expected \"ge:p,r,k,h\"");
+        };
+        let params = params
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<f64>, _>>()?;
+        let [p, r, k, h] = params[..] else {
+            bail!("the Gilbert-Elliott model takes 4 parameters p,r,k,h, got {s:?}");
+        };
+        if params.iter().any(|param| !(0.0..=1.0).contains(param)) {
+            bail!("the Gilbert-Elliott parameters are probabilities from 0 to 1, got {s:?}");
+        }
+        Ok(LossModel::GilbertElliott { p, r, k, h })
+    }
+}
+
+/// The state of one direction's loss model.
+struct LossChain {
+    model: LossModel,
+    bad: bool,
+    /// Whether the previous packet was dropped, to count loss bursts.
+    dropping: bool,
+}
+
+impl LossChain {
+    /// Advance the chain by one packet, returning whether it is delivered.
+    fn deliver(&mut self) -> bool {
+        let LossModel::GilbertElliott { p, r, k, h } = self.model;
+        let switch = if self.bad { r } else { p };
+        if rand::random::<f64>() < switch {
+            self.bad = !self.bad;
+        }
+        rand::random::<f64>() < if self.bad { h } else { k }
+    }
+}
+
+/// Direction a packet is relayed in.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Direction {
+    ToServer,
+    ToClient,
+}
+
+impl Direction {
+    const BOTH: [Direction; 2] = [Direction::ToServer, Direction::ToClient];
+}
+
+/// Impairments of one direction.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct PathImpairments {
+    pub(crate) loss: Option<LossModel>,
+    pub(crate) delay: Duration,
+    /// Bits per second.
+    pub(crate) bandwidth: Option<u64>,
+}
+
+impl PathImpairments {
+    pub(crate) fn is_impaired(&self) -> bool {
+        self.loss.is_some() || !self.delay.is_zero() || self.bandwidth.is_some()
+    }
+
+    /// Whether packets have to wait in a queue rather than go out right away.
+    fn is_shaped(&self) -> bool {
+        !self.delay.is_zero() || self.bandwidth.is_some()
+    }
+}
+
+impl fmt::Display for PathImpairments {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.loss {
+            Some(loss) => write!(f, "loss {loss} ({:.2}%)", loss.average_loss() * 100.0)?,
+            None => f.write_str("no loss")?,
+        }
+        write!(f, ", delay {:?}, ", self.delay)?;
+        match self.bandwidth {
+            Some(bandwidth) => write!(f, "{:.2} Mbit/s", bandwidth as f64 / 1_000_000.0),
+            None => f.write_str("unlimited bandwidth"),
+        }
+    }
+}
+
+/// Packets relayed in one direction and those the impairments dropped.
+#[derive(Default)]
+struct PathCounts {
+    packets: AtomicUsize,
+    dropped: AtomicUsize,
+    /// Runs of consecutive drops by the loss model.
+    bursts: AtomicUsize,
+    /// Dropped by a full bandwidth limited queue.
+    dropped_queue: AtomicUsize,
+}
+
+/// Impairments applied to relayed packets, in both directions.
+pub(crate) struct Impairments {
+    /// Packets with a larger UDP payload are dropped, emulating a path MTU.
+    max_payload: AtomicUsize,
+    pub(crate) dropped_oversize: AtomicUsize,
+    /// Per direction.
+    paths: Mutex<[PathImpairments; 2]>,
+    loss: [Mutex<Option<LossChain>>; 2],
+    counts: [PathCounts; 2],
+}
+
+impl Default for Impairments {
+    fn default() -> Self {
+        Self {
+            max_payload: AtomicUsize::new(MAX_DATAGRAM),
+            dropped_oversize: AtomicUsize::default(),
+            paths: Mutex::default(),
+            loss: Default::default(),
+            counts: Default::default(),
+        }
+    }
+}
+
+impl Impairments {
+    pub(crate) fn set_max_payload(&self, max_payload: usize) {
+        self.max_payload.store(max_payload, Ordering::Relaxed);
+    }
+
+    /// Impair the packets relayed in `direction`, before the first is.
+    pub(crate) fn configure(&self, direction: Direction, path: PathImpairments) {
+        info!("Impairment relay {direction:?}: {path}");
+        self.paths.lock().unwrap()[direction as usize] = path;
+        *self.loss[direction as usize].lock().unwrap() = path.loss.map(|model| LossChain {
+            model,
+            bad: false,
+            dropping: false,
+        });
+    }
+
+    fn path(&self, direction: Direction) -> PathImpairments {
+        self.paths.lock().unwrap()[direction as usize]
+    }
+
+    /// Whether a packet of `len` bytes is to be forwarded.
+    fn admit(&self, len: usize, direction: Direction) -> bool {
+        if len > self.max_payload.load(Ordering::Relaxed) {
+            self.dropped_oversize.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        let counts = &self.counts[direction as usize];
+        counts.packets.fetch_add(1, Ordering::Relaxed);
+        let mut loss = self.loss[direction as usize].lock().unwrap();
+        let Some(chain) = loss.as_mut() else {
+            return true;
+        };
+        let delivered = chain.deliver();
+        if !delivered {
+            counts.dropped.fetch_add(1, Ordering::Relaxed);
+            if !chain.dropping {
+                counts.bursts.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        chain.dropping = !delivered;
+        delivered
+    }
+
+    /// Log the packets the impairments dropped in each impaired direction.
+    pub(crate) fn report(&self) {
+        for direction in Direction::BOTH {
+            if !self.path(direction).is_impaired() {
+                continue;
+            }
+            let counts = &self.counts[direction as usize];
+            let packets = counts.packets.load(Ordering::Relaxed);
+            let dropped = counts.dropped.load(Ordering::Relaxed);
+            let bursts = counts.bursts.load(Ordering::Relaxed);
+            info!(
+                "Impairment relay {direction:?}: {dropped} of {packets} packets lost \
+                 ({:.2}%) in {bursts} bursts, {:.2} packets per burst, {} dropped by the \
+                 bandwidth limit",
+                dropped as f64 * 100.0 / packets.max(1) as f64,
+                dropped as f64 / bursts.max(1) as f64,
+                counts.dropped_queue.load(Ordering::Relaxed),
+            );
+        }
+    }
+}
+
+/// Where a link delivers its packets.
+#[derive(Clone)]
+enum Target {
+    /// The server, through the upstream socket connected to it.
+    Server(Arc<UdpSocket>),
+    /// A client, through the relay's own socket.
+    Client(Arc<UdpSocket>, SocketAddr),
+}
+
+impl Target {
+    async fn send(&self, packet: &[u8]) {
+        let _ = match self {
+            Target::Server(socket) => socket.send(packet).await,
+            Target::Client(socket, client) => socket.send_to(packet, client).await,
+        };
+    }
+}
+
+/// The packets of one peer in one direction, delayed and serialized at the
+/// bandwidth limit when the direction is shaped.
+struct Link {
+    target: Target,
+    direction: Direction,
+    impairments: Arc<Impairments>,
+    path: PathImpairments,
+    /// When the bottleneck finishes serializing the packets queued so far.
+    busy_until: Instant,
+    queue: Option<mpsc::UnboundedSender<(Instant, Vec<u8>)>>,
+}
+
+impl Link {
+    fn new(target: Target, direction: Direction, impairments: Arc<Impairments>) -> Self {
+        let path = impairments.path(direction);
+        let queue = path.is_shaped().then(|| {
+            let (sender, mut receiver) = mpsc::unbounded_channel::<(Instant, Vec<u8>)>();
+            let target = target.clone();
+            tokio::spawn(async move {
+                while let Some((deliver_at, packet)) = receiver.recv().await {
+                    time::sleep_until(deliver_at).await;
+                    target.send(&packet).await;
+                }
+            });
+            sender
+        });
+        Self {
+            target,
+            direction,
+            impairments,
+            path,
+            busy_until: Instant::now(),
+            queue,
+        }
+    }
+
+    async fn forward(&mut self, packet: &[u8]) {
+        if !self.impairments.admit(packet.len(), self.direction) {
+            return;
+        }
+        let Some(queue) = &self.queue else {
+            self.target.send(packet).await;
+            return;
+        };
+        let now = Instant::now();
+        let mut departure = now;
+        if let Some(bandwidth) = self.path.bandwidth {
+            let start = self.busy_until.max(now);
+            if start - now > MAX_QUEUE_DELAY {
+                self.impairments.counts[self.direction as usize]
+                    .dropped_queue
+                    .fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            let bits = packet.len() as u64 * 8;
+            departure = start + Duration::from_nanos(bits * 1_000_000_000 / bandwidth.max(1));
+            self.busy_until = departure;
+        }
+        let _ = queue.send((departure + self.path.delay, packet.to_vec()));
+    }
+}
+
+pub(crate) struct ImpairmentProxy {
+    local_addr: SocketAddr,
+    pub(crate) impairments: Arc<Impairments>,
+}
+
+impl ImpairmentProxy {
+    /// Start relaying to `upstream` from a loopback port, impairing each
+    /// direction as in `paths`, to the server first.
+    pub(crate) async fn start(upstream: SocketAddr, paths: [PathImpairments; 2]) -> Result<Self> {
+        let loopback = match upstream {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        };
+        let socket = Arc::new(UdpSocket::bind(SocketAddr::new(loopback, 0)).await?);
+        let local_addr = socket.local_addr()?;
+        let impairments = Arc::new(Impairments::default());
+        for (direction, path) in Direction::BOTH.into_iter().zip(paths) {
+            if path.is_impaired() {
+                impairments.configure(direction, path);
+            }
+        }
+        info!("Impairment relay {local_addr} forwarding to {upstream}");
+        tokio::spawn(relay_downstream(socket, upstream, impairments.clone()));
+        Ok(Self {
+            local_addr,
+            impairments,
+        })
+    }
+
+    /// Address the client has to connect to.
+    pub(crate) fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+/// Forward packets from clients to the server, creating the upstream socket of
+/// each client on its first packet.
+async fn relay_downstream(
+    socket: Arc<UdpSocket>,
+    upstream: SocketAddr,
+    impairments: Arc<Impairments>,
+) {
+    let mut peers: HashMap<SocketAddr, Link> = HashMap::new();
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let (len, client) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(err) => {
+                debug!("Impairment relay receive error: {err}");
+                continue;
+            }
+        };
+        let link = match peers.entry(client) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match connect_upstream(upstream).await {
+                Ok(upstream_socket) => {
+                    tokio::spawn(relay_upstream(
+                        upstream_socket.clone(),
+                        socket.clone(),
+                        client,
+                        impairments.clone(),
+                    ));
+                    let target = Target::Server(upstream_socket);
+                    entry.insert(Link::new(target, Direction::ToServer, impairments.clone()))
+                }
+                Err(err) => {
+                    warn!("Impairment relay failed to connect {client} upstream: {err}");
+                    continue;
+                }
+            },
+        };
+        link.forward(&buf[..len]).await;
+    }
+}
+
+/// Forward packets from the server back to `client`.
+async fn relay_upstream(
+    upstream_socket: Arc<UdpSocket>,
+    socket: Arc<UdpSocket>,
+    client: SocketAddr,
+    impairments: Arc<Impairments>,
+) {
+    let mut link = Link::new(
+        Target::Client(socket, client),
+        Direction::ToClient,
+        impairments,
+    );
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let len = match upstream_socket.recv(&mut buf).await {
+            Ok(len) => len,
+            Err(err) => {
+                debug!("Impairment relay receive error for {client}: {err}");
+                continue;
+            }
+        };
+        link.forward(&buf[..len]).await;
+    }
+}
+
+async fn connect_upstream(upstream: SocketAddr) -> Result<Arc<UdpSocket>> {
+    let unspecified = match upstream {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(unspecified, 0)).await?;
+    socket.connect(upstream).await?;
+    Ok(Arc::new(socket))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_gilbert_elliott() {
+        let model: LossModel = "ge:0.01,0.3,1,0".parse().unwrap();
+        assert_eq!(
+            model,
+            LossModel::GilbertElliott {
+                p: 0.01,
+                r: 0.3,
+                k: 1.0,
+                h: 0.0
+            }
+        );
+        assert_eq!(model.to_string().parse::<LossModel>().unwrap(), model);
+    }
+
+    #[test]
+    fn parse_gilbert_elliott_rejects_invalid() {
+        assert!("uniform:0.1".parse::<LossModel>().is_err());
+        assert!("ge:0.1,0.2,0.3".parse::<LossModel>().is_err());
+        assert!("ge:0.1,0.2,0.3,1.5".parse::<LossModel>().is_err());
+        assert!("ge:0.1,x,0.3,1".parse::<LossModel>().is_err());
+    }
+
+    #[test]
+    fn average_loss() {
+        // Only the bad state loses, the chain spends p / (p + r) in it.
+        let bursty: LossModel = "ge:0.1,0.3,1,0".parse().unwrap();
+        assert!((bursty.average_loss() - 0.25).abs() < 1e-9);
+        // Never leaving the good state, loss is 1 - k.
+        let uniform: LossModel = "ge:0,0,0.9,0".parse().unwrap();
+        assert!((uniform.average_loss() - 0.1).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,165 @@
+//! Listener mode with `--listener`: the server takes streams from any QUIC
+//! client, without the control protocol. Uni streams are served as requests,
+//! bidirectional streams are read to their end and echoed back on their send
+//! half, only their first bytes with `--echo-prefix`. Streams and bytes are
+//! counted per remote identity: the client's pubkey with
+//! `--expected-pubkeys`, else the fingerprint of the client certificate when
+//! one was presented (see `--request-client-certs`) and the IP address
+//! otherwise. With `--listener-no-responses` nothing is answered, the server
+//! is a plain sink.
+
+use {
+    crate::{identity, pinning, service::ConnectionCounters, ServerStats},
+    anyhow::Result,
+    quinn::{Connection, RecvStream, SendStream},
+    std::{
+        collections::HashMap,
+        sync::{atomic::Ordering, Arc, Mutex},
+    },
+    tracing::*,
+};
+
+/// Streams, bytes and responses of the connections of one remote identity.
+#[derive(Default)]
+struct IdentityTally {
+    connections: usize,
+    /// Totals of the connections already closed.
+    streams: usize,
+    bytes: usize,
+    responses: usize,
+    open: Vec<Arc<ConnectionCounters>>,
+}
+
+impl IdentityTally {
+    fn add(&mut self, counters: &ConnectionCounters) {
+        self.streams += counters.streams_received.load(Ordering::Relaxed);
+        self.bytes += counters.bytes_received.load(Ordering::Relaxed);
+        self.responses += counters.responses_sent.load(Ordering::Relaxed);
+    }
+}
+
+pub(crate) struct ListenerTally {
+    respond: bool,
+    identities: Mutex<HashMap<String, IdentityTally>>,
+}
+
+impl ListenerTally {
+    pub(crate) fn new(respond: bool) -> Self {
+        if respond {
+            info!("Listening for any QUIC client");
+        } else {
+            info!("Listening for any QUIC client, without responding");
+        }
+        Self {
+            respond,
+            identities: Mutex::default(),
+        }
+    }
+
+    pub(crate) fn responds(&self) -> bool {
+        self.respond
+    }
+
+    pub(crate) fn opened(&self, identity: &str, counters: Arc<ConnectionCounters>) {
+        let mut identities = self.identities.lock().unwrap();
+        let tally = identities.entry(identity.to_string()).or_default();
+        tally.connections += 1;
+        tally.open.push(counters);
+    }
+
+    pub(crate) fn closed(&self, identity: &str, counters: &Arc<ConnectionCounters>) {
+        let mut identities = self.identities.lock().unwrap();
+        if let Some(tally) = identities.get_mut(identity) {
+            tally.open.retain(|open| !Arc::ptr_eq(open, counters));
+            tally.add(counters);
+        }
+    }
+
+    /// Log the totals of every remote identity seen so far.
+    pub(crate) fn report(&self) {
+        let identities = self.identities.lock().unwrap();
+        for (identity, tally) in identities.iter() {
+            let mut totals = IdentityTally {
+                streams: tally.streams,
+                bytes: tally.bytes,
+                responses: tally.responses,
+                ..IdentityTally::default()
+            };
+            tally.open.iter().for_each(|open| totals.add(open));
+            info!(
+                "Listener identity {identity}: {} connections ({} open), {} streams, {} bytes, \
+                 {} responses",
+                tally.connections,
+                tally.open.len(),
+                totals.streams,
+                totals.bytes,
+                totals.responses,
+            );
+        }
+    }
+}
+
+/// The identity streams of `connection` are counted under, its pubkey if
+/// clients are `pinned`.
+pub(crate) fn remote_identity(connection: &Connection, pinned: bool) -> String {
+    if let Some(pubkey) = pinning::client_pubkey(connection).filter(|_| pinned) {
+        return format!("pubkey {pubkey}");
+    }
+    match identity::client_fingerprint(connection) {
+        Some(fingerprint) => format!("cert {fingerprint}"),
+        None => connection.remote_address().ip().to_string(),
+    }
+}
+
+/// Serve the bidirectional streams of `connection` until it closes, echoing
+/// each back if `respond`.
+pub(crate) async fn drive_bidi_streams(
+    connection: Connection,
+    stats: Arc<ServerStats>,
+    counters: Arc<ConnectionCounters>,
+    respond: bool,
+) {
+    while let Ok((send, recv)) = connection.accept_bi().await {
+        let stats = stats.clone();
+        let counters = counters.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve_bidi_stream(send, recv, &stats, &counters, respond).await {
+                debug!("Bidirectional stream failed: {err:#}");
+            }
+        });
+    }
+}
+
+async fn serve_bidi_stream(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    stats: &ServerStats,
+    counters: &ConnectionCounters,
+    respond: bool,
+) -> Result<()> {
+    let echo_len = match stats.echo_prefix {
+        0 => usize::MAX,
+        len => len,
+    };
+    let mut request = Vec::new();
+    while let Some(chunk) = recv.read_chunk(usize::MAX, true).await? {
+        counters
+            .bytes_received
+            .fetch_add(chunk.bytes.len(), Ordering::Relaxed);
+        if respond && request.len() < echo_len {
+            let n = (echo_len - request.len()).min(chunk.bytes.len());
+            request.extend_from_slice(&chunk.bytes[..n]);
+        }
+    }
+    stats.total_received.fetch_add(1, Ordering::Relaxed);
+    counters.streams_received.fetch_add(1, Ordering::Relaxed);
+    if respond {
+        send.write_all(&request).await?;
+        counters.responses_sent.fetch_add(1, Ordering::Relaxed);
+        counters
+            .bytes_sent
+            .fetch_add(request.len(), Ordering::Relaxed);
+    }
+    send.finish()?;
+    Ok(())
+}
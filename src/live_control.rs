@@ -0,0 +1,115 @@
+//! Live control of the stream workload: an offered request rate set with
+//! `--rate`, and with `--interactive` commands read from stdin while the
+//! client runs, to change the rate, pause and resume the senders and log a
+//! snapshot of the statistics or change the per-packet log sampling without
+//! restarting the client.
+
+use {
+    crate::{
+        packet_log,
+        requests::{InFlightGauge, ResponseStats},
+    },
+    std::{
+        sync::{
+            atomic::{AtomicU64, AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    },
+    tokio::{
+        io::{self, AsyncBufReadExt, BufReader},
+        sync::watch,
+        time::{sleep_until, Instant},
+    },
+    tracing::*,
+};
+
+pub(crate) struct LiveControl {
+    /// Offered requests per second over all connections, 0 for unlimited.
+    rate: AtomicU64,
+    connections: usize,
+    paused: watch::Sender<bool>,
+}
+
+impl LiveControl {
+    pub(crate) fn new(rate: u64, connections: usize) -> Self {
+        Self {
+            rate: AtomicU64::new(rate),
+            connections: connections.max(1),
+            paused: watch::Sender::new(false),
+        }
+    }
+
+    /// Wait while the senders are paused, then until the next send of a
+    /// connection is due at the offered rate. `next_send` is the connection's
+    /// schedule, advanced by one request.
+    pub(crate) async fn pace(&self, next_send: &mut Instant) {
+        if *self.paused.borrow() {
+            let mut paused = self.paused.subscribe();
+            let _ = paused.wait_for(|paused| !paused).await;
+        }
+        let now = Instant::now();
+        let rate = self.rate.load(Ordering::Relaxed);
+        if rate == 0 {
+            *next_send = now;
+            return;
+        }
+        if *next_send > now {
+            sleep_until(*next_send).await;
+        }
+        // Falling behind does not build up a burst to catch up with.
+        *next_send =
+            (*next_send).max(now) + Duration::from_secs_f64(self.connections as f64 / rate as f64);
+    }
+
+    fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+        match rate {
+            0 => info!("Offered rate unlimited"),
+            rate => info!("Offered rate {rate} requests/s"),
+        }
+    }
+
+    fn set_paused(&self, paused: bool) {
+        self.paused.send_replace(paused);
+        info!("Senders {}", if paused { "paused" } else { "resumed" });
+    }
+}
+
+/// Execute the commands typed on stdin until it closes.
+pub(crate) async fn run_commands(
+    control: Arc<LiveControl>,
+    total_sent: Arc<AtomicUsize>,
+    responses: Arc<ResponseStats>,
+    in_flight: Arc<InFlightGauge>,
+) {
+    info!("Reading commands from stdin: rate <requests/s>, sample <n>, pause, resume, stats");
+    let mut lines = BufReader::new(io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("rate"), Some(rate)) => match rate.parse() {
+                Ok(rate) => control.set_rate(rate),
+                Err(_) => warn!("Invalid rate {rate:?}"),
+            },
+            (Some("sample"), Some(rate)) => match rate.parse() {
+                Ok(rate) => packet_log::set_sample_rate(rate),
+                Err(_) => warn!("Invalid sample rate {rate:?}"),
+            },
+            (Some("pause"), None) => control.set_paused(true),
+            (Some("resume"), None) => control.set_paused(false),
+            (Some("stats"), None) => info!(
+                "Snapshot: {} requests sent, {} responses received, {} outstanding, latency {}",
+                total_sent.load(Ordering::Relaxed),
+                responses.received.load(Ordering::Relaxed),
+                in_flight.current(),
+                responses.latency.lock().unwrap(),
+            ),
+            (None, _) => {}
+            _ => warn!(
+                "Unknown command {line:?}, expected rate <requests/s>, sample <n>, pause, \
+                 resume or stats"
+            ),
+        }
+    }
+}
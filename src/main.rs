@@ -1,38 +1,137 @@
+mod alloc_stats;
+mod anomaly;
+mod calibration;
+mod checkpoint;
+mod clock;
+mod concurrency;
+mod control;
+mod drain;
+mod edge;
+mod endpoint_failures;
+mod flood;
+mod health;
+mod histogram;
+mod identity;
+mod idle;
+mod impair;
+mod listener;
+mod live_control;
+mod metrics_push;
+mod negative;
+mod otlp;
+mod packet_log;
+mod phases;
+mod pinning;
+mod pmtu;
+mod profile;
+mod rates;
+mod reconcile;
+mod replay;
+mod requests;
+mod resolve;
+mod results;
+mod runtime_stats;
+mod selftest;
+mod send_policy;
+mod service;
+mod stages;
+mod standby;
+mod stats_api;
+mod storm;
+mod stream_open;
+mod sync_start;
+mod target_limit;
+mod tls_negative;
+mod trace;
+mod transport_events;
+mod udp_stats;
+mod watchdog;
+
 use {
-    anyhow::{Context, Error, Result},
+    alloc_stats::AllocSnapshot,
+    anomaly::AnomalyTrigger,
+    anyhow::{bail, Context, Error, Result},
     bytes::Bytes,
+    calibration::MeasurementFloor,
+    checkpoint::Checkpoint,
+    clock::{Clock, ClockSource},
+    concurrency::ConcurrencyController,
+    control::{ControlConnection, ControlMessage, ControlStream},
+    drain::Drain,
+    edge::EdgeCaseCounts,
+    endpoint_failures::{EndpointFailure, EndpointFailures},
+    histogram::Histogram,
+    identity::{AcceptAnyClientCert, IdentityClass},
+    impair::{ImpairmentProxy, LossModel, PathImpairments},
+    listener::ListenerTally,
+    live_control::LiveControl,
+    metrics_push::MetricsPusher,
+    negative::NegativeScenario,
+    otlp::OtlpExport,
+    phases::{Phase, PhaseLatency},
+    pinning::PinnedClientKeys,
+    profile::ProfileSession,
     quinn::{
         crypto::rustls::{QuicClientConfig, QuicServerConfig},
-        Connection, Endpoint, EndpointConfig, ServerConfig, TokioRuntime, TransportConfig,
+        Connection, Endpoint, EndpointConfig, Runtime as _, SendStream, ServerConfig, TokioRuntime,
+        TransportConfig, WriteError,
+    },
+    rates::RateWindows,
+    replay::Recorder,
+    requests::{
+        EchoCheck, InFlightGauge, Outstanding, ResponseMode, ResponseStats, REQUEST_ID_LEN,
     },
+    results::{Environment, RunDirectory},
+    runtime_stats::RuntimeSampler,
     rustls::{
         crypto::ring::cipher_suite,
         pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
     },
+    send_policy::{ConnectionSelector, SendPolicy},
+    serde::Serialize,
+    service::{ConnectionCounters, RunSummary, RunTracker},
+    standby::StandbyPool,
+    stats_api::ConnectionRegistry,
     std::{
-        array, fs,
-        net::{IpAddr, Ipv4Addr, SocketAddr},
+        fs,
+        future::Future,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
         path::PathBuf,
+        str::FromStr,
         sync::{
-            atomic::{AtomicUsize, Ordering},
-            Arc,
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, Mutex,
         },
+        task::Poll,
         time::{Duration, Instant},
     },
     structopt::StructOpt,
+    target_limit::TargetLimits,
+    tls_negative::TlsNegativeCase,
     tokio::{
-        runtime::Runtime,
+        runtime::{Handle, Runtime},
+        sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore},
         task::{self, JoinHandle},
         time::{self, sleep_until, Instant as AsyncInstant},
     },
+    trace::{MessageTracer, TraceEvent},
     tracing::*,
+    tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt},
+    transport_events::{TransportEventTracker, TransportEvents},
+    udp_stats::{CountingSocket, RecvBatchStats},
+    watchdog::SchedulerDelay,
 };
 
 const PACKET_SIZE: usize = 1000;
+/// STOP_SENDING code of a request stream stalled past --stream-read-timeout.
+const STREAM_READ_TIMEOUT: u32 = 0x20;
 
-#[derive(StructOpt, Debug, Clone)]
+#[derive(StructOpt, Serialize, Debug, Clone)]
 #[structopt(name = "quic_bidir_test")]
 struct Opt {
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
     /// Run only the server
     #[structopt(long)]
     server_only: bool,
@@ -41,7 +140,7 @@ struct Opt {
     #[structopt(long)]
     client_only: bool,
 
-    /// Server address (IP:port) for client mode
+    /// Server address (IP:port, or host:port for the client) to use
     #[structopt(long, default_value = "0.0.0.0:11228")]
     server_address: String,
 
@@ -53,10 +152,103 @@ struct Opt {
     #[structopt(long, default_value = "10000")]
     num_packets: usize,
 
+    /// Independent logical clients run in this process, each with its own
+    /// endpoints, connections and statistics under run id <run id>-<n>
+    #[structopt(long, default_value = "1")]
+    instances: usize,
+
+    /// Identity class of the client instances, repeatable, assigned round
+    /// robin: "shared" (ephemeral source ports, no client certificate),
+    /// "random-port", "random-cert" or "random" (both). The outcome is
+    /// reported per class
+    #[structopt(long)]
+    identity: Vec<IdentityClass>,
+
+    /// Ask clients for a certificate, accepting any, to tell client
+    /// identities apart in the stats API
+    #[structopt(long)]
+    request_client_certs: bool,
+
+    /// File of hex encoded client pubkeys, one per line: the server requires
+    /// a client certificate with one of these keys and tags the connection
+    /// with it
+    #[structopt(long)]
+    expected_pubkeys: Option<PathBuf>,
+
+    /// Take streams from any QUIC client, without the control protocol:
+    /// bidirectional streams are echoed, streams and bytes are counted per
+    /// remote identity
+    #[structopt(long)]
+    listener: bool,
+
+    /// With --listener, answer no stream
+    #[structopt(long)]
+    listener_no_responses: bool,
+
     /// Number of endpoints on server side
     #[structopt(long, default_value = "8")]
     num_endpoints: usize,
 
+    /// Bind every server endpoint to its own port, counting up from the
+    /// server address port, instead of sharing one port with SO_REUSEPORT.
+    /// Clients learn the ports from the first connection and spread across them
+    #[structopt(long)]
+    distinct_ports: bool,
+
+    /// Run every server endpoint on a runtime of its own instead of one shared
+    /// runtime
+    #[structopt(long)]
+    runtime_per_endpoint: bool,
+
+    /// Worker threads of each endpoint runtime, 1 for a current-thread runtime
+    #[structopt(long, default_value = "1")]
+    endpoint_runtime_threads: usize,
+
+    /// Pin the threads of endpoint runtime i to core i (modulo the core count)
+    #[structopt(long)]
+    pin_cores: bool,
+
+    /// Handshakes the server processes concurrently, further incoming
+    /// connections are handled according to --overload-action
+    #[structopt(long)]
+    max_handshakes: Option<usize>,
+
+    /// What the server does with incoming connections beyond
+    /// --max-handshakes: "refuse" or "ignore"
+    #[structopt(long, default_value = "refuse")]
+    overload_action: OverloadAction,
+
+    /// Number of chunk buffers the server passes to each read_chunks call
+    #[structopt(long, default_value = "4")]
+    read_chunks: usize,
+
+    /// How the server shares its capacity between connections: "spawn" serves
+    /// every connection unbounded in its own task, "fair" serves at most
+    /// --stream-slots streams at once with connections taking turns
+    #[structopt(long, default_value = "spawn")]
+    connection_scheduling: ConnectionScheduling,
+
+    /// Streams served at once over all connections with
+    /// --connection-scheduling fair
+    #[structopt(long, default_value = "64")]
+    stream_slots: usize,
+
+    /// Do not accept datagrams on the endpoints of this process. A server
+    /// answers clients taking no datagrams on uni streams instead, counting
+    /// the fallbacks
+    #[structopt(long)]
+    no_datagrams: bool,
+
+    /// Milliseconds a request stream may stall without data before the
+    /// server stops it with STOP_SENDING and counts it as timed out
+    #[structopt(long)]
+    stream_read_timeout: Option<u64>,
+
+    /// Incoming connections buffered per endpoint before they are accepted,
+    /// quinn refuses connections beyond it
+    #[structopt(long)]
+    max_pending_incoming: Option<usize>,
+
     /// Server certificate
     #[structopt(long)]
     cert: Option<PathBuf>,
@@ -64,290 +256,3308 @@ struct Opt {
     /// Server key
     #[structopt(long)]
     key: Option<PathBuf>,
+
+    /// Subject alternative name (DNS name or IP) of the generated self-signed
+    /// server certificate, repeatable. Defaults to "localhost"
+    #[structopt(long)]
+    cert_san: Vec<String>,
+
+    /// Probe the server until it accepts connections before starting the client
+    #[structopt(long)]
+    wait_for_server: bool,
+
+    /// Maximum time in seconds to wait for the server with --wait-for-server
+    #[structopt(long, default_value = "30")]
+    wait_for_server_timeout: u64,
+
+    /// Unix time in seconds at which the measured window starts, so clients
+    /// and servers launched independently report aligned intervals. The
+    /// client connects first, the server aligns its interval reports
+    #[structopt(long)]
+    start_at: Option<f64>,
+
+    /// Address (IP:port) on which the server answers HTTP health checks
+    #[structopt(long)]
+    health_addr: Option<SocketAddr>,
+
+    /// Address (IP:port) of the server's HTTP JSON API: GET /stats for live
+    /// per-connection statistics, POST /reset to restart the totals, POST
+    /// /drain to shut the server down gracefully
+    #[structopt(long)]
+    control_addr: Option<SocketAddr>,
+
+    /// Seconds a shutting down server waits for the streams in flight to
+    /// complete before closing the connections
+    #[structopt(long, default_value = "10")]
+    drain_timeout: u64,
+
+    /// Instead of a run, check that handshakes fail as expected against a
+    /// local server with a broken certificate, repeatable: "expired",
+    /// "wrong-san" or "untrusted" (self-signed while verifying). The
+    /// handshake failure categories are counted
+    #[structopt(long)]
+    tls_negative: Vec<TlsNegativeCase>,
+
+    /// Instead of a run, set up a local server and client which disagree and
+    /// check the error each side surfaces and how quickly, repeatable:
+    /// "alpn-mismatch", "no-datagrams" (server takes none) or
+    /// "no-uni-streams" (server grants none)
+    #[structopt(long)]
+    negative: Vec<NegativeScenario>,
+
+    /// Run the server indefinitely, writing one summary per client run
+    #[structopt(long)]
+    service: bool,
+
+    /// Directory receiving result records. Every client run writes a
+    /// timestamped folder with its configuration, environment and results;
+    /// the service defaults to "results"
+    #[structopt(long)]
+    results_dir: Option<PathBuf>,
+
+    /// File the totals and latency histogram of a streams run are
+    /// checkpointed to, every --checkpoint-interval seconds and at its end
+    #[structopt(long)]
+    checkpoint: Option<PathBuf>,
+
+    /// Seconds between checkpoints
+    #[structopt(long, default_value = "60")]
+    checkpoint_interval: u64,
+
+    /// Continue the totals and latency histogram of an interrupted streams
+    /// run from its checkpoint, which is updated from then on unless
+    /// --checkpoint names another
+    #[structopt(long)]
+    resume: Option<PathBuf>,
+
+    /// Identifier of this client run, generated when not given
+    #[structopt(long, parse(try_from_str = parse_run_id))]
+    run_id: Option<String>,
+
+    /// Client workload: "streams", "stream-open", "datagram-flood", "idle" or
+    /// "connect-storm"
+    #[structopt(long, default_value = "streams")]
+    mode: Mode,
+
+    /// Client keep-alive interval in milliseconds, 5000 by default in idle mode
+    #[structopt(long)]
+    keep_alive_interval: Option<u64>,
+
+    /// How long to hold connections open in idle mode, in seconds
+    #[structopt(long, default_value = "600")]
+    idle_duration: u64,
+
+    /// Interval between RTT samples in idle mode, in seconds
+    #[structopt(long, default_value = "10")]
+    rtt_sample_interval: u64,
+
+    /// Exchange one round trip on every connection before the measured phase
+    #[structopt(long)]
+    prewarm: bool,
+
+    /// Where the heartbeats of the control streams run while the workload
+    /// is going: "shared", on the request connections ahead of the requests,
+    /// or "separate", on a connection of their own next to each
+    #[structopt(long, default_value = "shared")]
+    control_connection: ControlConnection,
+
+    /// Spare connections kept established per target in the streams
+    /// workload. A connection degrading past --failover-loss or --failover-rtt
+    /// hands its requests to a spare
+    #[structopt(long, default_value = "0")]
+    standby_connections: usize,
+
+    /// Packet loss in percent over a check interval at which an active
+    /// connection fails over to a standby connection
+    #[structopt(long, default_value = "5")]
+    failover_loss: f64,
+
+    /// RTT in milliseconds at which an active connection fails over to a
+    /// standby connection
+    #[structopt(long, default_value = "200")]
+    failover_rtt: u64,
+
+    /// Delay in milliseconds between starting consecutive client connects
+    #[structopt(long, default_value = "0")]
+    connect_stagger: u64,
+
+    /// Datagram size in datagram-flood mode, the largest the peer accepts by
+    /// default
+    #[structopt(long)]
+    datagram_size: Option<usize>,
+
+    /// Mix zero byte streams, one byte streams and maximum size datagrams
+    /// into the stream workload and check the server's counts of them
+    #[structopt(long)]
+    edge_cases: bool,
+
+    /// Have the server echo every request and verify the content of the
+    /// responses
+    #[structopt(long)]
+    echo: bool,
+
+    /// Answer every request with its first N bytes, so clients without our
+    /// framing can validate round trips. A server does so for all clients, a
+    /// client asks for it and counts responses not matching its requests
+    #[structopt(long)]
+    echo_prefix: Option<usize>,
+
+    /// Write N requests per stream, each framed by its length, before
+    /// finishing it, and report latency per position within the stream
+    #[structopt(long)]
+    messages_per_stream: Option<usize>,
+
+    /// Payload in bytes of every stream in stream-open mode, less than 8
+    #[structopt(long, default_value = "0")]
+    stream_payload: usize,
+
+    /// Connection attempts held in flight in connect-storm mode
+    #[structopt(long, default_value = "16")]
+    concurrency: usize,
+
+    /// In connect-storm mode, send a first request on every connection, as
+    /// 0-RTT early data once a session can be resumed, and report the 0-RTT
+    /// acceptance and its latency advantage
+    #[structopt(long = "send-0rtt")]
+    send_0rtt: bool,
+
+    /// Preset of the streams workload with asymmetric directions: "query"
+    /// sends 64 byte requests answered by 64 KiB responses, "upload" the
+    /// inverse. Each direction's throughput is reported
+    #[structopt(long)]
+    asymmetry: Option<Asymmetry>,
+
+    /// Have the server alternate between datagram and stream responses by
+    /// request id on the same connections, reporting the latency and loss of
+    /// both side by side
+    #[structopt(long)]
+    ab_response_modes: bool,
+
+    /// How requests are assigned to the connections in streams mode:
+    /// "pinned" sends the requests of every sender on its own connection,
+    /// "round-robin", "least-outstanding" (fewest requests awaiting a
+    /// response) or "random" pick a connection for every request. The
+    /// requests and latency per connection are reported
+    #[structopt(long, default_value = "pinned")]
+    send_policy: SendPolicy,
+
+    /// Requests awaiting a response over all connections to one server
+    /// address in streams mode, senders wait for the cap. Its utilization is
+    /// reported per target
+    #[structopt(long)]
+    max_inflight_per_target: Option<usize>,
+
+    /// Offered requests per second over all connections in streams mode, 0
+    /// to send as fast as possible
+    #[structopt(long, default_value = "0")]
+    rate: u64,
+
+    /// Read commands from stdin during the streams workload: "rate <n>",
+    /// "pause", "resume" and "stats"
+    #[structopt(long)]
+    interactive: bool,
+
+    /// Duration in seconds of time bound modes such as connect-storm and
+    /// datagram-flood
+    #[structopt(long, default_value = "30")]
+    duration: u64,
+
+    /// Adapt the requests in flight per connection to keep p99 latency under
+    /// --p99-target
+    #[structopt(long)]
+    auto_concurrency: bool,
+
+    /// Response latency target in milliseconds for --auto-concurrency
+    #[structopt(long, default_value = "50")]
+    p99_target: u64,
+
+    /// Milliseconds after which a request without response counts as expired
+    #[structopt(long)]
+    response_timeout: Option<u64>,
+
+    /// Route the client through the impairment relay and start dropping
+    /// packets above --pmtu-blackhole-size after this many seconds
+    #[structopt(long)]
+    pmtu_blackhole_after: Option<u64>,
+
+    /// Largest UDP payload passed by the relay once the PMTU black hole starts
+    #[structopt(long, default_value = "1252")]
+    pmtu_blackhole_size: usize,
+
+    /// Route the client through the impairment relay dropping packets in
+    /// bursts: "ge:p,r,k,h" for the Gilbert-Elliott model with the chances
+    /// p of entering and r of leaving the bad state, and k and h of
+    /// delivering a packet in the good and the bad state
+    #[structopt(long)]
+    emulate_loss_model: Option<LossModel>,
+
+    /// Loss model of the client to server direction of the relay, in place
+    /// of --emulate-loss-model
+    #[structopt(long)]
+    loss_to_server: Option<LossModel>,
+
+    /// Loss model of the server to client direction of the relay, in place
+    /// of --emulate-loss-model
+    #[structopt(long)]
+    loss_to_client: Option<LossModel>,
+
+    /// Milliseconds the relay delays packets to the server
+    #[structopt(long)]
+    delay_to_server: Option<u64>,
+
+    /// Milliseconds the relay delays packets to the client
+    #[structopt(long)]
+    delay_to_client: Option<u64>,
+
+    /// Mbit/s the relay passes to the server, per client endpoint
+    #[structopt(long)]
+    bandwidth_to_server: Option<f64>,
+
+    /// Mbit/s the relay passes to the client, per client endpoint
+    #[structopt(long)]
+    bandwidth_to_client: Option<f64>,
+
+    /// QUIC version to use: "v1", "draft-29" or a hex version number. Restricts
+    /// the versions the server supports and sets the client's initial version
+    #[structopt(long, parse(try_from_str = parse_quic_version))]
+    quic_version: Option<u32>,
+
+    /// TLS server name (SNI) the client connects with
+    #[structopt(long, default_value = "localhost")]
+    server_name: String,
+
+    /// Verify the server certificate against --ca-cert instead of accepting any
+    #[structopt(long)]
+    verify_cert: bool,
+
+    /// PEM file with the certificates trusted by the client with --verify-cert
+    #[structopt(long)]
+    ca_cert: Option<PathBuf>,
+
+    /// ALPN protocol offered by the client and accepted by the server
+    #[structopt(long, default_value = "perf")]
+    alpn: String,
+
+    /// PEM file with a certificate the client presents, with --client-key
+    #[structopt(long)]
+    client_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of --client-cert
+    #[structopt(long)]
+    client_key: Option<PathBuf>,
+
+    /// Use the client as a stream load generator against any QUIC server: no
+    /// control stream is opened and no responses are awaited, only the send
+    /// side is measured. Streams mode only
+    #[structopt(long)]
+    no_response_expected: bool,
+
+    /// Push interval statistics to InfluxDB during the run, as line protocol
+    /// to http://host:port/write?db=name or udp://host:port
+    #[structopt(long)]
+    influx_url: Option<String>,
+
+    /// Push interval statistics to this Graphite plaintext listener,
+    /// host:port
+    #[structopt(long)]
+    graphite_addr: Option<String>,
+
+    /// Shell command capturing packets, started for --capture-secs when a
+    /// request fails or a response exceeds --anomaly-latency, e.g.
+    /// "tcpdump -i eth0 -w anomaly-{time}.pcap udp"
+    #[structopt(long)]
+    pcap_on_anomaly: Option<String>,
+
+    /// Response latency in milliseconds counting as an anomaly
+    #[structopt(long, default_value = "100")]
+    anomaly_latency: u64,
+
+    /// Seconds every anomaly capture runs
+    #[structopt(long, default_value = "10")]
+    capture_secs: u64,
+
+    /// Record the connection, gap since the previous request and size of
+    /// every request of the streams workload to this CSV file
+    #[structopt(long)]
+    record: Option<PathBuf>,
+
+    /// Send the requests recorded with --record again, with the recorded
+    /// gaps and sizes, instead of --num-packets fixed size requests
+    #[structopt(long)]
+    replay: Option<PathBuf>,
+
+    /// Export spans and interval statistics over OTLP/gRPC to this collector,
+    /// e.g. http://localhost:4317. Stream sends and response receives are
+    /// sampled with --trace-sample. Needs a build with --features otlp
+    #[structopt(long)]
+    otlp_endpoint: Option<String>,
+
+    /// Write per-message events of sampled requests to this JSON lines file
+    #[structopt(long)]
+    trace_file: Option<PathBuf>,
+
+    /// Fraction of the requests recorded with --trace-file
+    #[structopt(long, default_value = "0.01")]
+    trace_sample: f64,
+
+    /// Profile the CPU during the measured window and write flamegraphs to
+    /// <path>-client.svg and <path>-server.svg. A standalone server writes
+    /// <path>-<run id>-server.svg per run. Needs a build with --features pprof
+    #[structopt(long)]
+    pprof: Option<PathBuf>,
+
+    /// Clock timing requests: "instant", "monotonic-raw" or "tsc". The TSC
+    /// needs an x86_64 build with --features tsc
+    #[structopt(long, default_value = "instant")]
+    clock: ClockSource,
+
+    /// Split the round trip of every nth request of the streams workload into
+    /// the open_uni wait, the write and the gap from the FIN to the response,
+    /// reporting percentiles per stage
+    #[structopt(long)]
+    stage_sample: Option<u64>,
+
+    /// Log the details of only every nth request and response, 0 for none.
+    /// Adjustable while running with POST /sample-rate/<n> on the stats API
+    /// or the interactive "sample <n>" command
+    #[structopt(long, default_value = "0")]
+    sample_rate: u64,
+
+    /// Milliseconds of runtime scheduling delay above which a stall is reported
+    #[structopt(long, default_value = "20")]
+    stall_threshold: u64,
+}
+
+#[derive(Debug, Clone, StructOpt, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Command {
+    /// Run a short loopback exchange in every traffic and response mode,
+    /// checking nothing is lost or corrupted, and exit non-zero on failure
+    Selftest {
+        /// Seconds each case may take before it fails
+        #[structopt(long, default_value = "5")]
+        timeout: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Mode {
+    /// Send `num_packets` streams per connection and receive datagram responses.
+    Streams,
+    /// Open and finish `num_packets` near empty streams per connection.
+    StreamOpen,
+    /// Exchange datagrams at full rate in both directions for `duration`.
+    DatagramFlood,
+    /// Send nothing but keep-alives and monitor connection survival and RTT.
+    Idle,
+    /// Keep `concurrency` handshakes in flight to stress connection setup.
+    ConnectStorm,
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "streams" => Ok(Mode::Streams),
+            "stream-open" => Ok(Mode::StreamOpen),
+            "datagram-flood" => Ok(Mode::DatagramFlood),
+            "idle" => Ok(Mode::Idle),
+            "connect-storm" => Ok(Mode::ConnectStorm),
+            _ => bail!(
+                "unknown mode {s:?}, expected \"streams\", \"stream-open\", \
+                 \"datagram-flood\", \"idle\" or \"connect-storm\""
+            ),
+        }
+    }
+}
+
+/// Request and response sizes of the streams workload with --asymmetry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Asymmetry {
+    /// Small requests with large responses, like RPC queries.
+    Query,
+    /// Large requests with small responses.
+    Upload,
+}
+
+impl Asymmetry {
+    const SMALL: usize = 64;
+    const LARGE: usize = 64 * 1024;
+
+    fn request_size(self) -> usize {
+        match self {
+            Asymmetry::Query => Self::SMALL,
+            Asymmetry::Upload => Self::LARGE,
+        }
+    }
+
+    fn response_size(self) -> usize {
+        match self {
+            Asymmetry::Query => Self::LARGE,
+            Asymmetry::Upload => Self::SMALL,
+        }
+    }
+}
+
+impl FromStr for Asymmetry {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "query" => Ok(Asymmetry::Query),
+            "upload" => Ok(Asymmetry::Upload),
+            _ => bail!("unknown asymmetry {s:?}, expected \"query\" or \"upload\""),
+        }
+    }
+}
+
+/// How the server shares its capacity between connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ConnectionScheduling {
+    /// Every connection serves its streams in its own task, unbounded.
+    Spawn,
+    /// Streams are served under a global limit of --stream-slots, granted
+    /// first come first served, so connections take turns round robin.
+    Fair,
+}
+
+impl FromStr for ConnectionScheduling {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "spawn" => Ok(ConnectionScheduling::Spawn),
+            "fair" => Ok(ConnectionScheduling::Fair),
+            _ => bail!("unknown connection scheduling {s:?}, expected \"spawn\" or \"fair\""),
+        }
+    }
+}
+
+/// Handling of incoming connections while the handshake limit is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum OverloadAction {
+    /// Answer with CONNECTION_REFUSED.
+    Refuse,
+    /// Drop the packet silently, the client retries until it times out.
+    Ignore,
+}
+
+impl FromStr for OverloadAction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "refuse" => Ok(OverloadAction::Refuse),
+            "ignore" => Ok(OverloadAction::Ignore),
+            _ => bail!("unknown overload action {s:?}, expected \"refuse\" or \"ignore\""),
+        }
+    }
+}
+
+fn parse_run_id(run_id: &str) -> Result<String> {
+    control::validate_run_id(run_id)?;
+    Ok(run_id.to_string())
+}
+
+fn parse_quic_version(version: &str) -> Result<u32> {
+    match version {
+        "v1" => Ok(1),
+        "draft-29" => Ok(0xff00_001d),
+        "v2" => bail!("QUIC v2 is not supported by quinn 0.11"),
+        _ => match version.strip_prefix("0x") {
+            Some(hex) => Ok(u32::from_str_radix(hex, 16)?),
+            None => bail!("unknown QUIC version {version:?}"),
+        },
+    }
+}
+
+/// Counters shared by all server endpoints and connections.
+struct ServerStats {
+    /// Streams received since the last rate sample, reset every second by
+    /// `report_stats`.
+    total_received: AtomicUsize,
+    /// Streams too short to carry a request id since the last report, which
+    /// are not answered.
+    bare_streams: AtomicUsize,
+    /// Time spent waiting in each phase of serving requests since the last
+    /// report.
+    phases: PhaseLatency,
+    /// Chunk buffers per `read_chunks` call.
+    read_chunks: usize,
+    /// `read_chunks` calls returning data and the chunks they returned since
+    /// the last report.
+    read_calls: AtomicUsize,
+    chunks_read: AtomicUsize,
+    /// Connections which completed the handshake and are still open.
+    active_connections: AtomicUsize,
+    start_time: Instant,
+    /// Open connections, for the stats API.
+    connections: ConnectionRegistry,
+    runs: RunTracker,
+    /// Where completed runs are recorded, only set in service mode.
+    results_dir: Option<PathBuf>,
+    /// Flamegraph path prefix of a standalone server. With the client in the
+    /// same process, the client's profile covers the server as well.
+    profile_prefix: Option<PathBuf>,
+    /// The run being profiled, only one at a time as the profiler is global.
+    profile: Mutex<Option<(String, ProfileSession)>>,
+    /// Distinct ports of the server endpoints, announced to clients.
+    endpoint_ports: Vec<u16>,
+    /// Bounds the handshakes in progress with --max-handshakes.
+    handshake_slots: Option<Arc<Semaphore>>,
+    overload_action: OverloadAction,
+    /// Incoming connections turned away by the handshake limit since the
+    /// last report.
+    refused_handshakes: AtomicUsize,
+    ignored_handshakes: AtomicUsize,
+    /// Endpoints which stopped while the others went on serving.
+    endpoint_failures: EndpointFailures,
+    /// Graceful shutdown on a signal or POST /drain.
+    drain: Arc<Drain>,
+    /// Time from the first chunk to the FIN of the request streams since the
+    /// last report, showing slowly trickling streams.
+    stream_completion: Mutex<Histogram>,
+    stream_read_timeout: Option<Duration>,
+    scheduling: ConnectionScheduling,
+    /// Bounds the streams served at once with fair connection scheduling.
+    stream_slots: Option<Arc<Semaphore>>,
+    /// Time from accepting a request stream to sending its response since
+    /// the last report, including the wait for a stream slot.
+    stream_service: Mutex<Histogram>,
+    /// Request streams stopped for stalling since the last report.
+    timed_out_streams: AtomicUsize,
+    /// Responses sent on a stream since the last report because the client
+    /// takes no datagrams.
+    datagram_fallbacks: AtomicUsize,
+    /// Streams of clients without the control protocol, with --listener.
+    listener: Option<ListenerTally>,
+    /// Request bytes echoed to every client with --echo-prefix, 0 for none.
+    echo_prefix: usize,
+    /// Whether clients are identified by their pubkeys, with
+    /// --expected-pubkeys.
+    pinned: bool,
+}
+
+impl ServerStats {
+    fn new(opt: &Opt, endpoint_ports: Vec<u16>) -> Self {
+        let standalone = (opt.server_only || opt.service) && !opt.client_only;
+        Self {
+            total_received: AtomicUsize::new(0),
+            bare_streams: AtomicUsize::new(0),
+            phases: PhaseLatency::default(),
+            read_chunks: opt.read_chunks.max(1),
+            read_calls: AtomicUsize::new(0),
+            chunks_read: AtomicUsize::new(0),
+            active_connections: AtomicUsize::new(0),
+            start_time: Instant::now(),
+            connections: ConnectionRegistry::default(),
+            runs: RunTracker::default(),
+            results_dir: opt.service.then(|| {
+                opt.results_dir
+                    .clone()
+                    .unwrap_or_else(|| PathBuf::from("results"))
+            }),
+            profile_prefix: opt.pprof.clone().filter(|_| standalone),
+            profile: Mutex::default(),
+            endpoint_ports,
+            handshake_slots: opt.max_handshakes.map(|max| Arc::new(Semaphore::new(max))),
+            overload_action: opt.overload_action,
+            refused_handshakes: AtomicUsize::new(0),
+            ignored_handshakes: AtomicUsize::new(0),
+            endpoint_failures: EndpointFailures::default(),
+            drain: Arc::new(Drain::new(Duration::from_secs(opt.drain_timeout))),
+            stream_completion: Mutex::default(),
+            stream_read_timeout: opt.stream_read_timeout.map(Duration::from_millis),
+            scheduling: opt.connection_scheduling,
+            stream_slots: (opt.connection_scheduling == ConnectionScheduling::Fair)
+                .then(|| Arc::new(Semaphore::new(opt.stream_slots.max(1)))),
+            stream_service: Mutex::default(),
+            timed_out_streams: AtomicUsize::new(0),
+            datagram_fallbacks: AtomicUsize::new(0),
+            listener: opt
+                .listener
+                .then(|| ListenerTally::new(!opt.listener_no_responses)),
+            echo_prefix: opt.echo_prefix.unwrap_or_default(),
+            pinned: opt.expected_pubkeys.is_some(),
+        }
+    }
+
+    /// Start profiling `run_id` unless another run is being profiled.
+    fn start_run_profile(&self, run_id: &str) {
+        if self.profile_prefix.is_none() {
+            return;
+        }
+        let mut profile = self.profile.lock().unwrap();
+        if profile.is_some() {
+            return;
+        }
+        match ProfileSession::start() {
+            Ok(session) => *profile = Some((run_id.to_string(), session)),
+            Err(err) => warn!("Not profiling run {run_id}: {err:#}"),
+        }
+    }
+
+    /// Log and record the summary of a completed run.
+    fn run_completed(&self, mut summary: RunSummary) {
+        summary.degraded = self.endpoint_failures.is_degraded();
+        let run_id = &summary.run_id;
+        self.finish_run_profile(run_id);
+        info!(
+            "Run {run_id} completed{}: {} connections, {} streams, {} bytes, {} responses in \
+             {:.2}s",
+            if summary.ended_by_client {
+                ""
+            } else {
+                " with its connections"
+            },
+            summary.connections,
+            summary.streams_received,
+            summary.bytes_received,
+            summary.responses_sent,
+            summary.duration_secs,
+        );
+        if let Some(results_dir) = &self.results_dir {
+            if let Err(err) = service::write_run_summary(results_dir, &summary) {
+                error!("Failed to record run {run_id}: {err:#}");
+            }
+        }
+    }
+
+    fn finish_run_profile(&self, run_id: &str) {
+        let Some(prefix) = &self.profile_prefix else {
+            return;
+        };
+        let mut profile = self.profile.lock().unwrap();
+        if profile.as_ref().is_none_or(|(id, _)| id != run_id) {
+            return;
+        }
+        let (_, session) = profile.take().unwrap();
+        let mut prefix = prefix.clone().into_os_string();
+        prefix.push(format!("-{run_id}"));
+        if let Err(err) = session.finish(&PathBuf::from(prefix)) {
+            error!("Failed to write the CPU profile of run {run_id}: {err:#}");
+        }
+    }
 }
 
 struct Server {
-    #[allow(dead_code)]
     runtime: Runtime,
 
     handles: Vec<JoinHandle<Result<(), Error>>>,
     local_address: SocketAddr,
+
+    /// Receives one message per endpoint once its accept loop is running.
+    ready_receiver: mpsc::Receiver<()>,
+    num_endpoints: usize,
+    stats: Arc<ServerStats>,
 }
 
 impl Server {
     fn create_server(opt: &Opt, addr: SocketAddr) -> Self {
-        let runtime = rt("quicbench".to_string());
+        let runtime = rt(profile::SERVER_THREAD_NAME.to_string());
         let _guard = runtime.enter();
 
-        let endpoints =
-            setup_server(&opt, addr, opt.num_endpoints).expect("Failed to create server");
+        let endpoint_runtimes: Vec<_> = if opt.runtime_per_endpoint {
+            let core_ids = if opt.pin_cores {
+                core_affinity::get_core_ids().unwrap_or_default()
+            } else {
+                Vec::new()
+            };
+            (0..opt.num_endpoints)
+                .map(|i| {
+                    let core_id = (!core_ids.is_empty()).then(|| core_ids[i % core_ids.len()]);
+                    endpoint_rt(i, opt.endpoint_runtime_threads, core_id)
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let recv_batches = Arc::new(RecvBatchStats::default());
+        let endpoints = setup_server(
+            opt,
+            addr,
+            opt.num_endpoints,
+            &endpoint_runtimes,
+            recv_batches.clone(),
+        )
+        .expect("Failed to create server");
         let mut handles = Vec::new();
-        let total_received = Arc::new(AtomicUsize::new(0));
+        let mut endpoint_ports: Vec<_> = endpoints
+            .iter()
+            .map(|endpoint| endpoint.local_addr().unwrap().port())
+            .collect();
+        endpoint_ports.dedup();
+        let stats = Arc::new(ServerStats::new(opt, endpoint_ports));
+        if (opt.server_only || opt.service) && !opt.client_only {
+            tokio::spawn(drain::drain_on_signal(stats.drain.clone()));
+        }
+
+        let (scheduler_delay, _) =
+            watchdog::spawn("Server", Duration::from_millis(opt.stall_threshold));
+        spawn_named(
+            "stats-reporter",
+            &Handle::current(),
+            report_stats(stats.clone(), scheduler_delay, recv_batches, opt.start_at),
+        );
+        if let Some(health_addr) = opt.health_addr {
+            let stats = stats.clone();
+            tokio::spawn(async move {
+                if let Err(err) = health::run_health_server(health_addr, stats).await {
+                    error!("Health check server on {health_addr} failed: {err:#}");
+                }
+            });
+        }
+        if let Some(control_addr) = opt.control_addr {
+            let stats = stats.clone();
+            tokio::spawn(async move {
+                if let Err(err) = stats_api::run_stats_api(control_addr, stats).await {
+                    error!("Stats API on {control_addr} failed: {err:#}");
+                }
+            });
+        }
+
+        let local_address = endpoints[0].local_addr().unwrap();
+        let num_endpoints = endpoints.len();
+        let (ready_sender, ready_receiver) = mpsc::channel(num_endpoints);
+        for (i, endpoint) in endpoints.into_iter().enumerate() {
+            let server = run_server(i, endpoint, stats.clone(), ready_sender.clone());
+            let runtime = endpoint_runtimes
+                .get(i)
+                .cloned()
+                .unwrap_or_else(Handle::current);
+            let task = spawn_named(&format!("server-endpoint-{i}"), &runtime, server);
+            handles.push(task);
+        }
+
+        Self {
+            runtime,
+            handles,
+            local_address,
+            ready_receiver,
+            num_endpoints,
+            stats,
+        }
+    }
+
+    /// Wait until every endpoint has entered its accept loop.
+    async fn wait_ready(&mut self) {
+        for _ in 0..self.num_endpoints {
+            if self.ready_receiver.recv().await.is_none() {
+                break;
+            }
+        }
+        info!("Server ready on {}", self.local_address);
+    }
+
+    async fn join(self) {
+        for handle in self.handles {
+            let _ = handle.await;
+        }
+        self.stats.drain.report();
+        self.stats.endpoint_failures.report(self.num_endpoints);
+        // Dropping the runtime would block inside the async context.
+        self.runtime.shutdown_background();
+    }
+}
+fn main() {
+    tokio::runtime::Builder::new_multi_thread()
+        .thread_name_fn(numbered_thread_names("client"))
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async_main());
+}
+
+async fn async_main() {
+    let mut opt = Opt::from_args();
+    let otlp = opt.otlp_endpoint.as_ref().map(|endpoint| {
+        Arc::new(OtlpExport::start(endpoint).expect("Failed to start the OTLP export"))
+    });
+    // The OTLP layer is boxed for the bare registry, so it comes first.
+    tracing_subscriber::registry()
+        .with(otlp.as_ref().map(|otlp| otlp.layer()))
+        .with(LevelFilter::INFO)
+        .with(tracing_subscriber::fmt::layer().with_thread_names(true))
+        .init();
+    results::install_panic_hook();
+    let environment = Environment::capture();
+    environment.report();
+    if opt.sample_rate > 0 {
+        packet_log::set_sample_rate(opt.sample_rate);
+    }
+
+    if let Some(Command::Selftest { timeout }) = &opt.command {
+        if !selftest::run(&opt, &environment, Duration::from_secs(*timeout)).await {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if !opt.tls_negative.is_empty() || !opt.negative.is_empty() {
+        if !opt.tls_negative.is_empty() {
+            if let Err(err) = tls_negative::run_cases(&opt.tls_negative).await {
+                error!("TLS negative path checks failed: {err:#}");
+            }
+        }
+        if !opt.negative.is_empty() {
+            if let Err(err) = negative::run_scenarios(&opt.negative).await {
+                error!("Negative scenarios failed: {err:#}");
+            }
+        }
+        return;
+    }
+
+    match (opt.server_only || opt.service, opt.client_only) {
+        (true, false) => {
+            let addr = opt
+                .server_address
+                .parse::<SocketAddr>()
+                .expect("Exepected correct server address in IP:port format"); // SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+            let server = Server::create_server(&opt, addr);
+            server.join().await;
+        }
+        (false, true) => {
+            let _ = run_client(&opt, &environment, otlp.clone()).await;
+        }
+        _ => {
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+            opt.server_address = addr.to_string();
+
+            let mut server = Server::create_server(&opt, addr);
+
+            opt.server_address = server.local_address.to_string();
+            server.wait_ready().await;
+            if let Ok(summaries) = run_client(&opt, &environment, otlp.clone()).await {
+                if opt.resume.is_some() {
+                    info!("Not reconciling with the server, the client totals span resumed runs");
+                } else if !summaries.is_empty() {
+                    reconcile::report(&summaries, &server.stats, !opt.no_response_expected);
+                }
+            }
+            server.join().await;
+        }
+    }
+}
+
+async fn report_stats(
+    stats: Arc<ServerStats>,
+    scheduler_delay: Arc<SchedulerDelay>,
+    recv_batches: Arc<RecvBatchStats>,
+    start_at: Option<f64>,
+) {
+    let mut runtime = RuntimeSampler::current();
+    if let Some(start_at) = start_at {
+        sync_start::wait_until(start_at, "Server").await;
+        // The first interval starts now, drop what came before.
+        stats.total_received.swap(0, Ordering::Relaxed);
+        stats.bare_streams.swap(0, Ordering::Relaxed);
+        stats.read_calls.swap(0, Ordering::Relaxed);
+        stats.chunks_read.swap(0, Ordering::Relaxed);
+        stats.phases.take();
+        std::mem::take(&mut *stats.stream_service.lock().unwrap());
+        std::mem::take(&mut *stats.stream_completion.lock().unwrap());
+        recv_batches.take();
+        scheduler_delay.take();
+        runtime.sample();
+    }
+    let mut last_allocations = AllocSnapshot::take();
+    let mut last_datapoint = AsyncInstant::now();
+    let mut rate_sample = time::interval(Duration::from_secs(1));
+    let (mut received, mut interval_received) = (0, 0);
+    let mut received_rates = RateWindows::default();
+    loop {
+        rate_sample.tick().await;
+        let newly_received = stats.total_received.swap(0, Ordering::Relaxed);
+        received += newly_received as u64;
+        interval_received += newly_received;
+        received_rates.sample(received);
+        if last_datapoint.elapsed().as_secs() >= 5 {
+            let total_received = std::mem::take(&mut interval_received);
+            let bare_streams = stats.bare_streams.swap(0, Ordering::Relaxed);
+            info!(
+                "Received packets: {total_received}, {bare_streams} of them without payload, \
+                 rate {received_rates}"
+            );
+            if stats.stream_read_timeout.is_some() {
+                info!(
+                    "Streams timed out reading: {}",
+                    stats.timed_out_streams.swap(0, Ordering::Relaxed)
+                );
+            }
+            let datagram_fallbacks = stats.datagram_fallbacks.swap(0, Ordering::Relaxed);
+            if datagram_fallbacks > 0 {
+                info!("Responses on streams to clients without datagrams: {datagram_fallbacks}");
+            }
+            if let Some(listener) = &stats.listener {
+                listener.report();
+            }
+            if stats.handshake_slots.is_some() {
+                info!(
+                    "Handshakes over the limit: {} refused, {} ignored",
+                    stats.refused_handshakes.swap(0, Ordering::Relaxed),
+                    stats.ignored_handshakes.swap(0, Ordering::Relaxed),
+                );
+            }
+            let read_calls = stats.read_calls.swap(0, Ordering::Relaxed);
+            let chunks_read = stats.chunks_read.swap(0, Ordering::Relaxed);
+            info!(
+                "Reads: {read_calls} read_chunks calls of up to {} chunks, {:.2} chunks per call",
+                stats.read_chunks,
+                chunks_read as f64 / read_calls.max(1) as f64,
+            );
+            info!("Server phases: {}", stats.phases.take());
+            info!(
+                "Stream service time with {:?} scheduling, accept to response: {}",
+                stats.scheduling,
+                std::mem::take(&mut *stats.stream_service.lock().unwrap())
+            );
+            info!(
+                "Stream completion, first chunk to FIN: {}",
+                std::mem::take(&mut *stats.stream_completion.lock().unwrap())
+            );
+            info!("UDP receive: {}", recv_batches.take());
+            info!("Server runtime: {}", runtime.sample());
+            let (delay, stalls) = scheduler_delay.take();
+            info!("Server scheduler delay: {delay}, {stalls} stalls");
+            if let Some(last) = last_allocations {
+                let allocations = AllocSnapshot::take().unwrap();
+                info!("Allocations: {}", allocations.since(&last));
+                last_allocations = Some(allocations);
+            }
+            last_datapoint = AsyncInstant::now();
+        }
+    }
+}
+
+async fn run_server(
+    index: usize,
+    endpoint: Endpoint,
+    stats: Arc<ServerStats>,
+    ready_sender: mpsc::Sender<()>,
+) -> Result<()> {
+    let local_addr = endpoint.local_addr()?;
+    info!("Server listening on {local_addr}");
+    let _ = ready_sender.send(()).await;
+    drop(ready_sender);
+
+    loop {
+        let handshake = tokio::select! {
+            handshake = endpoint.accept() => match handshake {
+                Some(handshake) => handshake,
+                // Not closed by a drain, the endpoint itself failed.
+                None => {
+                    stats
+                        .endpoint_failures
+                        .record(index, Some(local_addr), "stopped accepting connections");
+                    break;
+                }
+            },
+            () = stats.drain.started() => {
+                // New connections are refused from now on.
+                endpoint.set_server_config(None);
+                endpoint.wait_idle().await;
+                break;
+            }
+        };
+        info!(
+            "Got incoming connection from {:?}",
+            handshake.remote_address()
+        );
+        let permit = match &stats.handshake_slots {
+            Some(slots) => match slots.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    match stats.overload_action {
+                        OverloadAction::Refuse => {
+                            stats.refused_handshakes.fetch_add(1, Ordering::Relaxed);
+                            handshake.refuse();
+                        }
+                        OverloadAction::Ignore => {
+                            stats.ignored_handshakes.fetch_add(1, Ordering::Relaxed);
+                            handshake.ignore();
+                        }
+                    }
+                    continue;
+                }
+            },
+            None => None,
+        };
+        let stats = stats.clone();
+        let span = info_span!(
+            "conn",
+            conn_id = field::Empty,
+            peer_conn_id = field::Empty,
+            local = %local_addr,
+            remote = %handshake.remote_address(),
+            run_id = field::Empty,
+            pubkey = field::Empty,
+        );
+        tokio::spawn(
+            async move {
+                if let Err(e) = server_handle_connection(handshake, permit, stats).await {
+                    info!("connection lost: {:#}", e);
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    Ok(())
+}
+
+/// Serve one connection. `handshake_permit` holds a slot of the handshake
+/// limit until the handshake completed.
+async fn server_handle_connection(
+    handshake: quinn::Incoming,
+    handshake_permit: Option<OwnedSemaphorePermit>,
+    stats: Arc<ServerStats>,
+) -> Result<()> {
+    let connection = handshake.await.context("handshake failed")?;
+    drop(handshake_permit);
+    let connected_at = Instant::now();
+    Span::current().record("conn_id", connection.stable_id());
+    if let Some(pubkey) = pinning::client_pubkey(&connection).filter(|_| stats.pinned) {
+        Span::current().record("pubkey", pubkey.as_str());
+    }
+    info!("{} connected", connection.remote_address());
+    stats.active_connections.fetch_add(1, Ordering::Relaxed);
+    let counters = Arc::new(ConnectionCounters::default());
+    stats.connections.opened(&connection, counters.clone());
+    let settings = Arc::new(ResponseSettings::default());
+    settings
+        .echo_prefix
+        .store(stats.echo_prefix, Ordering::Relaxed);
+    // Listener clients speak no control protocol, their bidirectional
+    // streams are requests as well.
+    let listener_identity = stats.listener.as_ref().map(|listener| {
+        let identity = listener::remote_identity(&connection, stats.pinned);
+        info!("Listening to {identity}");
+        listener.opened(&identity, counters.clone());
+        settings
+            .silent
+            .store(!listener.responds(), Ordering::Relaxed);
+        identity
+    });
+    let control = {
+        let (connection, stats) = (connection.clone(), stats.clone());
+        let (counters, settings) = (counters.clone(), settings.clone());
+        async move {
+            match &stats.listener {
+                Some(_) => (None, false),
+                None => drive_control(connection, stats.clone(), counters, settings).await,
+            }
+        }
+    };
+    let bidi_streams = async {
+        if let Some(listener) = &stats.listener {
+            listener::drive_bidi_streams(
+                connection.clone(),
+                stats.clone(),
+                counters.clone(),
+                listener.responds(),
+            )
+            .await
+        }
+    };
+    let ((run_id, run_ended), result, (), (), ()) = tokio::join!(
+        control,
+        drive_stream(
+            connection.clone(),
+            stats.clone(),
+            counters.clone(),
+            settings
+        ),
+        drive_server_datagrams(connection.clone(), counters.clone()),
+        stats.drain.drain_connection(&connection, &counters),
+        bidi_streams,
+    );
+    stats.active_connections.fetch_sub(1, Ordering::Relaxed);
+    stats.connections.closed(&connection);
+    if let (Some(listener), Some(identity)) = (&stats.listener, &listener_identity) {
+        listener.closed(identity, &counters);
+    }
+    info!(
+        "Connection closed: {} streams, {} bytes, {} responses ({} on streams for lack of \
+         datagram support), {} streams timed out, reason {:?}",
+        counters.streams_received.load(Ordering::Relaxed),
+        counters.bytes_received.load(Ordering::Relaxed),
+        counters.responses_sent.load(Ordering::Relaxed),
+        counters.datagram_fallbacks.load(Ordering::Relaxed),
+        counters.streams_timed_out.load(Ordering::Relaxed),
+        connection.close_reason(),
+    );
+    let transport = connection.stats();
+    let lifetime = connected_at.elapsed();
+    report_wire_efficiency(
+        "client->server",
+        counters.bytes_received.load(Ordering::Relaxed) as u64,
+        transport.udp_rx.bytes,
+        None,
+        lifetime,
+    );
+    report_wire_efficiency(
+        "server->client",
+        counters.bytes_sent.load(Ordering::Relaxed) as u64,
+        transport.udp_tx.bytes,
+        Some(transport.path.lost_bytes),
+        lifetime,
+    );
+
+    if let Some(run_id) = run_id {
+        // A connection announcing the end of the run was accounted then.
+        if !run_ended {
+            if let Some(summary) = stats.runs.connection_closed(&run_id, &counters) {
+                stats.run_completed(summary);
+            }
+        }
+    } else if stats.results_dir.is_some() {
+        debug!(
+            "Connection from {} never announced a run id, not recorded",
+            connection.remote_address()
+        );
+    }
+    result?;
+    Ok(())
+}
+
+/// How the server answers the requests of a connection, as asked for by the
+/// client on the control stream.
+#[derive(Default)]
+struct ResponseSettings {
+    /// Answer with the whole request.
+    echo: AtomicBool,
+    /// Size of the responses, 0 for `PACKET_SIZE`.
+    size: AtomicUsize,
+    /// Alternate between datagram and stream responses by request id.
+    ab_modes: AtomicBool,
+    /// Answer no request, for listener clients with --listener-no-responses.
+    silent: AtomicBool,
+    /// Answer with the first this many bytes of the request, 0 for none.
+    echo_prefix: AtomicUsize,
+    /// Requests are framed by their length, several per stream.
+    framed: AtomicBool,
+}
+
+/// Serve the control stream of a connection, returning the run id announced
+/// by the client and whether the client announced the end of the run.
+/// `settings` are updated as the client asks for echoed requests or another
+/// response size.
+async fn drive_control(
+    connection: Connection,
+    stats: Arc<ServerStats>,
+    counters: Arc<ConnectionCounters>,
+    settings: Arc<ResponseSettings>,
+) -> (Option<String>, bool) {
+    let mut control = match ControlStream::accept(&connection).await {
+        Ok(control) => control,
+        Err(err) => {
+            debug!(
+                "No control stream from {}: {err:#}",
+                connection.remote_address()
+            );
+            return (None, false);
+        }
+    };
+
+    let mut run_id = None;
+    let mut run_ended = false;
+    let mut went_away = false;
+    loop {
+        let message = tokio::select! {
+            message = control.recv() => message,
+            () = stats.drain.started(), if !went_away => {
+                went_away = true;
+                if let Err(err) = control.send(&ControlMessage::GoAway).await {
+                    debug!("Failed to send go away: {err:#}");
+                    break;
+                }
+                continue;
+            }
+        };
+        match message {
+            Ok(Some(ControlMessage::Hello {
+                run_id: id,
+                connection_id,
+            })) => {
+                info!(
+                    "{} joined run {id} as client connection {connection_id}",
+                    connection.remote_address()
+                );
+                if run_id.is_none() {
+                    Span::current().record("run_id", id.as_str());
+                    Span::current().record("peer_conn_id", connection_id);
+                    stats.runs.connection_opened(&id);
+                    stats.connections.joined_run(&connection, &id);
+                    stats.start_run_profile(&id);
+                    run_id = Some(id);
+                }
+                let ports = stats.endpoint_ports.clone();
+                if let Err(err) = control.send(&ControlMessage::Endpoints { ports }).await {
+                    debug!("Failed to announce the endpoints: {err:#}");
+                    break;
+                }
+            }
+            Ok(Some(ControlMessage::Ping { nonce })) => {
+                if let Err(err) = control.send(&ControlMessage::Pong { nonce }).await {
+                    debug!("Failed to answer ping: {err:#}");
+                    break;
+                }
+            }
+            Ok(Some(ControlMessage::Echo)) => {
+                debug!("Echoing requests of {}", connection.remote_address());
+                settings.echo.store(true, Ordering::Relaxed);
+            }
+            Ok(Some(ControlMessage::EchoPrefix { len })) => {
+                debug!(
+                    "Echoing the first {len} bytes of the requests of {}",
+                    connection.remote_address()
+                );
+                settings.echo_prefix.store(len, Ordering::Relaxed);
+            }
+            Ok(Some(ControlMessage::ResponseSize { size })) => {
+                debug!(
+                    "Answering requests of {} with {size} bytes",
+                    connection.remote_address()
+                );
+                settings.size.store(size, Ordering::Relaxed);
+            }
+            Ok(Some(ControlMessage::AbResponses)) => {
+                debug!(
+                    "Alternating response modes for {}",
+                    connection.remote_address()
+                );
+                settings.ab_modes.store(true, Ordering::Relaxed);
+            }
+            Ok(Some(ControlMessage::Framed)) => {
+                debug!("Reading framed requests of {}", connection.remote_address());
+                settings.framed.store(true, Ordering::Relaxed);
+            }
+            Ok(Some(ControlMessage::Flood { size, duration })) => {
+                let received = &counters.datagrams_received;
+                match flood::serve_flood(&connection, received, size, duration).await {
+                    Ok((sent, received)) => {
+                        let result = ControlMessage::FloodResult { sent, received };
+                        if let Err(err) = control.send(&result).await {
+                            debug!("Failed to report the flood result: {err:#}");
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        warn!("Datagram flood failed: {err:#}");
+                        break;
+                    }
+                }
+            }
+            Ok(Some(ControlMessage::EdgeCases)) => {
+                let result = ControlMessage::EdgeCasesResult {
+                    zero_byte_streams: counters.zero_byte_streams.load(Ordering::Relaxed),
+                    short_streams: counters.short_streams.load(Ordering::Relaxed),
+                    datagrams: counters.datagrams_received.load(Ordering::Relaxed),
+                };
+                if let Err(err) = control.send(&result).await {
+                    debug!("Failed to report the edge case counts: {err:#}");
+                    break;
+                }
+            }
+            Ok(Some(ControlMessage::RunEnd)) => match &run_id {
+                Some(id) if !run_ended => {
+                    run_ended = true;
+                    if let Some(summary) = stats.runs.connection_ended(id, &counters) {
+                        stats.run_completed(summary);
+                    }
+                }
+                _ => debug!("Ignoring the end of a run never started or already ended"),
+            },
+            Ok(Some(message)) => debug!("Ignoring control message {message:?}"),
+            Ok(None) => break,
+            Err(err) => {
+                debug!(
+                    "Control stream from {} ended: {err:#}",
+                    connection.remote_address()
+                );
+                break;
+            }
+        }
+    }
+    (run_id, run_ended)
+}
+
+async fn drive_stream(
+    connection: quinn::Connection,
+    stats: Arc<ServerStats>,
+    counters: Arc<ConnectionCounters>,
+    settings: Arc<ResponseSettings>,
+) -> Result<()> {
+    // Reused for every stream so the read path does not allocate.
+    let mut chunks = vec![Bytes::new(); stats.read_chunks];
+    loop {
+        let accept_start = Instant::now();
+        let result = connection.accept_uni().await;
+        stats.phases.record(Phase::Accept, accept_start);
+        match result {
+            Ok(mut stream) => {
+                let accepted_at = Instant::now();
+                // Held while serving the stream, the semaphore grants the
+                // slots in order of request so connections take turns.
+                let _slot = match &stats.stream_slots {
+                    Some(slots) => {
+                        let slot = slots.acquire().await;
+                        stats.phases.record(Phase::Queue, accepted_at);
+                        slot.ok()
+                    }
+                    None => None,
+                };
+                // The request id prefix is echoed back in the response.
+                let mut request_id = [0u8; REQUEST_ID_LEN];
+                let mut request_id_len = 0;
+                // The whole request, or its prefix, only kept when echoing.
+                let echo = settings.echo.load(Ordering::Relaxed);
+                let echo_prefix = settings.echo_prefix.load(Ordering::Relaxed);
+                let mut request = Vec::new();
+                let mut stream_len = 0;
+                let mut first_chunk_at = None;
+                // Framed requests are answered one by one as they complete.
+                let framed = settings.framed.load(Ordering::Relaxed);
+                let mut frames = Vec::new();
+
+                let mut has_failure = false;
+                loop {
+                    let read_start = Instant::now();
+                    let read = stream.read_chunks(&mut chunks);
+                    let result = match stats.stream_read_timeout {
+                        Some(timeout) => time::timeout(timeout, read).await,
+                        None => Ok(read.await),
+                    };
+                    stats.phases.record(Phase::Read, read_start);
+                    let Ok(result) = result else {
+                        debug!(
+                            "Stopping a stream stalled for {:?}",
+                            stats.stream_read_timeout
+                        );
+                        let _ = stream.stop(STREAM_READ_TIMEOUT.into());
+                        counters.streams_timed_out.fetch_add(1, Ordering::Relaxed);
+                        stats.timed_out_streams.fetch_add(1, Ordering::Relaxed);
+                        has_failure = true;
+                        break;
+                    };
+                    match result {
+                        Ok(chunk) => match chunk {
+                            Some(n_chunks) => {
+                                if n_chunks == 0 {
+                                    break;
+                                }
+                                first_chunk_at.get_or_insert_with(Instant::now);
+                                stats.read_calls.fetch_add(1, Ordering::Relaxed);
+                                stats.chunks_read.fetch_add(n_chunks, Ordering::Relaxed);
+                                for chunk in &chunks[..n_chunks] {
+                                    if framed {
+                                        frames.extend_from_slice(chunk);
+                                        stream_len += chunk.len();
+                                        counters
+                                            .bytes_received
+                                            .fetch_add(chunk.len(), Ordering::Relaxed);
+                                        continue;
+                                    }
+                                    let n = (REQUEST_ID_LEN - request_id_len).min(chunk.len());
+                                    request_id[request_id_len..request_id_len + n]
+                                        .copy_from_slice(&chunk[..n]);
+                                    request_id_len += n;
+                                    if echo {
+                                        request.extend_from_slice(chunk);
+                                    } else if request.len() < echo_prefix {
+                                        let n = (echo_prefix - request.len()).min(chunk.len());
+                                        request.extend_from_slice(&chunk[..n]);
+                                    }
+                                    stream_len += chunk.len();
+                                    counters
+                                        .bytes_received
+                                        .fetch_add(chunk.len(), Ordering::Relaxed);
+                                }
+                                while let Some(message) = requests::take_frame(&mut frames) {
+                                    serve_framed_request(
+                                        &connection,
+                                        &stats,
+                                        &counters,
+                                        &settings,
+                                        message,
+                                        accepted_at,
+                                    )
+                                    .await;
+                                }
+                            }
+                            None => {
+                                break;
+                            }
+                        },
+                        Err(err) => {
+                            has_failure = true;
+                            error!("Had failure : {err:?}");
+                            break;
+                        }
+                    }
+                }
+                if !has_failure {
+                    if let Some(first_chunk_at) = first_chunk_at {
+                        stats
+                            .stream_completion
+                            .lock()
+                            .unwrap()
+                            .record_duration(first_chunk_at.elapsed());
+                    }
+                    if framed {
+                        // A frame cut short by the end of the stream.
+                        if !frames.is_empty() {
+                            counters.short_streams.fetch_add(1, Ordering::Relaxed);
+                        }
+                        continue;
+                    }
+                    stats
+                        .total_received
+                        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    counters.streams_received.fetch_add(1, Ordering::Relaxed);
+                    if packet_log::sampled() {
+                        info!(
+                            "Received a {stream_len} byte request stream, request id {:?}",
+                            requests::decode_request_id(&request_id[..request_id_len])
+                        );
+                    }
+                    if request_id_len < REQUEST_ID_LEN {
+                        stats.bare_streams.fetch_add(1, Ordering::Relaxed);
+                        if stream_len == 0 {
+                            counters.zero_byte_streams.fetch_add(1, Ordering::Relaxed);
+                        } else {
+                            counters.short_streams.fetch_add(1, Ordering::Relaxed);
+                        }
+                        continue;
+                    }
+                    if settings.silent.load(Ordering::Relaxed) {
+                        continue;
+                    }
+
+                    send_response(
+                        &connection,
+                        &stats,
+                        &counters,
+                        &settings,
+                        request_id,
+                        request,
+                        accepted_at,
+                    )
+                    .await;
+                }
+            }
+            Err(err) => {
+                info!(
+                    "Got error {err:?} for connection from {:?}",
+                    connection.remote_address()
+                );
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Count and answer a request of a framed stream. Every request counts as a
+/// stream received, so the totals compare with the requests the client sent.
+async fn serve_framed_request(
+    connection: &Connection,
+    stats: &ServerStats,
+    counters: &ConnectionCounters,
+    settings: &ResponseSettings,
+    message: Vec<u8>,
+    accepted_at: Instant,
+) {
+    stats.total_received.fetch_add(1, Ordering::Relaxed);
+    counters.streams_received.fetch_add(1, Ordering::Relaxed);
+    let Some(request_id) = message.get(..REQUEST_ID_LEN) else {
+        stats.bare_streams.fetch_add(1, Ordering::Relaxed);
+        counters.short_streams.fetch_add(1, Ordering::Relaxed);
+        return;
+    };
+    if settings.silent.load(Ordering::Relaxed) {
+        return;
+    }
+    let request_id: [u8; REQUEST_ID_LEN] = request_id.try_into().unwrap();
+    let echo_prefix = settings.echo_prefix.load(Ordering::Relaxed);
+    let request = if settings.echo.load(Ordering::Relaxed) {
+        message
+    } else {
+        message[..echo_prefix.min(message.len())].to_vec()
+    };
+    send_response(
+        connection,
+        stats,
+        counters,
+        settings,
+        request_id,
+        request,
+        accepted_at,
+    )
+    .await;
+}
+
+/// Answer the request `request_id` as a datagram, or on a stream when too
+/// large for one. `request` holds the request, or its prefix, when echoing.
+async fn send_response(
+    connection: &Connection,
+    stats: &ServerStats,
+    counters: &ConnectionCounters,
+    settings: &ResponseSettings,
+    request_id: [u8; REQUEST_ID_LEN],
+    request: Vec<u8>,
+    accepted_at: Instant,
+) {
+    let echo = settings.echo.load(Ordering::Relaxed);
+    let echo_prefix = settings.echo_prefix.load(Ordering::Relaxed);
+    let size = settings.size.load(Ordering::Relaxed);
+    let packet = if echo || echo_prefix > 0 {
+        request
+    } else {
+        let len = if size == 0 {
+            PACKET_SIZE
+        } else {
+            size.max(REQUEST_ID_LEN)
+        };
+        let mut packet = vec![b'a'; len];
+        packet[..REQUEST_ID_LEN].copy_from_slice(&request_id);
+        packet
+    };
+    // Responses beyond the datagram limit go on a stream.
+    let max_datagram_size = connection.max_datagram_size();
+    let on_stream = if max_datagram_size.is_none() {
+        // The client takes no datagrams at all.
+        if counters.datagram_fallbacks.fetch_add(1, Ordering::Relaxed) == 0 {
+            info!("Client takes no datagrams, answering on streams");
+        }
+        stats.datagram_fallbacks.fetch_add(1, Ordering::Relaxed);
+        true
+    } else if settings.ab_modes.load(Ordering::Relaxed) {
+        let id = requests::decode_request_id(&request_id).unwrap_or_default();
+        ResponseMode::of(id) == ResponseMode::Stream
+    } else {
+        (size > 0 || echo_prefix > 0) && max_datagram_size.is_some_and(|max| packet.len() > max)
+    };
+    let send_start = Instant::now();
+    let result = if on_stream {
+        send_stream_response(connection, &packet).await
+    } else {
+        connection
+            .send_datagram_wait(packet.clone().into())
+            .await
+            .map_err(Error::from)
+    };
+    stats.phases.record(Phase::Send, send_start);
+    stats
+        .stream_service
+        .lock()
+        .unwrap()
+        .record_duration(accepted_at.elapsed());
+
+    match result {
+        Ok(_) => {
+            counters.responses_sent.fetch_add(1, Ordering::Relaxed);
+            counters
+                .bytes_sent
+                .fetch_add(packet.len(), Ordering::Relaxed);
+            if packet_log::sampled() {
+                info!(
+                    "Sent a {} byte response to request {:?} {}",
+                    packet.len(),
+                    requests::decode_request_id(&packet),
+                    if on_stream {
+                        "on a stream"
+                    } else {
+                        "as a datagram"
+                    },
+                );
+            }
+            task::yield_now().await;
+        }
+        Err(err) => {
+            error!("Server send response error {err:?}");
+        }
+    }
+}
+
+async fn send_stream_response(connection: &Connection, response: &[u8]) -> Result<()> {
+    let mut stream = connection.open_uni().await?;
+    stream.write_all(response).await?;
+    stream.finish()?;
+    Ok(())
+}
+
+/// Count the datagrams the client sends on `connection`, until it closes.
+async fn drive_server_datagrams(connection: Connection, counters: Arc<ConnectionCounters>) {
+    while connection.read_datagram().await.is_ok() {
+        counters.datagrams_received.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Driving the receiving of datagrams for a connection.
+async fn drive_datagram(
+    connection: quinn::Connection,
+    outstanding: Arc<Outstanding>,
+    responses: Arc<ResponseStats>,
+    tracer: Option<Arc<MessageTracer>>,
+    span_every: Option<u64>,
+) -> Result<()> {
+    loop {
+        let result = connection.read_datagram().await;
+        match result {
+            Ok(bytes) => {
+                if let Some(tracer) = &tracer {
+                    if let Some(id) = requests::decode_request_id(&bytes) {
+                        if tracer.sampled(id) {
+                            tracer.record(connection.stable_id(), id, TraceEvent::Response);
+                        }
+                    }
+                }
+                let receive_span = match (span_every, requests::decode_request_id(&bytes)) {
+                    (Some(every), Some(id)) if id % every == 0 => {
+                        info_span!("response_receive", request_id = id)
+                    }
+                    _ => Span::none(),
+                };
+                receive_span.in_scope(|| responses.record_response(&outstanding, &bytes));
+                if packet_log::sampled() {
+                    info!(
+                        "Received a {} byte datagram response to request {:?}",
+                        bytes.len(),
+                        requests::decode_request_id(&bytes)
+                    );
+                }
+            }
+            Err(err) => {
+                info!(
+                    "Got error {err:?} for connection from {:?}",
+                    connection.remote_address()
+                );
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Receive the responses which the server sends on streams, those too large
+/// for a datagram.
+async fn drive_stream_responses(
+    connection: Connection,
+    outstanding: Arc<Outstanding>,
+    responses: Arc<ResponseStats>,
+) {
+    const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+
+    while let Ok(mut stream) = connection.accept_uni().await {
+        let outstanding = outstanding.clone();
+        let responses = responses.clone();
+        tokio::spawn(async move {
+            match stream.read_to_end(MAX_RESPONSE_SIZE).await {
+                Ok(response) => responses.record_response(&outstanding, &response),
+                Err(err) => debug!("Failed to read a stream response: {err:?}"),
+            }
+        });
+    }
+}
+
+async fn run_client(
+    opt: &Opt,
+    environment: &Environment,
+    otlp: Option<Arc<OtlpExport>>,
+) -> Result<Vec<StreamSummary>> {
+    let run_id = opt
+        .run_id
+        .clone()
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+    let identity = |instance: usize| {
+        opt.identity
+            .get(instance % opt.identity.len().max(1))
+            .copied()
+            .unwrap_or(IdentityClass::Shared)
+    };
+    let result = if opt.instances <= 1 {
+        let span = info_span!("run", run_id = %run_id);
+        run_client_with_id(opt, environment, otlp.clone(), run_id, identity(0))
+            .instrument(span)
+            .await
+            .map(|summary| summary.into_iter().collect())
+    } else {
+        let instances = (0..opt.instances).map(|instance| {
+            let run_id = format!("{run_id}-{instance}");
+            let span = info_span!("run", run_id = %run_id, instance);
+            run_client_with_id(opt, environment, otlp.clone(), run_id, identity(instance))
+                .instrument(span)
+        });
+        let results = join_all(instances.collect()).await;
+        let mut summaries = Vec::new();
+        for (instance, result) in results.iter().enumerate() {
+            match result {
+                Ok(summary) => summaries.extend(summary),
+                Err(err) => error!("Client instance {instance} failed: {err:#}"),
+            }
+        }
+        report_instances(&summaries, opt.instances);
+        if opt.identity.len() > 1 {
+            let outcomes: Vec<_> = results
+                .iter()
+                .enumerate()
+                .map(|(instance, result)| (identity(instance), result))
+                .collect();
+            identity::report_identity_classes(&outcomes);
+        }
+        Ok(results.into_iter().flatten().flatten().collect())
+    };
+    if let Some(otlp) = otlp {
+        otlp.shutdown();
+    }
+    result
+}
+
+/// Log the totals of the stream workloads of all client instances.
+fn report_instances(summaries: &[&StreamSummary], instances: usize) {
+    let mut latency = Histogram::default();
+    for summary in summaries {
+        latency.merge(&summary.latency);
+    }
+    let total =
+        |field: fn(&StreamSummary) -> usize| summaries.iter().copied().map(field).sum::<usize>();
+    info!(
+        "{} of {instances} client instances completed: {} sent, {} received, {} expired, {} \
+         late, {} corrupted, {} lost, goodput {:.2} Mbit/s",
+        summaries.len(),
+        total(|summary| summary.sent),
+        total(|summary| summary.received),
+        total(|summary| summary.expired),
+        total(|summary| summary.late),
+        total(|summary| summary.corrupted),
+        total(|summary| summary.lost),
+        summaries
+            .iter()
+            .map(|summary| summary.goodput_mbps)
+            .sum::<f64>(),
+    );
+    info!("Response latency over all instances: {latency}");
+}
+
+async fn run_client_with_id(
+    opt: &Opt,
+    environment: &Environment,
+    otlp: Option<Arc<OtlpExport>>,
+    run_id: String,
+    identity: IdentityClass,
+) -> Result<Option<StreamSummary>> {
+    let run_directory = match &opt.results_dir {
+        Some(results_dir) => {
+            let config = Opt {
+                run_id: Some(run_id.clone()),
+                ..opt.clone()
+            };
+            Some(RunDirectory::create(
+                results_dir,
+                &run_id,
+                &config,
+                environment,
+            )?)
+        }
+        None => None,
+    };
+    let pusher = MetricsPusher::new(
+        opt.influx_url.as_deref(),
+        opt.graphite_addr.as_deref(),
+        otlp,
+        &run_id,
+    )
+    .await?
+    .map(Arc::new);
+    if opt.no_response_expected && opt.mode != Mode::Streams {
+        bail!("--no-response-expected only generates load in streams mode");
+    }
+    let server_addrs = resolve::resolve(&opt.server_address).await?;
+    let mut server_addr = select_server_address(opt, &server_addrs).await?;
+
+    if server_addr.ip().is_unspecified() {
+        server_addr.set_ip(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        //server_addr.set_ip(IpAddr::V4(Ipv4Addr::new(145, 40, 90, 189)));
+    }
+    info!("Connecting to server {server_addr:?} as identity class {identity}");
+    let endpoint_failures = EndpointFailures::default();
+    // Endpoints which could not be bound are skipped, the indices are kept
+    // to report failures against the requested endpoints.
+    let (endpoint_indices, endpoints): (Vec<usize>, Vec<Endpoint>) = setup_client(
+        opt,
+        opt.num_threads,
+        unspecified_ip(&server_addr),
+        identity,
+        &endpoint_failures,
+    )
+    .map_err(|err| Error::msg(format!("Failed to create client: {err}")))?
+    .into_iter()
+    .unzip();
+    if endpoints.is_empty() {
+        bail!("No client endpoint could be bound");
+    }
+
+    // Scenarios needing impairments reach the server through the relay.
+    let path =
+        |loss: Option<LossModel>, delay: Option<u64>, bandwidth: Option<f64>| PathImpairments {
+            loss: loss.or(opt.emulate_loss_model),
+            delay: Duration::from_millis(delay.unwrap_or_default()),
+            bandwidth: bandwidth.map(|mbps| (mbps * 1_000_000.0) as u64),
+        };
+    let paths = [
+        path(
+            opt.loss_to_server,
+            opt.delay_to_server,
+            opt.bandwidth_to_server,
+        ),
+        path(
+            opt.loss_to_client,
+            opt.delay_to_client,
+            opt.bandwidth_to_client,
+        ),
+    ];
+    let proxy = if opt.pmtu_blackhole_after.is_some() || paths.iter().any(|p| p.is_impaired()) {
+        Some(ImpairmentProxy::start(server_addr, paths).await?)
+    } else {
+        None
+    };
+    let connect_addr = proxy
+        .as_ref()
+        .map_or(server_addr, |proxy| proxy.local_addr());
+
+    if opt.wait_for_server {
+        wait_for_server(
+            &endpoints[0],
+            connect_addr,
+            &opt.server_name,
+            Duration::from_secs(opt.wait_for_server_timeout),
+        )
+        .await?;
+    }
+
+    if opt.mode == Mode::ConnectStorm {
+        return storm::run_connect_storm(
+            &endpoints,
+            connect_addr,
+            &opt.server_name,
+            opt.concurrency,
+            Duration::from_secs(opt.duration),
+            opt.send_0rtt,
+        )
+        .await
+        .map(|()| None);
+    }
+
+    // The first connection learns the ports of the server endpoints, the
+    // others are spread across them.
+    let stagger = Duration::from_millis(opt.connect_stagger);
+    let mut connected = connect_all(
+        &endpoints[..1],
+        &endpoint_indices[..1],
+        &[connect_addr],
+        &opt.server_name,
+        stagger,
+        &endpoint_failures,
+    )
+    .await?;
+    // Without responses the peer is not this tool's server, no run is joined.
+    let (first_control, mut ports) = if opt.no_response_expected {
+        (None, Vec::new())
+    } else {
+        let (control, ports) = join_run(&connected[0].1, &run_id).await?;
+        (Some(control), ports)
+    };
+    let spread_addrs = if proxy.is_none() && ports.len() > 1 {
+        info!("Spreading connections across server ports {ports:?}");
+        // Start with the port after the one already connected to.
+        ports.sort_by_key(|port| *port != connect_addr.port());
+        ports.rotate_left(1);
+        ports
+            .iter()
+            .map(|port| SocketAddr::new(connect_addr.ip(), *port))
+            .collect()
+    } else {
+        vec![connect_addr]
+    };
+    connected.extend(
+        connect_all(
+            &endpoints[1..],
+            &endpoint_indices[1..],
+            &spread_addrs,
+            &opt.server_name,
+            stagger,
+            &endpoint_failures,
+        )
+        .await?,
+    );
+
+    let mut conns: Vec<(Connection, Span)> = Vec::default();
+    let mut controls: Vec<ControlStream> = Vec::default();
+    // The endpoint of each connection in `conns`, endpoints failing to
+    // connect being left out.
+    let mut conn_endpoints: Vec<Endpoint> = Vec::default();
+    let mut first_control = first_control;
+    for (endpoint, conn, connect_latency) in connected {
+        let conn_span = info_span!(
+            "conn",
+            conn_id = conn.stable_id(),
+            local = %endpoint.local_addr()?,
+            remote = %conn.remote_address(),
+        );
+        conn_span.in_scope(|| info!("Connected in {connect_latency:?}"));
+        if !opt.no_response_expected {
+            let mut control = match first_control.take() {
+                Some(control) => control,
+                None => join_run(&conn, &run_id).await?.0,
+            };
+            configure_control(opt, &mut control).await?;
+            if opt.control_connection == ControlConnection::Separate {
+                control
+                    .separate_heartbeats(&endpoint, conn.remote_address(), &opt.server_name)
+                    .await
+                    .context("connecting the heartbeat connection")?;
+            }
+            controls.push(control);
+        }
+        conns.push((conn, conn_span));
+        conn_endpoints.push(endpoint);
+    }
+
+    if opt.prewarm {
+        prewarm_connections(&conns, &mut controls).await?;
+    }
+
+    let blackhole = match (&proxy, opt.pmtu_blackhole_after) {
+        (Some(proxy), Some(after)) => {
+            let (stop, stop_receiver) = oneshot::channel();
+            let scenario = tokio::spawn(pmtu::run_blackhole_scenario(
+                conns.iter().map(|(conn, _)| conn.clone()).collect(),
+                proxy.impairments.clone(),
+                Duration::from_secs(after),
+                opt.pmtu_blackhole_size,
+                stop_receiver,
+            ));
+            Some((stop, scenario))
+        }
+        _ => None,
+    };
+
+    let standby = if opt.mode == Mode::Streams && opt.standby_connections > 0 {
+        let active: Vec<_> = conns.iter().map(|(conn, _)| conn.clone()).collect();
+        Some(StandbyPool::establish(opt, &run_id, &conn_endpoints, &active).await?)
+    } else {
+        None
+    };
+
+    if let Some(start_at) = opt.start_at {
+        sync_start::wait_until(start_at, "Client").await;
+    }
+    let mut summary = None;
+    match opt.mode {
+        Mode::Streams => {
+            let mut stream_summary = run_stream_workload(
+                opt,
+                &conns,
+                &mut controls,
+                pusher,
+                run_directory.as_ref(),
+                standby.clone(),
+            )
+            .await?;
+            stream_summary.degraded = endpoint_failures.is_degraded();
+            stream_summary.endpoint_failures = endpoint_failures.list();
+            if let Some(run_directory) = &run_directory {
+                run_directory.write_summary(&stream_summary)?;
+                run_directory.write_csv("intervals.csv", &stream_summary.intervals)?;
+            }
+            summary = Some(stream_summary);
+        }
+        Mode::DatagramFlood => {
+            flood::run_datagram_flood(
+                &conns,
+                &mut controls,
+                opt.datagram_size,
+                Duration::from_secs(opt.duration),
+            )
+            .await?
+        }
+        Mode::StreamOpen => {
+            stream_open::run_stream_open(&conns, opt.num_packets, opt.stream_payload).await?
+        }
+        Mode::ConnectStorm => unreachable!("connect storm does not use long lived connections"),
+        Mode::Idle => {
+            idle::monitor_idle_connections(
+                &conns,
+                Duration::from_secs(opt.idle_duration),
+                Duration::from_secs(opt.rtt_sample_interval),
+            )
+            .await;
+        }
+    }
+    endpoint_failures.report(opt.num_threads);
+    if let Some((stop, scenario)) = blackhole {
+        let _ = stop.send(());
+        let _ = scenario.await;
+    }
+    if let Some(proxy) = &proxy {
+        proxy.impairments.report();
+    }
+    if !controls.is_empty() {
+        let mut latency = Histogram::default();
+        controls
+            .iter()
+            .for_each(|control| latency.merge(control.latency()));
+        info!(
+            "Control round trips ({} connection): {latency}",
+            opt.control_connection
+        );
+    }
+    // The ping proves the server saw the end of the run before the close.
+    let run_ends = controls
+        .iter_mut()
+        .map(|control| async move {
+            control.send(&ControlMessage::RunEnd).await?;
+            control.ping().await
+        })
+        .collect();
+    for result in join_all(run_ends).await {
+        if let Err(err) = result {
+            debug!("Failed to announce the end of the run: {err:#}");
+        }
+    }
+    if let Some(standby) = &standby {
+        standby.finish().await;
+    }
+    for (conn, _) in &conns {
+        conn.close(0u32.into(), b"done");
+    }
+
+    // the following give the async sent datagrams to be sent out actually.
+    for endpoint in &endpoints {
+        endpoint.wait_idle().await;
+    }
+    Ok(summary)
+}
+
+fn unspecified_ip(addr: &SocketAddr) -> IpAddr {
+    match addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    }
+}
+
+/// Pick the address to run against. When the server resolved to both IPv4
+/// and IPv6 addresses, the families are raced and the first to complete a
+/// handshake wins.
+async fn select_server_address(opt: &Opt, addrs: &[SocketAddr]) -> Result<SocketAddr> {
+    let has_v4 = addrs.iter().any(|addr| addr.is_ipv4());
+    let has_v6 = addrs.iter().any(|addr| addr.is_ipv6());
+    if !(has_v4 && has_v6) {
+        return Ok(addrs[0]);
+    }
+
+    let endpoint_for = |ip| {
+        setup_client(
+            opt,
+            1,
+            ip,
+            IdentityClass::Shared,
+            &EndpointFailures::default(),
+        )
+        .ok()
+        .and_then(|mut e| e.pop())
+        .map(|(_, endpoint)| endpoint)
+    };
+    let v4 = endpoint_for(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let v6 = endpoint_for(IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+    let (addr, latency) =
+        resolve::happy_eyeballs(v4.as_ref(), v6.as_ref(), addrs, &opt.server_name).await?;
+    info!(
+        "Happy eyeballs: {} won with {addr} after {latency:?}",
+        if addr.is_ipv6() { "IPv6" } else { "IPv4" }
+    );
+    Ok(addr)
+}
+
+/// Connect every endpoint to the server, round robin across `server_addrs`,
+/// starting consecutive connects `stagger` apart rather than all at once.
+/// Returns the connections with their endpoint and connect latency, in
+/// endpoint order. Endpoints failing to connect are recorded in `failures`
+/// under their index in `indices` and left out, as long as one connects.
+async fn connect_all(
+    endpoints: &[Endpoint],
+    indices: &[usize],
+    server_addrs: &[SocketAddr],
+    server_name: &str,
+    stagger: Duration,
+    failures: &EndpointFailures,
+) -> Result<Vec<(Endpoint, Connection, Duration)>> {
+    let mut handles = Vec::with_capacity(endpoints.len());
+    let mut version_mismatches = 0;
+    for (i, endpoint) in endpoints.iter().enumerate() {
+        if i > 0 && !stagger.is_zero() {
+            time::sleep(stagger).await;
+        }
+        let server_addr = server_addrs[i % server_addrs.len()];
+        let connecting = match endpoint.connect(server_addr, server_name) {
+            Ok(connecting) => connecting,
+            Err(err) if endpoint_failures::is_endpoint_error(&err) => {
+                failures.record(indices[i], endpoint.local_addr().ok(), err);
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let endpoint = endpoint.clone();
+        handles.push(tokio::spawn(async move {
+            let start = Instant::now();
+            let result = connecting.await;
+            (endpoint, result, start.elapsed())
+        }));
+    }
+    if handles.is_empty() && !endpoints.is_empty() {
+        bail!("No endpoint could connect");
+    }
+
+    let mut connected = Vec::with_capacity(handles.len());
+    for handle in handles {
+        let (endpoint, result, latency) = handle.await?;
+        match result {
+            Ok(conn) => connected.push((endpoint, conn, latency)),
+            Err(quinn::ConnectionError::VersionMismatch) => version_mismatches += 1,
+            Err(err) => return Err(Error::new(err).context("Connection failed")),
+        }
+    }
+    if version_mismatches > 0 {
+        bail!("{version_mismatches} connections failed version negotiation");
+    }
+
+    if let Some(max) = connected.iter().map(|(_, _, latency)| *latency).max() {
+        let min = connected
+            .iter()
+            .map(|(_, _, latency)| *latency)
+            .min()
+            .unwrap();
+        let total: Duration = connected.iter().map(|(_, _, latency)| *latency).sum();
+        info!(
+            "Connected {} connections, connect latency min {min:?}, mean {:?}, max {max:?}",
+            connected.len(),
+            total / connected.len() as u32,
+        );
+    }
+    Ok(connected)
+}
+
+/// Open the control stream of `conn` and tag it with the run, returning the
+/// stream and the server endpoint ports announced in reply.
+async fn join_run(conn: &Connection, run_id: &str) -> Result<(ControlStream, Vec<u16>)> {
+    let mut control = ControlStream::open(conn).await?;
+    control
+        .send(&ControlMessage::Hello {
+            run_id: run_id.to_string(),
+            connection_id: conn.stable_id(),
+        })
+        .await?;
+    match control.recv().await? {
+        Some(ControlMessage::Endpoints { ports }) => Ok((control, ports)),
+        other => bail!("expected the server endpoints, got {other:?}"),
+    }
+}
+
+/// Ask the server for the response behaviour of the run on `control`. Every
+/// request waits for a pong, proving the server switched before any request
+/// goes out.
+async fn configure_control(opt: &Opt, control: &mut ControlStream) -> Result<()> {
+    if opt.echo {
+        control.send(&ControlMessage::Echo).await?;
+        control.ping().await?;
+    }
+    if let Some(len) = opt.echo_prefix {
+        control.send(&ControlMessage::EchoPrefix { len }).await?;
+        control.ping().await?;
+    }
+    if let Some(asymmetry) = opt.asymmetry {
+        let size = asymmetry.response_size();
+        control.send(&ControlMessage::ResponseSize { size }).await?;
+        control.ping().await?;
+    }
+    if opt.ab_response_modes {
+        control.send(&ControlMessage::AbResponses).await?;
+        control.ping().await?;
+    }
+    if opt.messages_per_stream.is_some() {
+        control.send(&ControlMessage::Framed).await?;
+        control.ping().await?;
+    }
+    Ok(())
+}
+
+/// Complete one control round trip per connection so the measured phase
+/// starts with warmed up connections.
+async fn prewarm_connections(
+    conns: &[(Connection, Span)],
+    controls: &mut [ControlStream],
+) -> Result<()> {
+    let start = Instant::now();
+    let mut max_rtt = Duration::ZERO;
+    for ((_, span), control) in conns.iter().zip(controls.iter_mut()) {
+        let rtt = control
+            .ping()
+            .instrument(span.clone())
+            .await
+            .context("prewarm round trip")?;
+        span.in_scope(|| debug!("Prewarm round trip took {rtt:?}"));
+        max_rtt = max_rtt.max(rtt);
+    }
+    info!(
+        "Prewarmed {} connections in {:?}, slowest round trip {max_rtt:?}",
+        conns.len(),
+        start.elapsed()
+    );
+    Ok(())
+}
+
+/// Outcome of a stream workload run, recorded in the run's results folder.
+#[derive(Serialize)]
+struct StreamSummary {
+    sent: usize,
+    received: usize,
+    expired: usize,
+    late: usize,
+    corrupted: usize,
+    lost: usize,
+    duration_secs: f64,
+    latency_p50_us: u64,
+    latency_p90_us: u64,
+    latency_p99_us: u64,
+    latency_max_us: u64,
+    /// Overhead of measuring latency on this machine, included in the
+    /// latencies above.
+    measurement_floor_us: f64,
+    /// Client to server application goodput in Mbit/s.
+    goodput_mbps: f64,
+    intervals: Vec<IntervalRecord>,
+    /// Set when endpoints failed and the run went on with the others.
+    degraded: bool,
+    endpoint_failures: Vec<EndpointFailure>,
+    #[serde(skip)]
+    latency: Histogram,
+}
+
+/// What a stream workload accumulated up to an error or panic.
+#[derive(Serialize)]
+struct PartialStreamSummary<'a> {
+    sent: usize,
+    received: usize,
+    expired: usize,
+    late: usize,
+    corrupted: usize,
+    duration_secs: f64,
+    latency_p50_us: Option<u64>,
+    latency_p99_us: Option<u64>,
+    intervals: &'a [IntervalRecord],
+}
+
+/// Progress of a stream workload over one reporting interval.
+#[derive(Serialize)]
+struct IntervalRecord {
+    /// Time from the start of the workload to the end of the interval.
+    elapsed_secs: f64,
+    sent: usize,
+    received: usize,
+    responses_per_sec: f64,
+    latency_p50_us: u64,
+    latency_p99_us: u64,
+    /// Responses expired, late or corrupted.
+    errors: usize,
+    /// Transport events over all connections in the interval.
+    #[serde(flatten)]
+    transport: TransportEvents,
+}
+
+/// Open `num_packets` request streams on every connection and collect their
+/// datagram responses.
+async fn run_stream_workload(
+    opt: &Opt,
+    conns: &[(Connection, Span)],
+    controls: &mut [ControlStream],
+    pusher: Option<Arc<MetricsPusher>>,
+    run_directory: Option<&RunDirectory>,
+    standby: Option<Arc<StandbyPool>>,
+) -> Result<StreamSummary> {
+    /// How long to wait for outstanding responses after the last request when
+    /// no response timeout is configured.
+    const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+    let clock = Clock::new(opt.clock)?;
+    clock.report();
+    let floor = MeasurementFloor::measure(clock).await;
+    info!("Latency measurement floor: {floor}");
+    if opt.asymmetry.is_some() && opt.echo {
+        bail!("--asymmetry sets the response size, echoed responses have the request size");
+    }
+    if let Some(len) = opt.echo_prefix {
+        if opt.echo || opt.asymmetry.is_some() {
+            bail!("--echo-prefix sets the response, it takes no --echo or --asymmetry");
+        }
+        if len < REQUEST_ID_LEN {
+            bail!("--echo-prefix has to cover the {REQUEST_ID_LEN} byte request id");
+        }
+    }
+    if opt.no_response_expected
+        && (opt.echo
+            || opt.echo_prefix.is_some()
+            || opt.asymmetry.is_some()
+            || opt.ab_response_modes
+            || opt.edge_cases
+            || opt.standby_connections > 0
+            || opt.messages_per_stream.is_some())
+    {
+        bail!(
+            "--no-response-expected takes no --echo, --echo-prefix, --asymmetry, \
+             --ab-response-modes, --edge-cases, --standby-connections or \
+             --messages-per-stream, they need this tool's server"
+        );
+    }
+    if opt.messages_per_stream == Some(0) {
+        bail!("--messages-per-stream needs at least one message per stream");
+    }
+    if opt.no_response_expected {
+        info!("No responses expected, send-side metrics only");
+    }
+    let packet = vec![0; opt.asymmetry.map_or(PACKET_SIZE, Asymmetry::request_size)];
+    let resumed = match &opt.resume {
+        Some(path) => Checkpoint::load(path)?,
+        None => Checkpoint::default(),
+    };
+    let checkpoint_path = opt.checkpoint.clone().or_else(|| opt.resume.clone());
+    let start = Instant::now();
+    let response_timeout = opt.response_timeout.map(Duration::from_millis);
+
+    let total_sent = Arc::new(AtomicUsize::default());
+    let anomaly = opt.pcap_on_anomaly.clone().map(|command| {
+        AnomalyTrigger::spawn(
+            command,
+            Duration::from_millis(opt.anomaly_latency),
+            Duration::from_secs(opt.capture_secs),
+        )
+    });
+    let responses = Arc::new(ResponseStats::new(
+        response_timeout,
+        match opt.echo_prefix {
+            Some(len) => Some(EchoCheck::Prefix(len.min(packet.len()))),
+            None => opt.echo.then_some(EchoCheck::Full(PACKET_SIZE)),
+        },
+        anomaly.clone(),
+        opt.ab_response_modes,
+        opt.stage_sample,
+        opt.messages_per_stream,
+    ));
+    let in_flight = Arc::new(InFlightGauge::default());
+    // Per request spans for the OTLP export, sampled like the trace file.
+    let span_every = opt
+        .otlp_endpoint
+        .is_some()
+        .then(|| ((1.0 / opt.trace_sample).round() as u64).max(1));
+    let tracer = match &opt.trace_file {
+        Some(path) => Some(Arc::new(MessageTracer::create(path, opt.trace_sample)?)),
+        None => None,
+    };
+    let (scheduler_delay, watchdog) =
+        watchdog::spawn("Client", Duration::from_millis(opt.stall_threshold));
+    let profile = match &opt.pprof {
+        Some(path) => Some((ProfileSession::start()?, path)),
+        None => None,
+    };
+    let allocations_at_start = AllocSnapshot::take();
+    let edge_cases = opt.edge_cases.then(|| Arc::new(EdgeCaseCounts::default()));
+    let controller = opt.auto_concurrency.then(|| {
+        Arc::new(ConcurrencyController::new(Duration::from_millis(
+            opt.p99_target,
+        )))
+    });
+    let live = Arc::new(LiveControl::new(opt.rate, conns.len()));
+    if opt.replay.is_some() && (opt.echo || opt.echo_prefix.is_some()) {
+        bail!("--replay sends requests of varying size, which --echo can not verify");
+    }
+    let mut schedules = match &opt.replay {
+        Some(path) => replay::load(path, conns.len())?
+            .into_iter()
+            .map(Some)
+            .collect(),
+        None => vec![None; conns.len()],
+    };
+    let recorder = match &opt.record {
+        Some(path) => Some(Arc::new(Recorder::create(path, conns.len())?)),
+        None => None,
+    };
+    let bytes_sent = Arc::new(AtomicUsize::default());
+    let commands = opt.interactive.then(|| {
+        tokio::spawn(live_control::run_commands(
+            live.clone(),
+            total_sent.clone(),
+            responses.clone(),
+            in_flight.clone(),
+        ))
+    });
+    let target_limits = opt.max_inflight_per_target.map(|limit| {
+        Arc::new(TargetLimits::new(
+            limit,
+            conns.iter().map(|(conn, _)| conn.remote_address()),
+        ))
+    });
+    let outstanding: Vec<_> = (0..conns.len())
+        .map(|index| {
+            let target_gauge = target_limits.as_ref().map(|limits| limits.gauge(index));
+            Arc::new(Outstanding::new(in_flight.clone(), target_gauge, clock))
+        })
+        .collect();
+    let connections: Arc<Vec<_>> = Arc::new(conns.iter().map(|(conn, _)| conn.clone()).collect());
+    let selector = Arc::new(ConnectionSelector::new(opt.send_policy, conns.len()));
+    // Servers answer on streams when the responses exceed the datagram
+    // limit, alternate modes or the client takes no datagrams.
+    let stream_responses = opt.asymmetry.is_some()
+        || opt.ab_response_modes
+        || opt.no_datagrams
+        || opt.echo_prefix.is_some();
+    let go_away: Vec<_> = conns
+        .iter()
+        .map(|_| Arc::new(AtomicBool::new(false)))
+        .collect();
+    let mut senders = Vec::with_capacity(conns.len());
+    for (index, (conn, conn_span)) in conns.iter().enumerate() {
+        let conn = conn.clone();
+        let mut packet = packet.clone();
+        let schedule: Option<Vec<_>> = schedules[index].take();
+        if let Some(schedule) = &schedule {
+            let largest = schedule.iter().map(|request| request.size).max();
+            packet.resize(largest.unwrap_or_default().max(REQUEST_ID_LEN), 0);
+        }
+        let num_packets = schedule.as_ref().map_or(opt.num_packets, Vec::len);
+        let recorder = recorder.clone();
+        let anomaly = anomaly.clone();
+        let bytes_sent = bytes_sent.clone();
+        let echo = opt.echo;
+        let echo_prefix = opt.echo_prefix.is_some();
+        let controller = controller.clone();
+        let edge_cases = edge_cases.clone();
+        let total_sent = total_sent.clone();
+        let go_away = go_away[index].clone();
+        let standby = standby.clone();
+        let conn_outstanding = outstanding[index].clone();
+        let all_outstanding = outstanding.clone();
+        let connections = connections.clone();
+        let selector = selector.clone();
+        let target_limits = target_limits.clone();
+        let tracer = tracer.clone();
+        let no_response_expected = opt.no_response_expected;
+        let messages_per_stream = opt.messages_per_stream;
+        if !no_response_expected {
+            tokio::spawn(
+                drive_datagram(
+                    conn.clone(),
+                    conn_outstanding.clone(),
+                    responses.clone(),
+                    tracer.clone(),
+                    span_every,
+                )
+                .instrument(conn_span.clone()),
+            );
+        }
+        if stream_responses && !no_response_expected {
+            tokio::spawn(
+                drive_stream_responses(conn.clone(), conn_outstanding.clone(), responses.clone())
+                    .instrument(conn_span.clone()),
+            );
+        }
+
+        let live = live.clone();
+        let responses = responses.clone();
+        senders.push(spawn_named(
+            &format!("sender-{}", senders.len()),
+            &Handle::current(),
+            async move {
+                let mut next_send = AsyncInstant::now();
+                let mut last_send = AsyncInstant::now();
+                // With --messages-per-stream, the stream being filled, the
+                // connection it is on and the messages written to it.
+                let mut framed_stream: Option<(usize, SendStream)> = None;
+                let mut stream_position = 0;
+                for i in 0..num_packets {
+                    live.pace(&mut next_send).await;
+                    if go_away.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let position = stream_position;
+                    // The messages of a stream all go to its connection.
+                    let target = match &framed_stream {
+                        Some((target, _)) => *target,
+                        None => selector.select(index, &all_outstanding),
+                    };
+                    let conn_outstanding = &all_outstanding[target];
+                    let conn = match &standby {
+                        Some(standby) => standby.connection(target),
+                        None => connections[target].clone(),
+                    };
+                    let len = match &schedule {
+                        Some(schedule) => {
+                            sleep_until(last_send + schedule[i].gap).await;
+                            schedule[i].size.max(REQUEST_ID_LEN)
+                        }
+                        None => packet.len(),
+                    };
+                    last_send = AsyncInstant::now();
+                    if let Some(recorder) = &recorder {
+                        recorder.record(index, len);
+                    }
+                    if let Some(edge_cases) = &edge_cases {
+                        if i % edge::EDGE_CASE_INTERVAL == 0 {
+                            if let Err(err) = edge_cases.send(&conn).await {
+                                error!("Edge case send error {err:#}");
+                            }
+                        }
+                    }
+                    if let Some(controller) = &controller {
+                        conn_outstanding.wait_below(controller.limit()).await;
+                    }
+                    if let Some(target_limits) = &target_limits {
+                        target_limits.wait(target).await;
+                    }
+                    let id = if no_response_expected {
+                        conn_outstanding.start_untracked()
+                    } else {
+                        conn_outstanding.start()
+                    };
+                    if responses.positions.is_some() {
+                        conn_outstanding.positioned(id, position);
+                    }
+                    let request_start = Instant::now();
+                    let tracer = tracer.as_ref().filter(|tracer| tracer.sampled(id));
+                    if let Some(tracer) = tracer {
+                        tracer.record(conn.stable_id(), id, TraceEvent::SendStart);
+                    }
+                    if echo {
+                        requests::encode_echo_request(&mut packet, id);
+                    } else if echo_prefix {
+                        requests::encode_prefix_request(&mut packet, id);
+                    } else {
+                        requests::encode_request_id(&mut packet, id);
+                    }
+                    let send_span = match span_every {
+                        Some(every) if id.is_multiple_of(every) => {
+                            info_span!("stream_send", request_id = id)
+                        }
+                        _ => Span::none(),
+                    };
+                    let mut opened_at = request_start;
+                    let result = async {
+                        if messages_per_stream.is_none() {
+                            let mut stream =
+                                conn.open_uni().await.map_err(WriteError::ConnectionLost)?;
+                            opened_at = Instant::now();
+                            return stream.write_all(&packet[..len]).await;
+                        }
+                        if framed_stream.is_none() {
+                            let stream =
+                                conn.open_uni().await.map_err(WriteError::ConnectionLost)?;
+                            framed_stream = Some((target, stream));
+                        }
+                        opened_at = Instant::now();
+                        let (_, stream) = framed_stream.as_mut().unwrap();
+                        stream
+                            .write_all(&requests::encode_frame_header(len))
+                            .await?;
+                        stream.write_all(&packet[..len]).await
+                    }
+                    .instrument(send_span)
+                    .await;
+                    if let Some(n) = messages_per_stream {
+                        stream_position += 1;
+                        // Dropping a full stream queues its FIN, after an
+                        // error the next message starts a new stream.
+                        if stream_position == n || result.is_err() {
+                            framed_stream = None;
+                            stream_position = 0;
+                        }
+                    }
+
+                    match result {
+                        Ok(_) => {
+                            // Dropping the stream right after queues its FIN.
+                            if let Some(stages) = &responses.stages {
+                                if stages.sampled(id) {
+                                    stages.sent(opened_at - request_start, opened_at.elapsed());
+                                    if !no_response_expected {
+                                        conn_outstanding.written(id);
+                                    }
+                                }
+                            }
+                            if let Some(tracer) = tracer {
+                                tracer.record(conn.stable_id(), id, TraceEvent::WriteComplete);
+                            }
+                            total_sent.fetch_add(1, Ordering::Relaxed);
+                            bytes_sent.fetch_add(len, Ordering::Relaxed);
+                            if let Some(standby) = &standby {
+                                standby.request_sent(target);
+                            }
+                            if packet_log::sampled() {
+                                info!(
+                                    "Sent request {id} of {len} bytes on connection {}",
+                                    conn.stable_id()
+                                );
+                            }
+                            task::yield_now().await;
+                        }
+                        // Without a standby to take over, the other
+                        // connections carry on and this sender stops.
+                        Err(WriteError::ConnectionLost(err)) if standby.is_none() => {
+                            conn_outstanding.cancel(id);
+                            error!("Connection lost, stopping its sender: {err}");
+                            if let Some(anomaly) = &anomaly {
+                                anomaly.error("connection lost");
+                            }
+                            break;
+                        }
+                        Err(err) => {
+                            conn_outstanding.cancel(id);
+                            error!("Send stream error {err:?}");
+                            if let Some(anomaly) = &anomaly {
+                                anomaly.error("send stream error");
+                            }
+                        }
+                    }
+                }
+            }
+            .instrument(conn_span.clone()),
+        ));
+    }
+
+    // A connection taking over a slot reports its responses into the
+    // requests outstanding of the slot.
+    let failover = standby.clone().map(|standby| {
+        let outstanding = outstanding.clone();
+        let responses = responses.clone();
+        let tracer = tracer.clone();
+        tokio::spawn(standby.monitor(move |index, conn| {
+            let conn_span = info_span!(
+                "conn",
+                conn_id = conn.stable_id(),
+                remote = %conn.remote_address(),
+            );
+            tokio::spawn(
+                drive_datagram(
+                    conn.clone(),
+                    outstanding[index].clone(),
+                    responses.clone(),
+                    tracer.clone(),
+                    span_every,
+                )
+                .instrument(conn_span.clone()),
+            );
+            if stream_responses {
+                tokio::spawn(
+                    drive_stream_responses(conn, outstanding[index].clone(), responses.clone())
+                        .instrument(conn_span),
+                );
+            }
+        }))
+    });
+
+    let expiry = response_timeout.map(|timeout| {
+        let outstanding = outstanding.clone();
+        let responses = responses.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval((timeout / 4).max(Duration::from_millis(1)));
+            loop {
+                interval.tick().await;
+                outstanding.iter().for_each(|o| responses.expire(o));
+            }
+        })
+    });
 
-        tokio::spawn(report_stats(total_received.clone()));
+    let adjuster = controller
+        .clone()
+        .map(|controller| tokio::spawn(controller.run(responses.clone())));
+    let intervals = Arc::new(Mutex::new(Vec::new()));
+    let progress = run_directory.map(|run_directory| {
+        let total_sent = total_sent.clone();
+        let responses = responses.clone();
+        let intervals = intervals.clone();
+        run_directory.track_progress(move || {
+            // try_lock, a panicking thread may hold the locks.
+            let latency = responses.latency.try_lock().ok();
+            let percentile = |p| latency.as_ref().map(|latency| latency.percentile(p));
+            let intervals = intervals.try_lock().ok();
+            serde_json::to_value(PartialStreamSummary {
+                sent: total_sent.load(Ordering::Relaxed),
+                received: responses.received.load(Ordering::Relaxed),
+                expired: responses.expired.load(Ordering::Relaxed),
+                late: responses.late.load(Ordering::Relaxed),
+                corrupted: responses.corrupted.load(Ordering::Relaxed),
+                duration_secs: start.elapsed().as_secs_f64(),
+                latency_p50_us: percentile(50.0),
+                latency_p99_us: percentile(99.0),
+                intervals: intervals.as_deref().map(Vec::as_slice).unwrap_or_default(),
+            })
+            .unwrap_or_default()
+        })
+    });
+    let reporter = spawn_named(
+        "stats-reporter",
+        &Handle::current(),
+        report_client_stats(
+            conns.iter().map(|(conn, _)| conn.clone()).collect(),
+            total_sent.clone(),
+            responses.clone(),
+            in_flight.clone(),
+            intervals.clone(),
+            pusher,
+        ),
+    );
+    let sampler = {
+        let in_flight = in_flight.clone();
+        let target_limits = target_limits.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_millis(10));
+            loop {
+                interval.tick().await;
+                in_flight.sample();
+                if let Some(target_limits) = &target_limits {
+                    target_limits.sample();
+                }
+            }
+        })
+    };
 
-        let local_address = endpoints[0].local_addr().unwrap();
-        for endpoint in endpoints {
-            let task = tokio::spawn(run_server(endpoint, total_received.clone()));
-            handles.push(task);
-        }
+    let checkpointer = checkpoint_path.clone().map(|path| {
+        let total_sent = total_sent.clone();
+        let bytes_sent = bytes_sent.clone();
+        let responses = responses.clone();
+        let resumed = resumed.clone();
+        let period = Duration::from_secs(opt.checkpoint_interval.max(1));
+        tokio::spawn(async move {
+            let mut interval = time::interval(period);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let segment = segment_checkpoint(
+                    total_sent.load(Ordering::Relaxed),
+                    bytes_sent.load(Ordering::Relaxed),
+                    &responses,
+                    start.elapsed(),
+                );
+                if let Err(err) = resumed.then(&segment).write(&path) {
+                    error!("Failed to checkpoint the run: {err:#}");
+                }
+            }
+        })
+    });
 
-        Self {
-            runtime,
-            handles,
-            local_address,
+    let senders_done = async {
+        for sender in senders {
+            let _ = sender.await;
         }
+    };
+    // The control streams are only read for the server going away until the
+    // senders are done.
+    tokio::select! {
+        () = senders_done => {}
+        () = drain::watch_go_away(controls, &go_away) => {}
     }
+    let duration = start.elapsed().as_secs_f64();
+    let total_sent = total_sent.load(Ordering::Relaxed);
+    info!(
+        "Sent (written to buffer) {} packets in {:.2} seconds ({:.2} packets/sec)",
+        total_sent,
+        duration,
+        total_sent as f64 / duration
+    );
 
-    async fn join(self) {
-        for handle in self.handles {
-            let _ = handle.await;
+    // Give the responses still in flight a chance to arrive before counting
+    // the remaining requests as lost.
+    let drain_deadline = Instant::now() + response_timeout.unwrap_or(DRAIN_TIMEOUT);
+    while outstanding.iter().any(|o| !o.is_empty()) && Instant::now() < drain_deadline {
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    if let Some(expiry) = expiry {
+        expiry.abort();
+    }
+    reporter.abort();
+    sampler.abort();
+    if let Some(commands) = commands {
+        commands.abort();
+    }
+    watchdog.abort();
+    if let Some(adjuster) = adjuster {
+        adjuster.abort();
+    }
+    if let Some(failover) = failover {
+        failover.abort();
+    }
+    if let Some(checkpointer) = checkpointer {
+        checkpointer.abort();
+    }
+    let window = start.elapsed();
+    if let Some(at_start) = allocations_at_start {
+        info!(
+            "Allocations in the measured window: {}",
+            AllocSnapshot::take().unwrap().since(&at_start)
+        );
+    }
+    if let Some((session, path)) = profile {
+        if let Err(err) = session.finish(path) {
+            error!("Failed to write the CPU profile: {err:#}");
         }
     }
-}
-#[tokio::main]
-async fn main() {
-    let mut opt = Opt::from_args();
-    tracing_subscriber::fmt::init();
+    outstanding.iter().for_each(|o| responses.expire(o));
+    let lost: usize = outstanding.iter().map(|o| o.len()).sum();
+    if let Some(modes) = &responses.response_modes {
+        modes.report(&outstanding);
+    }
+    if let Some(positions) = &responses.positions {
+        positions.report();
+    }
+    if let Some(stages) = &responses.stages {
+        stages.report();
+    }
 
-    match (opt.server_only, opt.client_only) {
-        (true, false) => {
-            let addr = opt
-                .server_address
-                .parse::<SocketAddr>()
-                .expect("Exepected correct server address in IP:port format"); // SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+    if !opt.no_response_expected {
+        info!(
+            "Responses: {} received, {} expired, {} late, {} corrupted, {lost} lost at end of run",
+            responses.received.load(Ordering::Relaxed),
+            responses.expired.load(Ordering::Relaxed),
+            responses.late.load(Ordering::Relaxed),
+            responses.corrupted.load(Ordering::Relaxed),
+        );
+        let latency = responses.latency.lock().unwrap();
+        info!("Response latency: {latency}, measurement floor {floor}");
+        info!(
+            "Response latency net of the measurement floor: p50 {:.2}us, p99 {:.2}us",
+            floor.net_us(latency.percentile(50.0)),
+            floor.net_us(latency.percentile(99.0)),
+        );
+    }
+    info!(
+        "Outstanding requests high-water mark: {}",
+        in_flight.high_water_mark()
+    );
+    if let Some(standby) = &standby {
+        standby.report();
+    }
+    selector.report(&outstanding);
+    if let Some(target_limits) = &target_limits {
+        target_limits.report();
+    }
+    if let Some(controller) = &controller {
+        info!(
+            "Converged concurrency: {} requests in flight per connection for p99 under {}ms",
+            controller.limit(),
+            opt.p99_target
+        );
+    }
+    let (delay, stalls) = scheduler_delay.take();
+    info!(
+        "Client scheduler delay: {delay}, {stalls} stalls over {}ms",
+        opt.stall_threshold
+    );
 
-            let server = Server::create_server(&opt, addr);
-            server.join().await;
-        }
-        (false, true) => {
-            let _ = run_client(&opt).await;
-        }
-        _ => {
-            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
-            opt.server_address = addr.to_string();
+    let (mut udp_tx_bytes, mut udp_rx_bytes, mut lost_bytes) = (0, 0, 0);
+    for (conn, _) in conns {
+        let transport = conn.stats();
+        udp_tx_bytes += transport.udp_tx.bytes;
+        udp_rx_bytes += transport.udp_rx.bytes;
+        lost_bytes += transport.path.lost_bytes;
+    }
+    report_wire_efficiency(
+        "client->server",
+        bytes_sent.load(Ordering::Relaxed) as u64,
+        udp_tx_bytes,
+        Some(lost_bytes),
+        window,
+    );
+    report_wire_efficiency(
+        "server->client",
+        responses.bytes_received.load(Ordering::Relaxed) as u64,
+        udp_rx_bytes,
+        None,
+        window,
+    );
+    if let Some(asymmetry) = opt.asymmetry {
+        let mbps = |bytes: usize| bytes as f64 * 8.0 / window.as_secs_f64() / 1_000_000.0;
+        info!(
+            "Asymmetry {asymmetry:?}: {} byte requests at {:.2} Mbit/s, {} byte responses at \
+             {:.2} Mbit/s",
+            asymmetry.request_size(),
+            mbps(bytes_sent.load(Ordering::Relaxed)),
+            asymmetry.response_size(),
+            mbps(responses.bytes_received.load(Ordering::Relaxed)),
+        );
+    }
 
-            let server = Server::create_server(&opt, addr);
+    if !opt.no_response_expected {
+        let latency = responses.latency.lock().unwrap();
+        check_littles_law(
+            latency.count() as f64 / window.as_secs_f64(),
+            latency.mean() / 1_000_000.0,
+            in_flight.average(),
+        );
+    }
 
-            opt.server_address = server.local_address.to_string();
-            time::sleep(Duration::from_secs(1)).await;
-            let _ = run_client(&opt).await;
-            server.join().await;
-        }
+    if let Some(edge_cases) = edge_cases {
+        edge_cases.verify(controls).await?;
+    }
+    if let Some(tracer) = tracer {
+        tracer.flush().await;
+    }
+    if let Some(recorder) = recorder {
+        recorder.flush();
     }
-}
 
-async fn report_stats(total_received: Arc<AtomicUsize>) {
-    let mut last_datapoint = AsyncInstant::now();
-    loop {
-        if last_datapoint.elapsed().as_secs() >= 5 {
-            let total_received = total_received.swap(0, Ordering::Relaxed);
-            info!("Received packets: {total_received}");
-            last_datapoint = AsyncInstant::now();
+    // The summary covers the segments of a resumed run as well.
+    let totals = resumed.then(&segment_checkpoint(
+        total_sent,
+        bytes_sent.load(Ordering::Relaxed),
+        &responses,
+        window,
+    ));
+    if let Some(path) = &checkpoint_path {
+        match totals.write(path) {
+            Ok(()) => info!("Checkpointed the run to {}", path.display()),
+            Err(err) => error!("Failed to checkpoint the run: {err:#}"),
         }
-        sleep_until(last_datapoint.checked_add(Duration::from_secs(5)).unwrap()).await;
     }
-}
-
-async fn run_server(endpoint: Endpoint, total_received: Arc<AtomicUsize>) -> Result<()> {
-    info!("Server listening on {}", endpoint.local_addr().unwrap());
-
-    while let Some(handshake) = endpoint.accept().await {
+    if opt.resume.is_some() {
         info!(
-            "Got incoming connection from {:?}",
-            handshake.remote_address()
+            "Totals over {} segments: {} sent, {} received, {} expired in {:.0}s, latency {}",
+            totals.segments,
+            totals.sent,
+            totals.received,
+            totals.expired,
+            totals.duration_secs,
+            totals.latency,
         );
-        let total_received = total_received.clone();
-        tokio::spawn(async move {
-            if let Err(e) = server_handle_connection(handshake, total_received).await {
-                info!("connection lost: {:#}", e);
-            }
-        });
     }
 
-    Ok(())
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+    let latency = &totals.latency;
+    let intervals = std::mem::take(&mut *intervals.lock().unwrap());
+    Ok(StreamSummary {
+        sent: totals.sent,
+        received: totals.received,
+        expired: totals.expired,
+        late: totals.late,
+        corrupted: totals.corrupted,
+        lost,
+        duration_secs: totals.duration_secs,
+        latency_p50_us: latency.percentile(50.0),
+        latency_p90_us: latency.percentile(90.0),
+        latency_p99_us: latency.percentile(99.0),
+        latency_max_us: latency.max(),
+        measurement_floor_us: floor.total().as_nanos() as f64 / 1000.0,
+        goodput_mbps: totals.bytes_sent as f64 * 8.0 / totals.duration_secs / 1_000_000.0,
+        intervals,
+        // Known to the caller, which set up the endpoints.
+        degraded: false,
+        endpoint_failures: Vec::new(),
+        latency: latency.clone(),
+    })
 }
 
-async fn server_handle_connection(
-    handshake: quinn::Incoming,
-    total_received: Arc<AtomicUsize>,
-) -> Result<()> {
-    let connection = handshake.await.context("handshake failed")?;
-    info!("{} connected", connection.remote_address());
-    tokio::try_join!(drive_stream(connection.clone(), total_received),)?;
-    Ok(())
+/// The totals of the current segment of a streams run, for its checkpoint.
+fn segment_checkpoint(
+    sent: usize,
+    bytes_sent: usize,
+    responses: &ResponseStats,
+    duration: Duration,
+) -> Checkpoint {
+    Checkpoint {
+        segments: 0,
+        sent,
+        received: responses.received.load(Ordering::Relaxed),
+        expired: responses.expired.load(Ordering::Relaxed),
+        late: responses.late.load(Ordering::Relaxed),
+        corrupted: responses.corrupted.load(Ordering::Relaxed),
+        bytes_sent,
+        bytes_received: responses.bytes_received.load(Ordering::Relaxed),
+        duration_secs: duration.as_secs_f64(),
+        latency: responses.latency.lock().unwrap().clone(),
+    }
 }
 
-async fn drive_stream(
-    connection: quinn::Connection,
-    total_received: Arc<AtomicUsize>,
-) -> Result<()> {
-    loop {
-        let result = connection.accept_uni().await;
-        let total_responses_sent = Arc::new(AtomicUsize::default());
-        match result {
-            Ok(mut stream) => {
-                let mut chunks: [Bytes; 4] = array::from_fn(|_| Bytes::new());
+/// Log application goodput against the bytes put on the wire for one
+/// direction. `lost_bytes` is only known on the sending side.
+fn report_wire_efficiency(
+    direction: &str,
+    app_bytes: u64,
+    wire_bytes: u64,
+    lost_bytes: Option<u64>,
+    elapsed: Duration,
+) {
+    let mbps = |bytes: u64| bytes as f64 * 8.0 / elapsed.as_secs_f64() / 1_000_000.0;
+    let percent_of_wire = |bytes: u64| bytes as f64 * 100.0 / wire_bytes.max(1) as f64;
+    let retransmitted = lost_bytes.unwrap_or_default();
+    let protocol_overhead = wire_bytes.saturating_sub(app_bytes + retransmitted);
+    info!(
+        "{direction}: goodput {:.2} Mbit/s, wire {:.2} Mbit/s, retransmission overhead {}, \
+         protocol overhead {:.2}%",
+        mbps(app_bytes),
+        mbps(wire_bytes),
+        lost_bytes.map_or("unknown".to_string(), |lost| format!(
+            "{:.2}%",
+            percent_of_wire(lost)
+        )),
+        percent_of_wire(protocol_overhead),
+    );
+}
 
-                let mut has_failure = false;
-                loop {
-                    let result = stream.read_chunks(&mut chunks).await;
-                    match result {
-                        Ok(chunk) => match chunk {
-                            Some(n_chunks) => {
-                                let chunks = chunks.iter().take(n_chunks).cloned();
-                                let n_chunks = chunks.len();
-                                if n_chunks == 0 {
-                                    break;
-                                }
-                            }
-                            None => {
-                                break;
-                            }
-                        },
-                        Err(err) => {
-                            has_failure = true;
-                            error!("Had failure : {err:?}");
-                            break;
-                        }
-                    }
-                }
-                if !has_failure {
-                    total_received.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                    debug!("Received a stream!");
+/// Compare the average number of requests in flight with the one predicted by
+/// Little's Law from throughput and mean latency. A large disagreement means
+/// the measurement itself is broken, e.g. a window which does not cover the
+/// requests it counts. Returns whether the two agree.
+fn check_littles_law(throughput: f64, mean_latency_secs: f64, average_in_flight: f64) -> bool {
+    const TOLERANCE: f64 = 2.0;
 
-                    // now send a response via datagram
-                    let packet = vec!['a' as u8; PACKET_SIZE];
-                    let result = connection.send_datagram_wait(packet.clone().into()).await;
+    let predicted = throughput * mean_latency_secs;
+    info!(
+        "Little's Law: {throughput:.1} responses/sec x {:.3} ms mean latency = {predicted:.2} \
+         expected in flight, {average_in_flight:.2} measured",
+        mean_latency_secs * 1000.0,
+    );
+    if predicted == 0.0 && average_in_flight == 0.0 {
+        return true;
+    }
+    let ratio = predicted / average_in_flight;
+    let agree = (1.0 / TOLERANCE..=TOLERANCE).contains(&ratio);
+    if !agree {
+        warn!(
+            "Little's Law check failed: predicted in flight is {ratio:.2}x the measured \
+             average, throughput or latency numbers are likely wrong"
+        );
+    }
+    agree
+}
 
-                    match result {
-                        Ok(_) => {
-                            total_responses_sent.fetch_add(1, Ordering::Relaxed);
-                            trace!("Server Sent datagram?");
-                            task::yield_now().await;
-                        }
-                        Err(err) => {
-                            error!("Server send datagram error {err:?}");
-                        }
-                    }
-                }
-            }
-            Err(err) => {
-                info!(
-                    "Got error {err:?} for connection from {:?}",
-                    connection.remote_address()
-                );
-                break;
+/// Log the client's send and response rates along with the number of
+/// requests awaiting a response and the runtime metrics every 5 seconds.
+async fn report_client_stats(
+    conns: Vec<Connection>,
+    total_sent: Arc<AtomicUsize>,
+    responses: Arc<ResponseStats>,
+    in_flight: Arc<InFlightGauge>,
+    intervals: Arc<Mutex<Vec<IntervalRecord>>>,
+    pusher: Option<Arc<MetricsPusher>>,
+) {
+    const INTERVAL: Duration = Duration::from_secs(5);
+    const RATE_SAMPLE: Duration = Duration::from_secs(1);
+
+    let start = Instant::now();
+    let mut runtime = RuntimeSampler::current();
+    let mut interval = time::interval(INTERVAL);
+    interval.tick().await;
+    responses.take_interval_latency();
+    let (mut last_sent, mut last_received, mut last_errors) = (0, 0, 0);
+    let mut transport_events = TransportEventTracker::new(&conns);
+    let mut rate_sample = time::interval(RATE_SAMPLE);
+    let (mut sent_rates, mut received_rates) = (RateWindows::default(), RateWindows::default());
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = rate_sample.tick() => {
+                sent_rates.sample(total_sent.load(Ordering::Relaxed) as u64);
+                received_rates.sample(responses.received.load(Ordering::Relaxed) as u64);
+                continue;
             }
         }
+        let sent = total_sent.load(Ordering::Relaxed);
+        let received = responses.received.load(Ordering::Relaxed);
+        let errors = responses.expired.load(Ordering::Relaxed)
+            + responses.late.load(Ordering::Relaxed)
+            + responses.corrupted.load(Ordering::Relaxed);
+        let transport = transport_events.interval(&conns);
+        info!(
+            "Sent requests: {}, received responses: {}, outstanding: {} (max {}), transport: \
+             {transport}",
+            sent - last_sent,
+            received - last_received,
+            in_flight.current(),
+            in_flight.high_water_mark(),
+        );
+        info!("Request rate: {sent_rates}, response rate: {received_rates}");
+        info!("Client runtime: {}", runtime.sample());
+        let latency = responses.take_interval_latency();
+        let record = IntervalRecord {
+            elapsed_secs: start.elapsed().as_secs_f64(),
+            sent: sent - last_sent,
+            received: received - last_received,
+            responses_per_sec: (received - last_received) as f64 / INTERVAL.as_secs_f64(),
+            latency_p50_us: latency.percentile(50.0),
+            latency_p99_us: latency.percentile(99.0),
+            errors: errors - last_errors,
+            transport,
+        };
+        if let Some(pusher) = &pusher {
+            pusher.push("interval", &record).await;
+        }
+        intervals.lock().unwrap().push(record);
+        (last_sent, last_received, last_errors) = (sent, received, errors);
     }
-    Ok(())
 }
 
-// Driving the receiving of datagrams for a connection.
-async fn drive_datagram(
-    connection: quinn::Connection,
-    total_received: Arc<AtomicUsize>,
+/// Repeatedly attempt a handshake with the server until one succeeds or the
+/// timeout expires. The probe connection is closed right away.
+async fn wait_for_server(
+    endpoint: &Endpoint,
+    server_addr: SocketAddr,
+    server_name: &str,
+    timeout: Duration,
 ) -> Result<()> {
+    const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+    let deadline = Instant::now() + timeout;
+    let mut attempts = 0usize;
     loop {
-        let result = connection.read_datagram().await;
-        match result {
-            Ok(bytes) => {
-                total_received.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                debug!("Received a datagram bytes: {bytes:?}!");
-            }
-            Err(err) => {
-                info!(
-                    "Got error {err:?} for connection from {:?}",
-                    connection.remote_address()
-                );
-                break;
+        attempts += 1;
+        let connecting = endpoint.connect(server_addr, server_name)?;
+        match time::timeout(PROBE_INTERVAL, connecting).await {
+            Ok(Ok(conn)) => {
+                conn.close(0u32.into(), b"probe");
+                info!("Server {server_addr} is connectable after {attempts} attempt(s)");
+                return Ok(());
             }
+            Ok(Err(err)) => debug!("Probe {attempts} to {server_addr} failed: {err:?}"),
+            Err(_) => debug!("Probe {attempts} to {server_addr} timed out"),
         }
+        if Instant::now() >= deadline {
+            anyhow::bail!("server {server_addr} not connectable after {attempts} attempts");
+        }
+        time::sleep(PROBE_INTERVAL / 10).await;
     }
-    Ok(())
 }
 
-async fn run_client(opt: &Opt) -> Result<()> {
-    let mut server_addr: SocketAddr = opt
-        .server_address
-        .parse()
-        .expect("Invalid server address format");
-
-    if server_addr.ip().is_unspecified() {
-        server_addr.set_ip(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
-        //server_addr.set_ip(IpAddr::V4(Ipv4Addr::new(145, 40, 90, 189)));
-    }
-    info!("Connecting to server {server_addr:?}");
-    let endpoints = setup_client(opt.num_threads).expect("Failed to create client");
-
-    let packet = vec![0; PACKET_SIZE];
-    let start = Instant::now();
+/// Thread names `<prefix>-0`, `<prefix>-1`, ... so that every thread can be
+/// told apart in `perf`, `top -H` and the log. Linux keeps 15 characters.
+fn numbered_thread_names(prefix: impl Into<String>) -> impl Fn() -> String + Send + Sync + 'static {
+    let prefix = prefix.into();
+    let next = AtomicUsize::new(0);
+    move || format!("{prefix}-{}", next.fetch_add(1, Ordering::Relaxed))
+}
 
-    let mut conns: Vec<Connection> = Vec::default();
-    let total_sent = Arc::new(AtomicUsize::default());
-    let total_received_responses = Arc::new(AtomicUsize::new(0));
-    for i in 0..opt.num_threads {
-        let conn = endpoints[i]
-            .connect(server_addr, "localhost")
-            .expect("Failed to connect")
-            .await
-            .expect("Connection failed");
-        conns.push(conn.clone());
-        let packet = packet.clone();
-        let num_packets = opt.num_packets;
-        let total_sent = total_sent.clone();
-        let total_received_responses = total_received_responses.clone();
-        let conn_t = conn.clone();
-        tokio::spawn(drive_datagram(conn_t, total_received_responses.clone()));
-
-        task::spawn(async move {
-            for _ in 0..num_packets {
-                let mut stream = conn.open_uni().await.unwrap();
-                let result = stream.write_all(&packet).await;
-
-                match result {
-                    Ok(_) => {
-                        total_sent.fetch_add(1, Ordering::Relaxed);
-                        trace!("Sent stream?");
-                        task::yield_now().await;
-                    }
-                    Err(err) => {
-                        error!("Send stream error {err:?}");
+/// Run all `futures` concurrently on the current task and return their
+/// outputs in order.
+async fn join_all<F: Future>(futures: Vec<F>) -> Vec<F::Output> {
+    let mut futures: Vec<_> = futures
+        .into_iter()
+        .map(|future| Some(Box::pin(future)))
+        .collect();
+    let mut outputs: Vec<_> = futures.iter().map(|_| None).collect();
+    std::future::poll_fn(|cx| {
+        let mut pending = false;
+        for (slot, output) in futures.iter_mut().zip(&mut outputs) {
+            if let Some(future) = slot {
+                match future.as_mut().poll(cx) {
+                    Poll::Ready(value) => {
+                        *output = Some(value);
+                        *slot = None;
                     }
+                    Poll::Pending => pending = true,
                 }
             }
-        });
-    }
-
-    let duration = start.elapsed().as_secs_f64();
-    let total_sent = total_sent.load(Ordering::Relaxed);
-    info!(
-        "Sent (written to buffer) {} packets in {:.2} seconds ({:.2} packets/sec)",
-        total_sent,
-        duration,
-        total_sent as f64 / duration
-    );
+        }
+        if pending {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    })
+    .await;
+    outputs.into_iter().map(Option::unwrap).collect()
+}
 
-    // the following give the async sent datagrams to be sent out actually.
-    for i in 0..opt.num_threads {
-        endpoints[i].wait_idle().await;
+/// Spawn `future` on `runtime` as task `name`. The name is visible to
+/// tokio-console and task dumps in builds with `--cfg tokio_unstable`.
+fn spawn_named<F>(name: &str, runtime: &Handle, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(tokio_unstable)]
+    return task::Builder::new()
+        .name(name)
+        .spawn_on(future, runtime)
+        .expect("spawning task");
+    #[cfg(not(tokio_unstable))]
+    {
+        let _ = name;
+        runtime.spawn(future)
     }
-    Ok(())
 }
 
+/// Runtime of the server, whose threads count their allocations as server
+/// allocations.
 pub fn rt(name: String) -> Runtime {
     tokio::runtime::Builder::new_multi_thread()
-        .thread_name(name)
+        .thread_name_fn(numbered_thread_names(name))
+        .on_thread_start(alloc_stats::mark_server_thread)
         .enable_all()
         .build()
         .unwrap()
 }
 
+/// Start the runtime dedicated to server endpoint `index`, current-thread
+/// for a single thread, optionally pinned to `core_id`. The runtime is driven
+/// by a thread of its own for the life of the process.
+fn endpoint_rt(index: usize, threads: usize, core_id: Option<core_affinity::CoreId>) -> Handle {
+    let name = format!("{}-ep{index}", profile::SERVER_THREAD_NAME);
+    let on_thread_start = move || {
+        alloc_stats::mark_server_thread();
+        if let Some(core_id) = core_id {
+            if !core_affinity::set_for_current(core_id) {
+                warn!("Failed to pin endpoint {index} to core {}", core_id.id);
+            }
+        }
+    };
+    let mut builder = if threads <= 1 {
+        tokio::runtime::Builder::new_current_thread()
+    } else {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.worker_threads(threads);
+        builder
+    };
+    let runtime = builder
+        .thread_name_fn(numbered_thread_names(name.clone()))
+        .on_thread_start(on_thread_start)
+        .enable_all()
+        .build()
+        .unwrap();
+    let handle = runtime.handle().clone();
+    // A current-thread runtime only makes progress inside `block_on`.
+    std::thread::Builder::new()
+        .name(name)
+        .spawn(move || {
+            on_thread_start();
+            runtime.block_on(std::future::pending::<()>())
+        })
+        .unwrap();
+    handle
+}
+
+/// Create `count` server endpoints. With `runtimes` given, endpoint i is
+/// driven by `runtimes[i]`, otherwise by the current runtime. The receive
+/// batching of all endpoint sockets is counted in `recv_batches`.
 fn setup_server(
     opt: &Opt,
     addr: SocketAddr,
     count: usize,
+    runtimes: &[Handle],
+    recv_batches: Arc<RecvBatchStats>,
 ) -> Result<Vec<Endpoint>, Box<dyn std::error::Error>> {
     let (key, cert) = match (&opt.key, &opt.cert) {
         (Some(key), Some(cert)) => {
@@ -361,7 +3571,13 @@ fn setup_server(
             )
         }
         _ => {
-            let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+            let sans = if opt.cert_san.is_empty() {
+                vec!["localhost".to_string()]
+            } else {
+                opt.cert_san.clone()
+            };
+            info!("Generating self-signed certificate for {sans:?}");
+            let cert = rcgen::generate_simple_self_signed(sans)?;
             (
                 PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()),
                 vec![CertificateDer::from(cert.cert)],
@@ -380,37 +3596,76 @@ fn setup_server(
         ..default_provider
     };
 
-    let mut crypto = rustls::ServerConfig::builder_with_provider(provider.into())
+    let provider = Arc::new(provider);
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
         .with_protocol_versions(&[&rustls::version::TLS13])
-        .unwrap()
-        .with_no_client_auth()
-        .with_single_cert(cert, key.into())
         .unwrap();
-    crypto.alpn_protocols = vec![b"perf".to_vec()];
+    let builder = if let Some(path) = &opt.expected_pubkeys {
+        let pubkeys = pinning::load_expected_pubkeys(path)?;
+        builder.with_client_cert_verifier(PinnedClientKeys::new(provider, pubkeys))
+    } else if opt.request_client_certs {
+        info!("Asking clients for certificates");
+        builder.with_client_cert_verifier(AcceptAnyClientCert::new(provider))
+    } else {
+        builder.with_no_client_auth()
+    };
+    let mut crypto = builder.with_single_cert(cert, key.into()).unwrap();
+    crypto.alpn_protocols = vec![opt.alpn.as_bytes().to_vec()];
+    // Accept 0-RTT data of resumed sessions, quinn takes no other limit.
+    crypto.max_early_data_size = u32::MAX;
 
     let crypto = Arc::new(QuicServerConfig::try_from(crypto)?);
 
+    let mut endpoint_config = EndpointConfig::default();
+    if let Some(version) = opt.quic_version {
+        info!("Server supports only QUIC version {version:#x}");
+        endpoint_config.supported_versions(vec![version]);
+    }
+
     let mut transport_config = TransportConfig::default();
-    transport_config.datagram_receive_buffer_size(Some(PACKET_SIZE * 1024 * 1024));
+    transport_config
+        .datagram_receive_buffer_size((!opt.no_datagrams).then_some(PACKET_SIZE * 1024 * 1024));
 
     let mut server_config = ServerConfig::with_crypto(crypto);
     server_config.transport = Arc::new(transport_config);
+    if let Some(max_pending_incoming) = opt.max_pending_incoming {
+        server_config.max_incoming(max_pending_incoming);
+    }
 
     let mut endpoints = Vec::new();
 
-    let (_port, mut sockets) = solana_net_utils::multi_bind_in_range(
-        addr.ip(),
-        (addr.port(), addr.port() + count as u16),
-        count,
-    )
-    .unwrap();
+    let mut sockets = if opt.distinct_ports {
+        // Port 0 gives every endpoint its own ephemeral port.
+        (0..count)
+            .map(|i| {
+                let port = if addr.port() == 0 {
+                    0
+                } else {
+                    addr.port() + i as u16
+                };
+                std::net::UdpSocket::bind(SocketAddr::new(addr.ip(), port))
+            })
+            .collect::<Result<Vec<_>, _>>()?
+    } else {
+        let (_port, sockets) = solana_net_utils::multi_bind_in_range_with_config(
+            addr.ip(),
+            (addr.port(), addr.port() + count as u16),
+            solana_net_utils::SocketConfig::default().reuseport(true),
+            count,
+        )
+        .unwrap();
+        sockets
+    };
 
-    for socket in sockets.drain(..) {
-        let endpoint = Endpoint::new(
-            EndpointConfig::default(),
+    for (i, socket) in sockets.drain(..).enumerate() {
+        let _guard = runtimes.get(i).map(Handle::enter);
+        let runtime = Arc::new(TokioRuntime);
+        let socket = CountingSocket::new(runtime.wrap_udp_socket(socket)?, recv_batches.clone());
+        let endpoint = Endpoint::new_with_abstract_socket(
+            endpoint_config.clone(),
             Some(server_config.clone()),
-            socket,
-            Arc::new(TokioRuntime),
+            Arc::new(socket),
+            runtime,
         )?;
         endpoints.push(endpoint);
     }
@@ -472,7 +3727,13 @@ impl rustls::client::danger::ServerCertVerifier for SkipServerVerification {
     }
 }
 
-fn setup_client(count: usize) -> Result<Vec<Endpoint>, Box<dyn std::error::Error>> {
+fn setup_client(
+    opt: &Opt,
+    count: usize,
+    bind_ip: IpAddr,
+    identity: IdentityClass,
+    failures: &EndpointFailures,
+) -> Result<Vec<(usize, Endpoint)>, Box<dyn std::error::Error>> {
     info!("Setting up client");
     let default_provider = rustls::crypto::ring::default_provider();
     let provider = Arc::new(rustls::crypto::CryptoProvider {
@@ -487,14 +3748,62 @@ fn setup_client(count: usize) -> Result<Vec<Endpoint>, Box<dyn std::error::Error
 
     let mut transport_config = TransportConfig::default();
     transport_config.datagram_send_buffer_size(PACKET_SIZE * 1024 * 1024);
+    if opt.no_datagrams {
+        transport_config.datagram_receive_buffer_size(None);
+    }
+    let keep_alive_interval = match (opt.keep_alive_interval, opt.mode) {
+        (Some(0), _) => None,
+        (Some(ms), _) => Some(Duration::from_millis(ms)),
+        (None, Mode::Idle) => Some(Duration::from_secs(5)),
+        // Standby connections sit idle until a failover.
+        (None, _) if opt.standby_connections > 0 => Some(Duration::from_secs(5)),
+        (None, _) => None,
+    };
+    transport_config.keep_alive_interval(keep_alive_interval);
 
-    let mut crypto = rustls::ClientConfig::builder_with_provider(provider.clone())
+    let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
         .with_protocol_versions(&[&rustls::version::TLS13])
-        .unwrap()
-        .dangerous()
-        .with_custom_certificate_verifier(SkipServerVerification::new(provider))
-        .with_no_client_auth();
-    crypto.alpn_protocols = vec![b"perf".to_vec()];
+        .unwrap();
+    let builder = if opt.verify_cert {
+        let ca_cert = opt
+            .ca_cert
+            .as_ref()
+            .ok_or("--verify-cert requires --ca-cert")?;
+        let ca_cert = fs::read(ca_cert)?;
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut ca_cert.as_ref()) {
+            roots.add(cert?)?;
+        }
+        info!(
+            "Verifying server certificates for {} against {} trusted certificates",
+            opt.server_name,
+            roots.len()
+        );
+        builder.with_root_certificates(roots)
+    } else {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(SkipServerVerification::new(provider))
+    };
+    let client_cert = match (&opt.client_cert, &opt.client_key) {
+        (Some(cert), Some(key)) => {
+            let cert = fs::read(cert)?;
+            let key = fs::read(key)?;
+            Some((
+                rustls_pemfile::certs(&mut cert.as_ref()).collect::<Result<_, _>>()?,
+                rustls_pemfile::private_key(&mut key.as_ref())?
+                    .ok_or("no private key in --client-key")?,
+            ))
+        }
+        (None, None) => identity.certificate()?,
+        _ => return Err("--client-cert and --client-key go together".into()),
+    };
+    let mut crypto = match client_cert {
+        Some((cert, key)) => builder.with_client_auth_cert(cert, key)?,
+        None => builder.with_no_client_auth(),
+    };
+    crypto.alpn_protocols = vec![opt.alpn.as_bytes().to_vec()];
+    crypto.enable_early_data = opt.send_0rtt;
 
     info!("Setting up QuicClientConfig...");
 
@@ -503,15 +3812,59 @@ fn setup_client(count: usize) -> Result<Vec<Endpoint>, Box<dyn std::error::Error
     let mut client_config = quinn::ClientConfig::new(crypto);
 
     client_config.transport_config(Arc::new(transport_config));
+    // quinn does not negotiate compatible versions, every established
+    // connection uses the client's initial version.
+    let version = opt.quic_version.unwrap_or(1);
+    client_config.version(version);
+    info!("Client uses QUIC version {version:#x}");
 
     info!("Creating client endpoint...");
 
     let mut endpoints = Vec::new();
 
-    for _ in 0..count {
-        let mut endpoint = Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0))?;
+    for index in 0..count {
+        let bound = if identity.random_port() {
+            identity::bind_random_port(bind_ip).and_then(|socket| {
+                Endpoint::new(
+                    EndpointConfig::default(),
+                    None,
+                    socket,
+                    Arc::new(TokioRuntime),
+                )
+            })
+        } else {
+            Endpoint::client(SocketAddr::new(bind_ip, 0))
+        };
+        // Out of local ports, the run goes on with the endpoints bound so far.
+        let mut endpoint = match bound {
+            Ok(endpoint) => endpoint,
+            Err(err) if endpoint_failures::is_address_exhausted(&err) => {
+                failures.record(index, None, err);
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
         endpoint.set_default_client_config(client_config.clone());
-        endpoints.push(endpoint);
+        endpoints.push((index, endpoint));
+    }
+    if endpoints.is_empty() {
+        return Err("no client endpoint could be bound".into());
     }
     Ok(endpoints)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn littles_law() {
+        // 1000 responses/sec at 10 ms keep 10 requests in flight.
+        assert!(check_littles_law(1000.0, 0.01, 10.0));
+        assert!(check_littles_law(1000.0, 0.01, 6.0));
+        assert!(!check_littles_law(1000.0, 0.01, 4.0));
+        assert!(!check_littles_law(1000.0, 0.01, 30.0));
+        assert!(!check_littles_law(0.0, 0.01, 10.0));
+        assert!(check_littles_law(0.0, 0.0, 0.0));
+    }
+}
@@ -0,0 +1,230 @@
+//! Push of interval statistics to timeseries databases during the run:
+//! InfluxDB line protocol over HTTP or UDP with `--influx-url`, and the
+//! Graphite plaintext protocol over TCP with `--graphite-addr`, and gauges of
+//! the OTLP export with `--otlp-endpoint`. Every numeric field of a record
+//! becomes one value, tagged with the run id.
+
+use {
+    crate::otlp::OtlpExport,
+    anyhow::{bail, Context, Result},
+    serde::Serialize,
+    serde_json::Value,
+    std::{
+        net::SocketAddr,
+        sync::Arc,
+        time::{SystemTime, UNIX_EPOCH},
+    },
+    tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpStream, UdpSocket},
+        sync::Mutex,
+    },
+    tracing::*,
+};
+
+enum Sink {
+    /// `host:port` and the path with query of an InfluxDB write endpoint.
+    InfluxHttp {
+        host: String,
+        path: String,
+    },
+    InfluxUdp(SocketAddr),
+    /// The connection is kept between pushes and reopened after errors.
+    Graphite {
+        addr: SocketAddr,
+        connection: Mutex<Option<TcpStream>>,
+    },
+    Otlp(Arc<OtlpExport>),
+}
+
+pub(crate) struct MetricsPusher {
+    sinks: Vec<Sink>,
+    run_id: String,
+}
+
+async fn resolve_one(addr: &str) -> Result<SocketAddr> {
+    tokio::net::lookup_host(addr)
+        .await?
+        .next()
+        .with_context(|| format!("{addr} did not resolve"))
+}
+
+/// Parse `http://host:port/write?db=name` or `udp://host:port`.
+async fn parse_influx_url(url: &str) -> Result<Sink> {
+    if let Some(rest) = url.strip_prefix("http://") {
+        let (host, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let path = if path.is_empty() { "/write" } else { path };
+        Ok(Sink::InfluxHttp {
+            host: host.to_string(),
+            path: path.to_string(),
+        })
+    } else if let Some(addr) = url.strip_prefix("udp://") {
+        Ok(Sink::InfluxUdp(resolve_one(addr).await?))
+    } else {
+        bail!(
+            "unsupported InfluxDB URL {url:?}, expected http://host:port/write?db=name or \
+             udp://host:port"
+        )
+    }
+}
+
+/// Numeric fields of `record`, booleans as 0 or 1.
+fn numeric_fields(record: &impl Serialize) -> Result<Vec<(String, f64)>> {
+    let Value::Object(fields) = serde_json::to_value(record)? else {
+        bail!("metrics are not a record");
+    };
+    Ok(fields
+        .into_iter()
+        .filter_map(|(name, value)| {
+            let value = match value {
+                Value::Number(number) => number.as_f64()?,
+                Value::Bool(flag) => f64::from(u8::from(flag)),
+                _ => return None,
+            };
+            Some((name, value))
+        })
+        .collect())
+}
+
+impl MetricsPusher {
+    pub(crate) async fn new(
+        influx_url: Option<&str>,
+        graphite_addr: Option<&str>,
+        otlp: Option<Arc<OtlpExport>>,
+        run_id: &str,
+    ) -> Result<Option<Self>> {
+        let mut sinks = Vec::new();
+        if let Some(url) = influx_url {
+            sinks.push(parse_influx_url(url).await?);
+        }
+        if let Some(addr) = graphite_addr {
+            sinks.push(Sink::Graphite {
+                addr: resolve_one(addr).await?,
+                connection: Mutex::new(None),
+            });
+        }
+        sinks.extend(otlp.map(Sink::Otlp));
+        Ok((!sinks.is_empty()).then(|| Self {
+            sinks,
+            run_id: run_id.to_string(),
+        }))
+    }
+
+    /// Push the numeric fields of `record` as `measurement`. Failures are
+    /// logged, the run goes on without the values.
+    pub(crate) async fn push(&self, measurement: &str, record: &impl Serialize) {
+        let fields = match numeric_fields(record) {
+            Ok(fields) => fields,
+            Err(err) => {
+                error!("Failed to push {measurement} metrics: {err:#}");
+                return;
+            }
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        for sink in &self.sinks {
+            let result = match sink {
+                Sink::InfluxHttp { host, path } => {
+                    let line = self.influx_line(measurement, &fields, now.as_nanos());
+                    post_influx(host, path, &line).await
+                }
+                Sink::InfluxUdp(addr) => {
+                    let line = self.influx_line(measurement, &fields, now.as_nanos());
+                    send_udp(*addr, &line).await
+                }
+                Sink::Graphite { addr, connection } => {
+                    let lines = self.graphite_lines(measurement, &fields, now.as_secs());
+                    send_graphite(*addr, connection, &lines).await
+                }
+                Sink::Otlp(otlp) => {
+                    for (name, value) in &fields {
+                        otlp.record(&format!("{measurement}.{name}"), *value, &self.run_id);
+                    }
+                    Ok(())
+                }
+            };
+            if let Err(err) = result {
+                warn!("Failed to push {measurement} metrics: {err:#}");
+            }
+        }
+    }
+
+    fn influx_line(&self, measurement: &str, fields: &[(String, f64)], timestamp: u128) -> String {
+        let fields: Vec<_> = fields
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect();
+        format!(
+            "{measurement},run_id={} {} {timestamp}\n",
+            self.run_id,
+            fields.join(",")
+        )
+    }
+
+    fn graphite_lines(
+        &self,
+        measurement: &str,
+        fields: &[(String, f64)],
+        timestamp: u64,
+    ) -> String {
+        fields
+            .iter()
+            .map(|(name, value)| {
+                format!(
+                    "quicbench.{}.{measurement}.{name} {value} {timestamp}\n",
+                    self.run_id
+                )
+            })
+            .collect()
+    }
+}
+
+async fn post_influx(host: &str, path: &str, body: &str) -> Result<()> {
+    let mut stream = TcpStream::connect(host).await?;
+    let request = format!(
+        "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: text/plain\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => bail!("InfluxDB answered {status:?}"),
+    }
+}
+
+async fn send_udp(addr: SocketAddr, payload: &str) -> Result<()> {
+    let bind: SocketAddr = if addr.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.send_to(payload.as_bytes(), addr).await?;
+    Ok(())
+}
+
+async fn send_graphite(
+    addr: SocketAddr,
+    connection: &Mutex<Option<TcpStream>>,
+    lines: &str,
+) -> Result<()> {
+    let mut connection = connection.lock().await;
+    if connection.is_none() {
+        *connection = Some(TcpStream::connect(addr).await?);
+    }
+    let result = connection
+        .as_mut()
+        .unwrap()
+        .write_all(lines.as_bytes())
+        .await;
+    if result.is_err() {
+        *connection = None;
+    }
+    Ok(result?)
+}
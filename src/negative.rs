@@ -0,0 +1,241 @@
+//! Misconfiguration scenarios with `--negative`: a local server and client
+//! are set up to disagree, on the ALPN, on datagram support or on uni
+//! streams, and the error each side surfaces is checked against the one the
+//! scenario expects, along with how long after the first packet each side
+//! noticed.
+
+use {
+    anyhow::{bail, Context, Error, Result},
+    bytes::Bytes,
+    quinn::{
+        crypto::rustls::{QuicClientConfig, QuicServerConfig},
+        ClientConfig, Connection, ConnectionError, Endpoint, SendDatagramError, ServerConfig,
+        TransportConfig, TransportErrorCode,
+    },
+    rustls::{
+        pki_types::{CertificateDer, PrivatePkcs8KeyDer},
+        AlertDescription, RootCertStore,
+    },
+    serde::Serialize,
+    std::{
+        fmt,
+        net::{Ipv4Addr, SocketAddr},
+        str::FromStr,
+        sync::Arc,
+        time::{Duration, Instant},
+    },
+    tokio::{sync::oneshot, time},
+    tracing::*,
+};
+
+const ALPN: &[u8] = b"perf";
+const SERVER_NAME: &str = "localhost";
+/// Longest either side may take to notice the misconfiguration.
+const DETECTION_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long opening a stream without credit has to stay blocked.
+const STALL_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum NegativeScenario {
+    /// The client offers an ALPN the server does not speak.
+    AlpnMismatch,
+    /// The server does not accept datagrams.
+    NoDatagrams,
+    /// The server grants no uni streams.
+    NoUniStreams,
+}
+
+impl fmt::Display for NegativeScenario {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            NegativeScenario::AlpnMismatch => "alpn-mismatch",
+            NegativeScenario::NoDatagrams => "no-datagrams",
+            NegativeScenario::NoUniStreams => "no-uni-streams",
+        })
+    }
+}
+
+impl FromStr for NegativeScenario {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "alpn-mismatch" => Ok(NegativeScenario::AlpnMismatch),
+            "no-datagrams" => Ok(NegativeScenario::NoDatagrams),
+            "no-uni-streams" => Ok(NegativeScenario::NoUniStreams),
+            _ => bail!(
+                "unknown negative scenario {s:?}, expected \"alpn-mismatch\", \
+                 \"no-datagrams\" or \"no-uni-streams\""
+            ),
+        }
+    }
+}
+
+/// Run every scenario in `scenarios`, logging what each side surfaced.
+/// Returns an error if any scenario did not fail as expected.
+pub(crate) async fn run_scenarios(scenarios: &[NegativeScenario]) -> Result<()> {
+    let mut unexpected = 0;
+    for scenario in scenarios {
+        if let Err(err) = check(*scenario).await {
+            unexpected += 1;
+            error!("Negative scenario {scenario}: {err:#}");
+        }
+    }
+    if unexpected > 0 {
+        bail!("{unexpected} scenarios did not fail as expected");
+    }
+    Ok(())
+}
+
+async fn check(scenario: NegativeScenario) -> Result<()> {
+    let mut server_transport = TransportConfig::default();
+    let mut client_alpn = ALPN;
+    match scenario {
+        NegativeScenario::AlpnMismatch => client_alpn = b"not-perf",
+        NegativeScenario::NoDatagrams => {
+            server_transport.datagram_receive_buffer_size(None);
+        }
+        NegativeScenario::NoUniStreams => {
+            server_transport.max_concurrent_uni_streams(0u32.into());
+        }
+    }
+
+    let cert = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])?;
+    let cert_der = CertificateDer::from(cert.cert);
+    let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+    let mut crypto = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(&[&rustls::version::TLS13])?
+    .with_no_client_auth()
+    .with_single_cert(vec![cert_der.clone()], key.into())?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let mut server_config =
+        ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?));
+    server_config.transport = Arc::new(server_transport);
+
+    let mut roots = RootCertStore::empty();
+    roots.add(cert_der)?;
+    let mut crypto = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(&[&rustls::version::TLS13])?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    crypto.alpn_protocols = vec![client_alpn.to_vec()];
+    let client_config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto)?));
+
+    let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    let server = Endpoint::server(server_config, localhost)?;
+    let server_addr = server.local_addr()?;
+    let client = Endpoint::client(localhost)?;
+
+    let start = Instant::now();
+    let (accepted_sender, accepted) = oneshot::channel();
+    let acceptor = {
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Some(incoming) = server.accept().await {
+                let result = incoming.await;
+                let _ = accepted_sender.send((start.elapsed(), result));
+            }
+        })
+    };
+    let connecting = client.connect_with(client_config, server_addr, SERVER_NAME)?;
+    let client_result = time::timeout(DETECTION_TIMEOUT, connecting)
+        .await
+        .context("client handshake did not complete")?;
+    let client_at = start.elapsed();
+    let (server_at, server_result) = time::timeout(DETECTION_TIMEOUT, accepted)
+        .await
+        .context("server handshake did not complete")?
+        .context("server saw no connection")?;
+
+    let result = match scenario {
+        NegativeScenario::AlpnMismatch => {
+            let expected =
+                TransportErrorCode::crypto(u8::from(AlertDescription::NoApplicationProtocol));
+            expect_code(scenario, "client", client_result.err(), expected, client_at).and(
+                expect_code(scenario, "server", server_result.err(), expected, server_at),
+            )
+        }
+        NegativeScenario::NoDatagrams => {
+            let connection = client_result?;
+            let _server_connection = server_result?;
+            expect_no_datagrams(scenario, &connection, start)
+        }
+        NegativeScenario::NoUniStreams => {
+            let connection = client_result?;
+            let _server_connection = server_result?;
+            expect_blocked_uni(scenario, &connection).await
+        }
+    };
+    client.close(0u32.into(), b"done");
+    server.close(0u32.into(), b"done");
+    acceptor.abort();
+    result
+}
+
+/// Check that one side's handshake failed with transport error `expected`.
+fn expect_code(
+    scenario: NegativeScenario,
+    side: &str,
+    err: Option<ConnectionError>,
+    expected: TransportErrorCode,
+    after: Duration,
+) -> Result<()> {
+    let code = match &err {
+        Some(ConnectionError::TransportError(err)) => Some(err.code),
+        Some(ConnectionError::ConnectionClosed(close)) => Some(close.error_code),
+        _ => None,
+    };
+    match (code, err) {
+        (Some(code), Some(err)) if code == expected => {
+            info!("Negative scenario {scenario}: {side} failed after {after:?} with {err}");
+            Ok(())
+        }
+        (_, Some(err)) => bail!("{side} expected {expected}, failed with {err}"),
+        (_, None) => bail!("{side} expected {expected}, the handshake succeeded"),
+    }
+}
+
+/// Check that the client learns the server takes no datagrams, before
+/// sending one.
+fn expect_no_datagrams(
+    scenario: NegativeScenario,
+    connection: &Connection,
+    start: Instant,
+) -> Result<()> {
+    if let Some(size) = connection.max_datagram_size() {
+        bail!("client expected no datagram support, the server takes {size} bytes");
+    }
+    match connection.send_datagram(Bytes::from_static(b"probe")) {
+        Err(SendDatagramError::UnsupportedByPeer) => {
+            info!(
+                "Negative scenario {scenario}: client send_datagram failed after {:?} with \
+                 {}, the server had nothing to detect",
+                start.elapsed(),
+                SendDatagramError::UnsupportedByPeer
+            );
+            Ok(())
+        }
+        other => bail!("client expected the datagram to be unsupported by the peer, got {other:?}"),
+    }
+}
+
+/// Check that the client can not open a uni stream without the server's
+/// credit.
+async fn expect_blocked_uni(scenario: NegativeScenario, connection: &Connection) -> Result<()> {
+    match time::timeout(STALL_TIMEOUT, connection.open_uni()).await {
+        Err(_) => {
+            info!(
+                "Negative scenario {scenario}: client open_uni blocked for {STALL_TIMEOUT:?} \
+                 without stream credit, the server had nothing to detect"
+            );
+            Ok(())
+        }
+        Ok(Ok(_)) => bail!("client expected open_uni to block, a stream opened"),
+        Ok(Err(err)) => bail!("client expected open_uni to block, it failed with {err}"),
+    }
+}
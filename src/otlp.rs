@@ -0,0 +1,112 @@
+//! OpenTelemetry export over OTLP/gRPC with `--otlp-endpoint`: the tracing
+//! spans of the run (run, connection, sampled stream sends and response
+//! receives) and the interval statistics as gauges, all carrying the run id.
+//! Needs a build with --features otlp.
+
+use {
+    anyhow::Result,
+    tracing_subscriber::{Layer, Registry},
+};
+
+/// The tracing layer of the export, first on the registry.
+pub(crate) type OtlpLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+#[cfg(feature = "otlp")]
+use {
+    opentelemetry::{
+        metrics::{Gauge, Meter, MeterProvider as _},
+        trace::TracerProvider as _,
+        KeyValue,
+    },
+    opentelemetry_otlp::WithExportConfig,
+    opentelemetry_sdk::{
+        metrics::{PeriodicReader, SdkMeterProvider},
+        runtime,
+        trace::TracerProvider,
+        Resource,
+    },
+    std::{collections::HashMap, sync::Mutex},
+    tracing::error,
+};
+
+#[cfg(feature = "otlp")]
+const SERVICE_NAME: &str = "quic-bidir-test";
+
+#[cfg(feature = "otlp")]
+pub(crate) struct OtlpExport {
+    tracer_provider: TracerProvider,
+    meter_provider: SdkMeterProvider,
+    meter: Meter,
+    gauges: Mutex<HashMap<String, Gauge<f64>>>,
+}
+
+#[cfg(feature = "otlp")]
+impl OtlpExport {
+    pub(crate) fn start(endpoint: &str) -> Result<Self> {
+        let resource = Resource::new([KeyValue::new("service.name", SERVICE_NAME)]);
+        let span_exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()?;
+        let tracer_provider = TracerProvider::builder()
+            .with_batch_exporter(span_exporter, runtime::Tokio)
+            .with_resource(resource.clone())
+            .build();
+        let metric_exporter = opentelemetry_otlp::MetricExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()?;
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(metric_exporter, runtime::Tokio).build())
+            .with_resource(resource)
+            .build();
+        Ok(Self {
+            meter: meter_provider.meter(SERVICE_NAME),
+            tracer_provider,
+            meter_provider,
+            gauges: Mutex::default(),
+        })
+    }
+
+    pub(crate) fn layer(&self) -> OtlpLayer {
+        tracing_opentelemetry::layer()
+            .with_tracer(self.tracer_provider.tracer(SERVICE_NAME))
+            .boxed()
+    }
+
+    pub(crate) fn record(&self, name: &str, value: f64, run_id: &str) {
+        let mut gauges = self.gauges.lock().unwrap();
+        let gauge = gauges
+            .entry(name.to_string())
+            .or_insert_with(|| self.meter.f64_gauge(format!("quicbench.{name}")).build());
+        gauge.record(value, &[KeyValue::new("run_id", run_id.to_string())]);
+    }
+
+    /// Export what is still buffered.
+    pub(crate) fn shutdown(&self) {
+        if let Err(err) = self.tracer_provider.shutdown() {
+            error!("Failed to flush OTLP spans: {err}");
+        }
+        if let Err(err) = self.meter_provider.shutdown() {
+            error!("Failed to flush OTLP metrics: {err}");
+        }
+    }
+}
+
+#[cfg(not(feature = "otlp"))]
+pub(crate) struct OtlpExport;
+
+#[cfg(not(feature = "otlp"))]
+impl OtlpExport {
+    pub(crate) fn start(_endpoint: &str) -> Result<Self> {
+        anyhow::bail!("OTLP export needs a build with --features otlp")
+    }
+
+    pub(crate) fn layer(&self) -> OtlpLayer {
+        tracing_subscriber::layer::Identity::new().boxed()
+    }
+
+    pub(crate) fn record(&self, _name: &str, _value: f64, _run_id: &str) {}
+
+    pub(crate) fn shutdown(&self) {}
+}
@@ -0,0 +1,32 @@
+//! Sampled per-packet logging with `--sample-rate`: the details of every
+//! request and response are logged for only every nth message, so the
+//! logging does not perturb the measurement. The rate can be changed while
+//! running, on the server with `POST /sample-rate/<n>` on the stats API and
+//! on the client with the interactive `sample <n>` command. 0 turns it off.
+
+use {
+    std::sync::atomic::{AtomicU64, Ordering},
+    tracing::*,
+};
+
+static SAMPLE_RATE: AtomicU64 = AtomicU64::new(0);
+/// Messages seen while sampling, over the whole process.
+static MESSAGES: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn set_sample_rate(rate: u64) {
+    SAMPLE_RATE.store(rate, Ordering::Relaxed);
+    if rate == 0 {
+        info!("Per-packet logging off");
+    } else {
+        info!("Logging every {rate}th packet");
+    }
+}
+
+/// Whether the current message is logged in detail.
+pub(crate) fn sampled() -> bool {
+    let rate = SAMPLE_RATE.load(Ordering::Relaxed);
+    rate != 0
+        && MESSAGES
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(rate)
+}
@@ -0,0 +1,60 @@
+//! Server pipeline breakdown: how long the server waits in each phase of
+//! serving a request, to see which one saturates first under load.
+
+use {
+    crate::histogram::Histogram,
+    std::{fmt, mem, sync::Mutex, time::Instant},
+};
+
+#[derive(Clone, Copy)]
+pub(crate) enum Phase {
+    /// Waiting in `accept_uni` for the next request stream.
+    Accept,
+    /// Waiting for a stream slot with `--connection-scheduling fair`.
+    Queue,
+    /// Waiting in `read_chunks` for request data.
+    Read,
+    /// Waiting in `send_datagram_wait` for room to send the response.
+    Send,
+}
+
+#[derive(Default)]
+pub(crate) struct PhaseLatency {
+    phases: [Mutex<Histogram>; 4],
+}
+
+impl PhaseLatency {
+    /// Record the time since `start` for `phase`.
+    pub(crate) fn record(&self, phase: Phase, start: Instant) {
+        let waited = start.elapsed();
+        self.phases[phase as usize]
+            .lock()
+            .unwrap()
+            .record_duration(waited);
+    }
+
+    /// Return the histograms collected since the last call, resetting them.
+    pub(crate) fn take(&self) -> PhaseSnapshot {
+        PhaseSnapshot {
+            phases: self
+                .phases
+                .each_ref()
+                .map(|phase| mem::take(&mut *phase.lock().unwrap())),
+        }
+    }
+}
+
+pub(crate) struct PhaseSnapshot {
+    phases: [Histogram; 4],
+}
+
+impl fmt::Display for PhaseSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [accept, queue, read, send] = &self.phases;
+        write!(
+            f,
+            "accept_uni {accept}; stream slot {queue}; read_chunks {read}; send_datagram_wait \
+             {send}"
+        )
+    }
+}
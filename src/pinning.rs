@@ -0,0 +1,233 @@
+//! Client key pinning with `--expected-pubkeys`: the server requires a client
+//! certificate and accepts it only if its public key is on the allowlist,
+//! like production identifies its peers by the keypairs their certificates
+//! are derived from. The pubkey, hex encoded, then tags the connection in
+//! the logs, the stats API and the listener counts.
+//!
+//! The certificates are not otherwise validated: keypair-derived
+//! certificates are self-signed, the key is the identity.
+
+use {
+    anyhow::{bail, Context, Result},
+    quinn::Connection,
+    rustls::{
+        client::danger::HandshakeSignatureValid,
+        crypto::CryptoProvider,
+        pki_types::{CertificateDer, UnixTime},
+        server::danger::{ClientCertVerified, ClientCertVerifier},
+        CertificateError, DigitallySignedStruct, DistinguishedName, SignatureScheme,
+    },
+    std::{collections::HashSet, fs, path::Path, sync::Arc},
+    tracing::*,
+};
+
+/// Read the allowlist: one hex encoded public key per line, blank lines and
+/// lines starting with '#' ignored.
+pub(crate) fn load_expected_pubkeys(path: &Path) -> Result<HashSet<Vec<u8>>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("reading expected pubkeys {}", path.display()))?;
+    let mut pubkeys = HashSet::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match decode_hex(line) {
+            Some(pubkey) => pubkeys.insert(pubkey),
+            None => bail!(
+                "{}:{}: not a hex pubkey: {line:?}",
+                path.display(),
+                number + 1
+            ),
+        };
+    }
+    info!(
+        "Accepting clients with {} pubkeys from {}",
+        pubkeys.len(),
+        path.display()
+    );
+    Ok(pubkeys)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// One DER element of `input`: its tag, its contents and what follows it.
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, mut input) = input.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let octets = (first & 0x7f) as usize;
+        if octets == 0 || octets > 4 || input.len() < octets {
+            return None;
+        }
+        let len = input[..octets]
+            .iter()
+            .fold(0, |len, byte| (len << 8) | *byte as usize);
+        input = &input[octets..];
+        len
+    };
+    (input.len() >= len).then(|| (tag, &input[..len], &input[len..]))
+}
+
+/// The public key of a DER certificate, from its SubjectPublicKeyInfo.
+fn certificate_pubkey(certificate: &[u8]) -> Option<&[u8]> {
+    const SEQUENCE: u8 = 0x30;
+    const VERSION: u8 = 0xa0;
+    const BIT_STRING: u8 = 0x03;
+
+    let (SEQUENCE, certificate, _) = der_element(certificate)? else {
+        return None;
+    };
+    let (SEQUENCE, mut tbs, _) = der_element(certificate)? else {
+        return None;
+    };
+    if tbs.first() == Some(&VERSION) {
+        tbs = der_element(tbs)?.2;
+    }
+    // Serial number, signature algorithm, issuer, validity and subject.
+    for _ in 0..5 {
+        tbs = der_element(tbs)?.2;
+    }
+    let (SEQUENCE, spki, _) = der_element(tbs)? else {
+        return None;
+    };
+    let algorithm_rest = der_element(spki)?.2;
+    let (BIT_STRING, key, _) = der_element(algorithm_rest)? else {
+        return None;
+    };
+    // The first octet counts the unused bits, 0 for keys.
+    key.split_first().map(|(_, key)| key)
+}
+
+/// Hex encoded public key of the certificate the peer of `connection`
+/// presented.
+pub(crate) fn client_pubkey(connection: &Connection) -> Option<String> {
+    let certs = connection
+        .peer_identity()?
+        .downcast::<Vec<CertificateDer<'static>>>()
+        .ok()?;
+    certificate_pubkey(certs.first()?).map(encode_hex)
+}
+
+/// Server side verifier requiring a client certificate with a pubkey on the
+/// allowlist.
+#[derive(Debug)]
+pub(crate) struct PinnedClientKeys {
+    provider: Arc<CryptoProvider>,
+    pubkeys: HashSet<Vec<u8>>,
+}
+
+impl PinnedClientKeys {
+    pub(crate) fn new(provider: Arc<CryptoProvider>, pubkeys: HashSet<Vec<u8>>) -> Arc<Self> {
+        Arc::new(Self { provider, pubkeys })
+    }
+}
+
+impl ClientCertVerifier for PinnedClientKeys {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        match certificate_pubkey(end_entity) {
+            Some(pubkey) if self.pubkeys.contains(pubkey) => Ok(ClientCertVerified::assertion()),
+            Some(pubkey) => {
+                warn!("Rejecting client pubkey {}", encode_hex(pubkey));
+                Err(CertificateError::ApplicationVerificationFailure.into())
+            }
+            None => Err(CertificateError::BadEncoding.into()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_round_trip() {
+        assert_eq!(decode_hex("00ff7A"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(decode_hex(""), Some(Vec::new()));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(encode_hex(&[0x00, 0xff, 0x7a]), "00ff7a");
+    }
+
+    #[test]
+    fn der_element_lengths() {
+        assert_eq!(
+            der_element(&[0x04, 0x02, 1, 2, 9]),
+            Some((0x04, &[1, 2][..], &[9][..]))
+        );
+        let mut long = vec![0x04, 0x81, 0x80];
+        long.extend([7; 0x80]);
+        let (tag, contents, rest) = der_element(&long).unwrap();
+        assert_eq!((tag, contents.len(), rest.len()), (0x04, 0x80, 0));
+        assert_eq!(der_element(&[0x04, 0x03, 1, 2]), None);
+        assert_eq!(der_element(&[0x04, 0x80]), None);
+        assert_eq!(der_element(&[0x04]), None);
+    }
+
+    #[test]
+    fn pubkey_of_generated_certificate() {
+        let cert = rcgen::generate_simple_self_signed(vec!["client".to_string()]).unwrap();
+        assert_eq!(
+            certificate_pubkey(cert.cert.der()),
+            Some(cert.key_pair.public_key_raw())
+        );
+        assert_eq!(certificate_pubkey(&[0x30, 0x00]), None);
+    }
+}
@@ -0,0 +1,91 @@
+//! PMTU black hole scenario: part way through the run the impairment relay
+//! starts dropping packets larger than a tunnel MTU, and we watch whether
+//! quinn's black hole detection brings traffic back and how long it stalled.
+
+use {
+    crate::impair::Impairments,
+    quinn::Connection,
+    std::{
+        sync::{atomic::Ordering, Arc},
+        time::{Duration, Instant},
+    },
+    tokio::{
+        sync::oneshot,
+        time::{self, MissedTickBehavior},
+    },
+    tracing::*,
+};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Datagram frames received, i.e. responses, across `conns`.
+fn responses_received(conns: &[Connection]) -> u64 {
+    conns
+        .iter()
+        .map(|conn| conn.stats().frame_rx.datagram)
+        .sum()
+}
+
+/// Shrink the relay's maximum payload to `max_payload` after `after`, then
+/// sample the received responses until `stop` fires and report the longest
+/// stall and the black holes detected.
+pub(crate) async fn run_blackhole_scenario(
+    conns: Vec<Connection>,
+    impairments: Arc<Impairments>,
+    after: Duration,
+    max_payload: usize,
+    mut stop: oneshot::Receiver<()>,
+) {
+    tokio::select! {
+        _ = time::sleep(after) => {}
+        _ = &mut stop => {
+            warn!("Run finished before the PMTU black hole started");
+            return;
+        }
+    }
+    let mtus: Vec<_> = conns
+        .iter()
+        .map(|conn| conn.stats().path.current_mtu)
+        .collect();
+    info!("Starting PMTU black hole at {max_payload} bytes, current MTUs {mtus:?}");
+    impairments.set_max_payload(max_payload);
+
+    let mut interval = time::interval(SAMPLE_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let received_at_start = responses_received(&conns);
+    let mut last_received = received_at_start;
+    let mut last_progress = Instant::now();
+    // Only stalls which ended count, the run finishing is not a stall.
+    let mut longest_stall = Duration::ZERO;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = &mut stop => break,
+        }
+        let received = responses_received(&conns);
+        if received != last_received {
+            longest_stall = longest_stall.max(last_progress.elapsed());
+            last_received = received;
+            last_progress = Instant::now();
+        }
+    }
+
+    let black_holes: u64 = conns
+        .iter()
+        .map(|conn| conn.stats().path.black_holes_detected)
+        .sum();
+    let mtus: Vec<_> = conns
+        .iter()
+        .map(|conn| conn.stats().path.current_mtu)
+        .collect();
+    let responses_after = last_received - received_at_start;
+    info!(
+        "PMTU black hole: {} oversize packets dropped, {black_holes} black holes detected, \
+         longest stall {longest_stall:?}, {responses_after} responses afterwards, \
+         final MTUs {mtus:?}",
+        impairments.dropped_oversize.load(Ordering::Relaxed),
+    );
+    if responses_after == 0 {
+        warn!("Traffic did not recover from the PMTU black hole");
+    }
+}
@@ -0,0 +1,70 @@
+//! CPU profiling of the measured window, compiled in with `--features pprof`.
+//!
+//! The sampling profiler is process wide. Samples are split by thread name
+//! into the server runtime ("quicbench" threads) and everything else, the
+//! client, and each part is written as a flamegraph SVG.
+
+use {anyhow::Result, std::path::Path};
+
+/// Name of the server runtime threads.
+pub(crate) const SERVER_THREAD_NAME: &str = "quicbench";
+
+#[cfg(feature = "pprof")]
+pub(crate) struct ProfileSession {
+    guard: pprof::ProfilerGuard<'static>,
+}
+
+#[cfg(feature = "pprof")]
+impl ProfileSession {
+    const FREQUENCY_HZ: i32 = 999;
+
+    pub(crate) fn start() -> Result<Self> {
+        let guard = pprof::ProfilerGuardBuilder::default()
+            .frequency(Self::FREQUENCY_HZ)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()?;
+        tracing::info!("CPU profiling at {} Hz", Self::FREQUENCY_HZ);
+        Ok(Self { guard })
+    }
+
+    /// Stop sampling and write `<prefix>-client.svg` and `<prefix>-server.svg`
+    /// for the roles which have samples.
+    pub(crate) fn finish(self, prefix: &Path) -> Result<()> {
+        use std::{collections::HashMap, fs::File, path::PathBuf};
+
+        let report = self.guard.report().build()?;
+        let (server, client): (HashMap<_, _>, HashMap<_, _>) = report
+            .data
+            .into_iter()
+            .partition(|(frames, _)| frames.thread_name.starts_with(SERVER_THREAD_NAME));
+        for (role, data) in [("client", client), ("server", server)] {
+            if data.is_empty() {
+                continue;
+            }
+            let mut path = prefix.as_os_str().to_owned();
+            path.push(format!("-{role}.svg"));
+            let path = PathBuf::from(path);
+            let role_report = pprof::Report {
+                data,
+                timing: report.timing.clone(),
+            };
+            role_report.flamegraph(File::create(&path)?)?;
+            tracing::info!("Wrote {role} CPU profile to {}", path.display());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "pprof"))]
+pub(crate) struct ProfileSession;
+
+#[cfg(not(feature = "pprof"))]
+impl ProfileSession {
+    pub(crate) fn start() -> Result<Self> {
+        anyhow::bail!("CPU profiling needs a build with --features pprof")
+    }
+
+    pub(crate) fn finish(self, _prefix: &Path) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+//! Moving rates over the last 1, 10 and 60 seconds, shown next to the counts
+//! of the 5 second interval reports. A stall shorter than the interval hides
+//! in its count, the 1 second rate shows it.
+
+use std::{collections::VecDeque, fmt, time::Instant};
+
+/// Windows the rates are computed over, in seconds.
+const WINDOWS: [u64; 3] = [1, 10, 60];
+
+/// Timestamped samples of a cumulative count, taken about once a second.
+#[derive(Default)]
+pub(crate) struct RateWindows {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl RateWindows {
+    /// Record the current value of the cumulative count.
+    pub(crate) fn sample(&mut self, count: u64) {
+        self.sample_at(Instant::now(), count);
+    }
+
+    fn sample_at(&mut self, now: Instant, count: u64) {
+        self.samples.push_back((now, count));
+        let longest = WINDOWS[WINDOWS.len() - 1];
+        // Keep one sample older than the longest window to measure it from.
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0).as_secs() >= longest {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Rate per second over the last `window` seconds, over the time
+    /// sampled if that is shorter.
+    fn rate(&self, window: u64) -> Option<f64> {
+        let (now, latest) = *self.samples.back()?;
+        let (then, earlier) = self
+            .samples
+            .iter()
+            .rev()
+            .find(|(at, _)| now.duration_since(*at).as_secs() >= window)
+            .or(self.samples.front())?;
+        let elapsed = now.duration_since(*then).as_secs_f64();
+        (elapsed > 0.0).then(|| (latest - earlier) as f64 / elapsed)
+    }
+}
+
+impl fmt::Display for RateWindows {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, window) in WINDOWS.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            match self.rate(*window) {
+                Some(rate) => write!(f, "{window}s {rate:.1}/s")?,
+                None => write!(f, "{window}s -")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::time::Duration};
+
+    #[test]
+    fn rates_over_the_windows() {
+        let mut rates = RateWindows::default();
+        assert_eq!(rates.to_string(), "1s -, 10s -, 60s -");
+
+        let start = Instant::now();
+        // 100 per second for 20 seconds, then 1000 in the last second.
+        for second in 0..=20 {
+            rates.sample_at(start + Duration::from_secs(second), second * 100);
+        }
+        rates.sample_at(start + Duration::from_secs(21), 3000);
+        assert_eq!(rates.rate(1), Some(1000.0));
+        assert_eq!(rates.rate(10), Some(190.0));
+        // Only 21 seconds were sampled.
+        assert!((rates.rate(60).unwrap() - 3000.0 / 21.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn old_samples_are_dropped() {
+        let mut rates = RateWindows::default();
+        let start = Instant::now();
+        for second in 0..300 {
+            rates.sample_at(start + Duration::from_secs(second), second);
+        }
+        assert!(rates.samples.len() <= 62);
+        assert_eq!(rates.rate(60), Some(1.0));
+    }
+}
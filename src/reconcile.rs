@@ -0,0 +1,105 @@
+//! Reconciliation of the two halves of a combined run, with client and
+//! server in one process: what the client sent against what the server
+//! received, what the server answered against what the client got back.
+//! Each half reports on its own, a request lost between them shows only
+//! here.
+
+use {
+    crate::{ServerStats, StreamSummary},
+    tracing::*,
+};
+
+/// One direction of the run: the count of the sending and of the receiving
+/// half.
+struct Row {
+    what: &'static str,
+    sender: &'static str,
+    sent: usize,
+    receiver: &'static str,
+    received: Option<usize>,
+}
+
+impl Row {
+    fn difference(&self) -> Option<i64> {
+        self.received
+            .map(|received| self.sent as i64 - received as i64)
+    }
+}
+
+/// Log the reconciliation table of the stream workloads in `summaries`
+/// against the counts of the server, warning about every discrepancy.
+/// Client received is not known when `responses_expected` is off.
+pub(crate) fn report(summaries: &[StreamSummary], stats: &ServerStats, responses_expected: bool) {
+    let (streams_received, responses_sent) = stats.connections.stream_totals();
+    let client_sent = summaries.iter().map(|summary| summary.sent).sum();
+    let client_received = summaries.iter().map(|summary| summary.received).sum();
+    let rows = [
+        Row {
+            what: "Requests",
+            sender: "client sent",
+            sent: client_sent,
+            receiver: "server received",
+            received: Some(streams_received),
+        },
+        Row {
+            what: "Responses",
+            sender: "server sent",
+            sent: responses_sent,
+            receiver: "client received",
+            received: responses_expected.then_some(client_received),
+        },
+    ];
+
+    info!("Reconciliation of client and server:");
+    info!(
+        "  {:<10} {:>16} {:>16} {:>12}",
+        "", "sent", "received", "difference"
+    );
+    for row in &rows {
+        let received = row
+            .received
+            .map_or_else(|| "-".to_string(), |received| received.to_string());
+        let difference = row
+            .difference()
+            .map_or_else(|| "-".to_string(), |difference| format!("{difference:+}"));
+        let flag = if row.difference().unwrap_or(0) != 0 {
+            "  <<"
+        } else {
+            ""
+        };
+        info!(
+            "  {:<10} {:>16} {:>16} {:>12}{flag}",
+            row.what, row.sent, received, difference
+        );
+    }
+    for row in &rows {
+        match row.difference() {
+            Some(difference) if difference > 0 => warn!(
+                "{}: {} {}, {} {}, {difference} missing",
+                row.what,
+                row.sender,
+                row.sent,
+                row.receiver,
+                row.received.unwrap_or_default(),
+            ),
+            Some(difference) if difference < 0 => warn!(
+                "{}: {} {}, {} {}, {} more received than sent",
+                row.what,
+                row.sender,
+                row.sent,
+                row.receiver,
+                row.received.unwrap_or_default(),
+                -difference,
+            ),
+            _ => {}
+        }
+    }
+    let expired: usize = summaries.iter().map(|summary| summary.expired).sum();
+    let late: usize = summaries.iter().map(|summary| summary.late).sum();
+    if expired + late > 0 && rows.iter().any(|row| row.difference().unwrap_or(0) != 0) {
+        info!(
+            "The client gave up on {expired} requests and got {late} responses after the \
+             timeout, which may explain the differences"
+        );
+    }
+}
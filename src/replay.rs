@@ -0,0 +1,104 @@
+//! Record and replay of the request timing of the stream workload. With
+//! `--record` every request's connection, gap since the previous request on
+//! that connection and size are written as CSV; `--replay` sends the same
+//! sequence again, so a captured burst pattern can be reproduced exactly.
+
+use {
+    anyhow::{bail, Context, Result},
+    std::{
+        fs::{self, File},
+        io::{BufWriter, Write},
+        path::Path,
+        sync::Mutex,
+        time::{Duration, Instant},
+    },
+    tracing::*,
+};
+
+const HEADER: &str = "connection,gap_us,size";
+
+/// One request of a replay schedule.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ScheduledRequest {
+    /// Time since the previous request of the connection.
+    pub(crate) gap: Duration,
+    pub(crate) size: usize,
+}
+
+pub(crate) struct Recorder {
+    writer: Mutex<BufWriter<File>>,
+    /// Time of the last request per connection.
+    last_sends: Mutex<Vec<Option<Instant>>>,
+}
+
+impl Recorder {
+    pub(crate) fn create(path: &Path, connections: usize) -> Result<Self> {
+        let mut writer = BufWriter::new(
+            File::create(path).with_context(|| format!("creating {}", path.display()))?,
+        );
+        writeln!(writer, "{HEADER}")?;
+        info!("Recording request timing to {}", path.display());
+        Ok(Self {
+            writer: Mutex::new(writer),
+            last_sends: Mutex::new(vec![None; connections]),
+        })
+    }
+
+    /// Record a request of `size` bytes starting now on `connection`.
+    pub(crate) fn record(&self, connection: usize, size: usize) {
+        let now = Instant::now();
+        let gap = {
+            let mut last_sends = self.last_sends.lock().unwrap();
+            let last = last_sends[connection].replace(now);
+            last.map_or(Duration::ZERO, |last| now - last)
+        };
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(err) = writeln!(writer, "{connection},{},{size}", gap.as_micros()) {
+            error!("Failed to record request timing: {err}");
+        }
+    }
+
+    pub(crate) fn flush(&self) {
+        if let Err(err) = self.writer.lock().unwrap().flush() {
+            error!("Failed to write the request timing record: {err}");
+        }
+    }
+}
+
+/// Load the recording at `path` as one schedule per connection. Recordings
+/// of more connections than `connections` are folded onto the available
+/// ones.
+pub(crate) fn load(path: &Path, connections: usize) -> Result<Vec<Vec<ScheduledRequest>>> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    let mut lines = contents.lines();
+    if lines.next() != Some(HEADER) {
+        bail!("{} is not a request timing record", path.display());
+    }
+    let mut schedules = vec![Vec::new(); connections];
+    for (number, line) in lines.enumerate() {
+        let fields: Vec<_> = line.split(',').collect();
+        let [connection, gap_us, size] = fields[..] else {
+            bail!(
+                "line {} of {}: expected {HEADER}",
+                number + 2,
+                path.display()
+            );
+        };
+        let parse = |field: &str| {
+            field
+                .parse::<u64>()
+                .with_context(|| format!("line {} of {}", number + 2, path.display()))
+        };
+        schedules[parse(connection)? as usize % connections].push(ScheduledRequest {
+            gap: Duration::from_micros(parse(gap_us)?),
+            size: parse(size)? as usize,
+        });
+    }
+    info!(
+        "Replaying {} requests from {}",
+        schedules.iter().map(Vec::len).sum::<usize>(),
+        path.display()
+    );
+    Ok(schedules)
+}
@@ -0,0 +1,671 @@
+//! Client side correlation of requests with their datagram responses.
+//!
+//! Every request stream starts with its little-endian request id, which the
+//! server copies into the response so the client can measure round trip
+//! latency per request.
+//!
+//! In echo mode the server returns the whole request. The request id is then
+//! followed by a checksum over the rest of the request, and the body is a
+//! pattern derived from the id, so the client can tell a corrupted response
+//! from a lost one.
+//!
+//! With `--echo-prefix` the server returns the first bytes of the request
+//! only. The body of the request is the same pattern derived from the id,
+//! without a checksum, so the client verifies the prefix from its id alone.
+//!
+//! With `--ab-response-modes` the server alternates between datagram and
+//! stream responses by request id, and latency and loss are kept per mode.
+//!
+//! With `--messages-per-stream` a stream carries several requests, each
+//! framed by its little-endian u32 length, and latency is kept per position
+//! of the request within its stream.
+
+use {
+    crate::{anomaly::AnomalyTrigger, clock::Clock, histogram::Histogram, stages::StageLatency},
+    std::{
+        collections::HashMap,
+        fmt,
+        sync::{
+            atomic::{AtomicU64, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, Instant},
+    },
+    tokio::sync::Notify,
+    tracing::*,
+};
+
+pub(crate) const REQUEST_ID_LEN: usize = 8;
+
+pub(crate) fn encode_request_id(packet: &mut [u8], id: u64) {
+    let len = packet.len().min(REQUEST_ID_LEN);
+    packet[..len].copy_from_slice(&id.to_le_bytes()[..len]);
+}
+
+pub(crate) fn decode_request_id(bytes: &[u8]) -> Option<u64> {
+    let id = bytes.get(..REQUEST_ID_LEN)?;
+    Some(u64::from_le_bytes(id.try_into().unwrap()))
+}
+
+/// Length of the frame header of a request with --messages-per-stream.
+pub(crate) const FRAME_HEADER_LEN: usize = 4;
+
+pub(crate) fn encode_frame_header(len: usize) -> [u8; FRAME_HEADER_LEN] {
+    (len as u32).to_le_bytes()
+}
+
+/// Remove the first complete frame from `buffer` and return its message,
+/// `None` until the whole frame arrived.
+pub(crate) fn take_frame(buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let header = buffer.get(..FRAME_HEADER_LEN)?;
+    let len = u32::from_le_bytes(header.try_into().unwrap()) as usize;
+    if buffer.len() < FRAME_HEADER_LEN + len {
+        return None;
+    }
+    let message = buffer[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len].to_vec();
+    buffer.drain(..FRAME_HEADER_LEN + len);
+    Some(message)
+}
+
+/// How the server answers a request with --ab-response-modes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ResponseMode {
+    Datagram,
+    Stream,
+}
+
+impl ResponseMode {
+    pub(crate) const ALL: [ResponseMode; 2] = [ResponseMode::Datagram, ResponseMode::Stream];
+
+    /// The modes alternate by request id, so both see the same conditions.
+    pub(crate) fn of(id: u64) -> Self {
+        Self::ALL[(id % 2) as usize]
+    }
+}
+
+impl fmt::Display for ResponseMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ResponseMode::Datagram => "datagram",
+            ResponseMode::Stream => "stream",
+        })
+    }
+}
+
+/// Latency and loss per response mode with --ab-response-modes.
+#[derive(Default)]
+pub(crate) struct ResponseModeStats {
+    latency: [Mutex<Histogram>; 2],
+    received: [AtomicUsize; 2],
+    expired: [AtomicUsize; 2],
+}
+
+impl ResponseModeStats {
+    fn received(&self, id: u64, latency: Duration) {
+        let mode = ResponseMode::of(id) as usize;
+        self.received[mode].fetch_add(1, Ordering::Relaxed);
+        self.latency[mode].lock().unwrap().record_duration(latency);
+    }
+
+    fn expired(&self, id: u64) {
+        self.expired[ResponseMode::of(id) as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Log the modes side by side, with the requests of `outstanding` still
+    /// pending at the end of the run counted as lost.
+    pub(crate) fn report(&self, outstanding: &[Arc<Outstanding>]) {
+        for mode in ResponseMode::ALL {
+            let index = mode as usize;
+            let lost: usize = outstanding
+                .iter()
+                .map(|outstanding| outstanding.count_pending(|id| ResponseMode::of(id) == mode))
+                .sum();
+            let received = self.received[index].load(Ordering::Relaxed);
+            let expired = self.expired[index].load(Ordering::Relaxed);
+            info!(
+                "{mode} responses: {received} received, {expired} expired, {lost} lost \
+                 ({:.2}% failed), latency {}",
+                (expired + lost) as f64 * 100.0 / (received + expired + lost).max(1) as f64,
+                self.latency[index].lock().unwrap(),
+            );
+        }
+    }
+}
+
+/// Latency per position of the requests within their stream with
+/// --messages-per-stream. The position of each request is kept by the
+/// `Outstanding` of its connection, the ids being per connection.
+pub(crate) struct StreamPositionStats {
+    latency: Vec<Mutex<Histogram>>,
+}
+
+impl StreamPositionStats {
+    pub(crate) fn new(messages_per_stream: usize) -> Self {
+        Self {
+            latency: (0..messages_per_stream).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    fn received(&self, position: usize, latency: Duration) {
+        self.latency[position]
+            .lock()
+            .unwrap()
+            .record_duration(latency);
+    }
+
+    /// Log the latency of every position, the later ones wait for the
+    /// earlier ones of their stream.
+    pub(crate) fn report(&self) {
+        for (position, latency) in self.latency.iter().enumerate() {
+            info!(
+                "Latency at stream position {position}: {}",
+                latency.lock().unwrap()
+            );
+        }
+    }
+}
+
+const CHECKSUM_LEN: usize = 4;
+
+/// FNV-1a, good enough to catch corruption and cheap to compute.
+fn checksum(parts: &[&[u8]]) -> u32 {
+    parts
+        .iter()
+        .flat_map(|part| part.iter())
+        .fold(0x811c_9dc5, |hash, byte| {
+            (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+        })
+}
+
+/// Fill `packet` with an echo request: id, checksum and a body derived from
+/// the id.
+pub(crate) fn encode_echo_request(packet: &mut [u8], id: u64) {
+    let header_len = REQUEST_ID_LEN + CHECKSUM_LEN;
+    assert!(
+        packet.len() >= header_len,
+        "echo requests carry id and checksum"
+    );
+    packet[..REQUEST_ID_LEN].copy_from_slice(&id.to_le_bytes());
+    for (i, byte) in packet[header_len..].iter_mut().enumerate() {
+        *byte = id.wrapping_add(i as u64) as u8;
+    }
+    let sum = checksum(&[&packet[..REQUEST_ID_LEN], &packet[header_len..]]);
+    packet[REQUEST_ID_LEN..header_len].copy_from_slice(&sum.to_le_bytes());
+}
+
+/// Whether an echoed `response` is intact: `expected_len` bytes long with a
+/// checksum matching its id and body.
+fn verify_echo_response(response: &[u8], expected_len: usize) -> bool {
+    let header_len = REQUEST_ID_LEN + CHECKSUM_LEN;
+    if response.len() != expected_len || response.len() < header_len {
+        return false;
+    }
+    let sum = u32::from_le_bytes(response[REQUEST_ID_LEN..header_len].try_into().unwrap());
+    sum == checksum(&[&response[..REQUEST_ID_LEN], &response[header_len..]])
+}
+
+/// Fill `packet` with a request whose body is the pattern derived from the
+/// id, for --echo-prefix.
+pub(crate) fn encode_prefix_request(packet: &mut [u8], id: u64) {
+    encode_request_id(packet, id);
+    for (i, byte) in packet.iter_mut().enumerate().skip(REQUEST_ID_LEN) {
+        *byte = id.wrapping_add(i as u64) as u8;
+    }
+}
+
+/// Whether `response` is the `expected_len` byte prefix of the request
+/// `encode_prefix_request` filled in for its id.
+fn verify_prefix_response(response: &[u8], expected_len: usize) -> bool {
+    let Some(id) = decode_request_id(response) else {
+        return false;
+    };
+    response.len() == expected_len
+        && response
+            .iter()
+            .enumerate()
+            .skip(REQUEST_ID_LEN)
+            .all(|(i, byte)| *byte == id.wrapping_add(i as u64) as u8)
+}
+
+/// How the client verifies the responses of echoing servers.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum EchoCheck {
+    /// The whole request of this length, with --echo.
+    Full(usize),
+    /// A prefix of this length, with --echo-prefix.
+    Prefix(usize),
+}
+
+impl EchoCheck {
+    fn verify(self, response: &[u8]) -> bool {
+        match self {
+            EchoCheck::Full(len) => verify_echo_response(response, len),
+            EchoCheck::Prefix(len) => verify_prefix_response(response, len),
+        }
+    }
+}
+
+/// Number of requests awaiting a response across all connections, or those
+/// of one target.
+#[derive(Default)]
+pub(crate) struct InFlightGauge {
+    current: AtomicUsize,
+    high_water_mark: AtomicUsize,
+    /// Sum and count of periodic samples, for the time averaged gauge.
+    sample_sum: AtomicUsize,
+    samples: AtomicUsize,
+    /// Notified whenever requests complete.
+    released: Notify,
+}
+
+impl InFlightGauge {
+    fn increment(&self) {
+        let current = self.current.fetch_add(1, Ordering::Relaxed) + 1;
+        self.high_water_mark.fetch_max(current, Ordering::Relaxed);
+    }
+
+    fn decrement(&self, count: usize) {
+        self.current.fetch_sub(count, Ordering::Relaxed);
+        self.released.notify_waiters();
+    }
+
+    /// Wait until fewer than `limit` requests are in flight.
+    pub(crate) async fn wait_below(&self, limit: usize) {
+        loop {
+            let released = self.released.notified();
+            if self.current() < limit {
+                return;
+            }
+            released.await;
+        }
+    }
+
+    pub(crate) fn current(&self) -> usize {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn high_water_mark(&self) -> usize {
+        self.high_water_mark.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn sample(&self) {
+        self.sample_sum.fetch_add(self.current(), Ordering::Relaxed);
+        self.samples.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Average of the samples taken so far.
+    pub(crate) fn average(&self) -> f64 {
+        let samples = self.samples.load(Ordering::Relaxed);
+        if samples == 0 {
+            return 0.0;
+        }
+        self.sample_sum.load(Ordering::Relaxed) as f64 / samples as f64
+    }
+}
+
+/// Requests of one connection still awaiting their response.
+pub(crate) struct Outstanding {
+    next_id: AtomicU64,
+    /// Start timestamps of the requests, taken with `clock`.
+    pending: Mutex<HashMap<u64, u64>>,
+    clock: Clock,
+    gauge: Arc<InFlightGauge>,
+    /// Gauge of the target of the connection with --max-inflight-per-target.
+    target_gauge: Option<Arc<InFlightGauge>>,
+    /// Notified whenever requests leave `pending`.
+    released: Notify,
+    /// Latency of the responses received in time.
+    pub(crate) latency: Mutex<Histogram>,
+    /// When the write of the requests sampled with --stage-sample finished.
+    written: Mutex<HashMap<u64, Instant>>,
+    /// Position within their stream of the requests with
+    /// --messages-per-stream.
+    positions: Mutex<HashMap<u64, usize>>,
+}
+
+impl Outstanding {
+    pub(crate) fn new(
+        gauge: Arc<InFlightGauge>,
+        target_gauge: Option<Arc<InFlightGauge>>,
+        clock: Clock,
+    ) -> Self {
+        Self {
+            next_id: AtomicU64::default(),
+            pending: Mutex::default(),
+            clock,
+            gauge,
+            target_gauge,
+            released: Notify::new(),
+            latency: Mutex::default(),
+            written: Mutex::default(),
+            positions: Mutex::default(),
+        }
+    }
+
+    /// Allocate an id for a new request and start its clock.
+    pub(crate) fn start(&self) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let start = self.clock.now();
+        self.pending.lock().unwrap().insert(id, start);
+        self.gauge.increment();
+        if let Some(target_gauge) = &self.target_gauge {
+            target_gauge.increment();
+        }
+        id
+    }
+
+    /// Account `count` requests leaving `pending`.
+    fn release(&self, count: usize) {
+        self.gauge.decrement(count);
+        if let Some(target_gauge) = &self.target_gauge {
+            target_gauge.decrement(count);
+        }
+        self.released.notify_waiters();
+    }
+
+    /// Note that the write of sampled request `id` finished.
+    pub(crate) fn written(&self, id: u64) {
+        self.written.lock().unwrap().insert(id, Instant::now());
+    }
+
+    fn take_written(&self, id: u64) -> Option<Instant> {
+        self.written.lock().unwrap().remove(&id)
+    }
+
+    /// Note that request `id` is message `position` of its stream.
+    pub(crate) fn positioned(&self, id: u64, position: usize) {
+        self.positions.lock().unwrap().insert(id, position);
+    }
+
+    fn take_position(&self, id: u64) -> Option<usize> {
+        self.positions.lock().unwrap().remove(&id)
+    }
+
+    /// Allocate an id for a request no response is expected for.
+    pub(crate) fn start_untracked(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Forget a request which could not be sent.
+    pub(crate) fn cancel(&self, id: u64) {
+        self.take_written(id);
+        self.take_position(id);
+        if self.pending.lock().unwrap().remove(&id).is_some() {
+            self.release(1);
+        }
+    }
+
+    /// Complete request `id`, returning its latency unless it is unknown or
+    /// already expired.
+    fn complete(&self, id: u64) -> Option<Duration> {
+        let start = self.pending.lock().unwrap().remove(&id)?;
+        self.release(1);
+        Some(self.clock.since(start))
+    }
+
+    /// Drop every request older than `timeout`, passing their ids to
+    /// `expired_id`, returning how many expired.
+    fn expire(&self, timeout: Duration, mut expired_id: impl FnMut(u64)) -> usize {
+        let mut pending = self.pending.lock().unwrap();
+        let before = pending.len();
+        pending.retain(|id, start| {
+            let keep = self.clock.since(*start) < timeout;
+            if !keep {
+                self.take_written(*id);
+                self.take_position(*id);
+                expired_id(*id);
+            }
+            keep
+        });
+        let expired = before - pending.len();
+        if expired > 0 {
+            self.release(expired);
+        }
+        expired
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// Number of pending requests whose id matches `filter`.
+    fn count_pending(&self, filter: impl Fn(u64) -> bool) -> usize {
+        let pending = self.pending.lock().unwrap();
+        pending.keys().filter(|id| filter(**id)).count()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pending.lock().unwrap().is_empty()
+    }
+
+    /// Wait until fewer than `limit` requests are pending.
+    pub(crate) async fn wait_below(&self, limit: usize) {
+        loop {
+            let released = self.released.notified();
+            if self.len() < limit {
+                return;
+            }
+            released.await;
+        }
+    }
+}
+
+pub(crate) struct ResponseStats {
+    /// Responses arriving later than this count as expired rather than
+    /// contributing to the latency distribution.
+    timeout: Option<Duration>,
+    /// Set when the server echoes requests, enabling verification of every
+    /// response.
+    echo_check: Option<EchoCheck>,
+    /// Responses completing their request within the response timeout,
+    /// disjoint from `expired`, `late` and `corrupted`.
+    pub(crate) received: AtomicUsize,
+    /// Bytes of all verified responses, including expired and late ones.
+    pub(crate) bytes_received: AtomicUsize,
+    /// Requests without a response within the response timeout.
+    pub(crate) expired: AtomicUsize,
+    /// Responses for requests which had already expired, or carrying an
+    /// unknown id.
+    pub(crate) late: AtomicUsize,
+    /// Echoed responses failing verification, or prefixes not matching the
+    /// request, not counted as received.
+    pub(crate) corrupted: AtomicUsize,
+    pub(crate) latency: Mutex<Histogram>,
+    /// Latency since the last `take_recent_latency`.
+    recent_latency: Mutex<Histogram>,
+    /// Latency since the last `take_interval_latency`, for the reporter.
+    interval_latency: Mutex<Histogram>,
+    /// Told about every latency and failed request.
+    anomaly: Option<Arc<AnomalyTrigger>>,
+    /// Kept with --ab-response-modes.
+    pub(crate) response_modes: Option<ResponseModeStats>,
+    /// Kept with --stage-sample.
+    pub(crate) stages: Option<StageLatency>,
+    /// Kept with --messages-per-stream.
+    pub(crate) positions: Option<StreamPositionStats>,
+}
+
+impl ResponseStats {
+    pub(crate) fn new(
+        timeout: Option<Duration>,
+        echo_check: Option<EchoCheck>,
+        anomaly: Option<Arc<AnomalyTrigger>>,
+        ab_response_modes: bool,
+        stage_sample: Option<u64>,
+        messages_per_stream: Option<usize>,
+    ) -> Self {
+        Self {
+            timeout,
+            echo_check,
+            received: AtomicUsize::default(),
+            bytes_received: AtomicUsize::default(),
+            expired: AtomicUsize::default(),
+            late: AtomicUsize::default(),
+            corrupted: AtomicUsize::default(),
+            latency: Mutex::default(),
+            recent_latency: Mutex::default(),
+            interval_latency: Mutex::default(),
+            anomaly,
+            response_modes: ab_response_modes.then(ResponseModeStats::default),
+            stages: stage_sample.map(StageLatency::new),
+            positions: messages_per_stream.map(StreamPositionStats::new),
+        }
+    }
+
+    pub(crate) fn record_response(&self, outstanding: &Outstanding, response: &[u8]) {
+        if let Some(echo_check) = self.echo_check {
+            if !echo_check.verify(response) {
+                self.corrupted.fetch_add(1, Ordering::Relaxed);
+                if let Some(anomaly) = &self.anomaly {
+                    anomaly.error("corrupted response");
+                }
+                // Keep the request from also counting as lost, if the id
+                // survived.
+                if let Some(id) = decode_request_id(response) {
+                    outstanding.cancel(id);
+                }
+                return;
+            }
+        }
+        self.bytes_received
+            .fetch_add(response.len(), Ordering::Relaxed);
+        let id = decode_request_id(response);
+        match id.and_then(|id| outstanding.complete(id)) {
+            Some(latency) if self.timeout.is_some_and(|timeout| latency > timeout) => {
+                self.expired.fetch_add(1, Ordering::Relaxed);
+                if let (Some(modes), Some(id)) = (&self.response_modes, id) {
+                    modes.expired(id);
+                }
+                if let Some(id) = id {
+                    outstanding.take_position(id);
+                }
+                if let Some(anomaly) = &self.anomaly {
+                    anomaly.error("expired request");
+                }
+            }
+            Some(latency) => {
+                self.received.fetch_add(1, Ordering::Relaxed);
+                if let Some(anomaly) = &self.anomaly {
+                    anomaly.latency(latency);
+                }
+                self.latency.lock().unwrap().record_duration(latency);
+                outstanding.latency.lock().unwrap().record_duration(latency);
+                self.recent_latency.lock().unwrap().record_duration(latency);
+                self.interval_latency
+                    .lock()
+                    .unwrap()
+                    .record_duration(latency);
+                if let (Some(modes), Some(id)) = (&self.response_modes, id) {
+                    modes.received(id, latency);
+                }
+                if let (Some(positions), Some(position)) = (
+                    &self.positions,
+                    id.and_then(|id| outstanding.take_position(id)),
+                ) {
+                    positions.received(position, latency);
+                }
+                if let (Some(stages), Some(id)) = (&self.stages, id) {
+                    if let Some(written) = outstanding.take_written(id) {
+                        stages.responded(written.elapsed());
+                    }
+                }
+            }
+            None => {
+                self.late.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub(crate) fn take_recent_latency(&self) -> Histogram {
+        std::mem::take(&mut *self.recent_latency.lock().unwrap())
+    }
+
+    pub(crate) fn take_interval_latency(&self) -> Histogram {
+        std::mem::take(&mut *self.interval_latency.lock().unwrap())
+    }
+
+    /// Expire the requests of `outstanding` older than the response timeout.
+    pub(crate) fn expire(&self, outstanding: &Outstanding) {
+        if let Some(timeout) = self.timeout {
+            let expired = outstanding.expire(timeout, |id| {
+                if let Some(modes) = &self.response_modes {
+                    modes.expired(id);
+                }
+            });
+            self.expired.fetch_add(expired, Ordering::Relaxed);
+            if expired > 0 {
+                if let Some(anomaly) = &self.anomaly {
+                    anomaly.error("expired request");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::clock::ClockSource};
+
+    #[test]
+    fn request_id_round_trip() {
+        let mut packet = [0; 16];
+        encode_request_id(&mut packet, 0x0102_0304_0506_0708);
+        assert_eq!(decode_request_id(&packet), Some(0x0102_0304_0506_0708));
+        assert_eq!(decode_request_id(&packet[..REQUEST_ID_LEN - 1]), None);
+    }
+
+    fn frame(message: &[u8]) -> Vec<u8> {
+        let mut frame = encode_frame_header(message.len()).to_vec();
+        frame.extend_from_slice(message);
+        frame
+    }
+
+    #[test]
+    fn take_frame_waits_for_the_whole_frame() {
+        let frame = frame(b"hello");
+        let mut buffer = frame[..FRAME_HEADER_LEN - 1].to_vec();
+        assert_eq!(take_frame(&mut buffer), None);
+        buffer = frame[..frame.len() - 1].to_vec();
+        assert_eq!(take_frame(&mut buffer), None);
+        assert_eq!(buffer.len(), frame.len() - 1);
+        buffer.push(*frame.last().unwrap());
+        assert_eq!(take_frame(&mut buffer), Some(b"hello".to_vec()));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn take_frame_leaves_the_next_frame() {
+        let mut buffer = frame(b"first");
+        buffer.extend(frame(b""));
+        buffer.extend(&frame(b"third")[..3]);
+        assert_eq!(take_frame(&mut buffer), Some(b"first".to_vec()));
+        assert_eq!(take_frame(&mut buffer), Some(Vec::new()));
+        assert_eq!(take_frame(&mut buffer), None);
+        assert_eq!(buffer.len(), 3);
+    }
+
+    #[test]
+    fn stream_positions_are_per_connection() {
+        let clock = Clock::new(ClockSource::Instant).unwrap();
+        let gauge = Arc::new(InFlightGauge::default());
+        let connections: Vec<_> = (0..2)
+            .map(|_| Outstanding::new(gauge.clone(), None, clock))
+            .collect();
+        let responses = ResponseStats::new(None, None, None, false, None, Some(2));
+        // Both connections send id 0, at different positions.
+        for (position, outstanding) in connections.iter().enumerate() {
+            let id = outstanding.start();
+            assert_eq!(id, 0);
+            outstanding.positioned(id, position);
+        }
+        let mut response = [0; REQUEST_ID_LEN];
+        encode_request_id(&mut response, 0);
+        for outstanding in &connections {
+            responses.record_response(outstanding, &response);
+        }
+        let positions = responses.positions.as_ref().unwrap();
+        for latency in &positions.latency {
+            assert_eq!(latency.lock().unwrap().count(), 1);
+        }
+    }
+}
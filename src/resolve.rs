@@ -0,0 +1,110 @@
+//! Server address resolution, racing IPv6 and IPv4 connection attempts
+//! Happy Eyeballs style (RFC 8305) when a hostname resolves to both.
+
+use {
+    anyhow::{anyhow, bail, Context, Result},
+    quinn::Endpoint,
+    std::{
+        net::SocketAddr,
+        time::{Duration, Instant},
+    },
+    tokio::{net::lookup_host, task::JoinSet, time},
+    tracing::*,
+};
+
+/// Delay before starting the next attempt while earlier ones are pending,
+/// the recommended value from RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Resolve `server_address`, either an IP:port literal or a host:port name.
+pub(crate) async fn resolve(server_address: &str) -> Result<Vec<SocketAddr>> {
+    if let Ok(addr) = server_address.parse::<SocketAddr>() {
+        return Ok(vec![addr]);
+    }
+    let addrs: Vec<_> = lookup_host(server_address)
+        .await
+        .with_context(|| format!("resolving {server_address}"))?
+        .collect();
+    if addrs.is_empty() {
+        bail!("{server_address} did not resolve to any address");
+    }
+    info!("Resolved {server_address} to {addrs:?}");
+    Ok(addrs)
+}
+
+/// Order addresses alternating between families, starting with IPv6.
+fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.iter().copied().partition(|addr| addr.is_ipv6());
+    let mut ordered = Vec::with_capacity(addrs.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Race connection attempts to `addrs`, starting a new attempt every
+/// `CONNECTION_ATTEMPT_DELAY` or as soon as one fails. Returns the first
+/// address which completed a handshake with its connect latency. Addresses of
+/// a family without endpoint are skipped.
+pub(crate) async fn happy_eyeballs(
+    v4: Option<&Endpoint>,
+    v6: Option<&Endpoint>,
+    addrs: &[SocketAddr],
+    server_name: &str,
+) -> Result<(SocketAddr, Duration)> {
+    let candidates: Vec<_> = interleave(addrs)
+        .into_iter()
+        .filter_map(|addr| {
+            let endpoint = if addr.is_ipv6() { v6 } else { v4 };
+            endpoint.map(|endpoint| (addr, endpoint))
+        })
+        .collect();
+
+    let start = Instant::now();
+    let mut attempts = JoinSet::new();
+    let mut candidates = candidates.into_iter().peekable();
+    loop {
+        if let Some((addr, endpoint)) = candidates.next() {
+            debug!("Happy eyeballs attempt to {addr}");
+            let connecting = endpoint.connect(addr, server_name)?;
+            attempts.spawn(async move { (addr, connecting.await) });
+        }
+        if attempts.is_empty() {
+            return Err(anyhow!("no address of {addrs:?} is connectable"));
+        }
+        let more_candidates = candidates.peek().is_some();
+        tokio::select! {
+            Some(result) = attempts.join_next() => match result? {
+                (addr, Ok(connection)) => {
+                    connection.close(0u32.into(), b"happy eyeballs");
+                    attempts.abort_all();
+                    return Ok((addr, start.elapsed()));
+                }
+                (addr, Err(err)) => debug!("Happy eyeballs attempt to {addr} failed: {err}"),
+            },
+            _ = time::sleep(CONNECTION_ATTEMPT_DELAY), if more_candidates => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interleave_alternates_families_starting_with_ipv6() {
+        let addrs: Vec<SocketAddr> = ["10.0.0.1:1", "10.0.0.2:1", "[::1]:1", "10.0.0.3:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        assert_eq!(
+            interleave(&addrs),
+            vec![addrs[2], addrs[0], addrs[1], addrs[3]]
+        );
+        assert!(interleave(&[]).is_empty());
+    }
+}
@@ -0,0 +1,344 @@
+//! Per-run result folders for the client: `<results dir>/<UTC time>-<run id>`
+//! holding the resolved configuration, a snapshot of the environment and the
+//! run's outputs, so any run can be reproduced and compared later. The
+//! environment snapshot also heads the log of every client and server.
+//!
+//! A run ending in an error or a panic still records what it accumulated, as
+//! a summary marked `"incomplete": true`.
+
+use {
+    anyhow::{bail, Context, Result},
+    serde::Serialize,
+    serde_json::Value,
+    std::{
+        fs, panic,
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+        thread,
+        time::{SystemTime, UNIX_EPOCH},
+    },
+    tracing::*,
+};
+
+#[derive(Serialize)]
+struct NetworkInterface {
+    name: String,
+    mtu: Option<u32>,
+    /// Link speed in Mbit/s, as reported by the driver.
+    speed_mbps: Option<i64>,
+    address: Option<String>,
+}
+
+/// What a result depends on besides the configuration: the machine, its
+/// network setup and the versions of the software under test.
+#[derive(Serialize)]
+pub(crate) struct Environment {
+    hostname: Option<String>,
+    os: Option<String>,
+    kernel: Option<String>,
+    cpu_model: Option<String>,
+    cpus: usize,
+    /// Socket buffer limits, bounding what the endpoints can configure.
+    rmem_max: Option<u64>,
+    wmem_max: Option<u64>,
+    interfaces: Vec<NetworkInterface>,
+    tool_version: &'static str,
+    git_commit: &'static str,
+    quinn_version: &'static str,
+    rustls_version: &'static str,
+}
+
+/// Contents of a single line sysfs or procfs file.
+fn read_trimmed(path: impl AsRef<Path>) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|contents| contents.trim().to_string())
+}
+
+fn network_interfaces() -> Vec<NetworkInterface> {
+    let Ok(entries) = fs::read_dir("/sys/class/net") else {
+        return Vec::new();
+    };
+    let mut interfaces: Vec<_> = entries
+        .flatten()
+        .map(|entry| {
+            let path = entry.path();
+            NetworkInterface {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                mtu: read_trimmed(path.join("mtu")).and_then(|mtu| mtu.parse().ok()),
+                speed_mbps: read_trimmed(path.join("speed")).and_then(|speed| speed.parse().ok()),
+                address: read_trimmed(path.join("address")),
+            }
+        })
+        .collect();
+    interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+    interfaces
+}
+
+/// Value of `key` in `/etc/os-release`, unquoted.
+fn os_release(key: &str) -> Option<String> {
+    let contents = fs::read_to_string("/etc/os-release").ok()?;
+    contents.lines().find_map(|line| {
+        let value = line.strip_prefix(key)?.strip_prefix('=')?;
+        Some(value.trim_matches('"').to_string())
+    })
+}
+
+fn cpu_model() -> Option<String> {
+    let contents = fs::read_to_string("/proc/cpuinfo").ok()?;
+    contents.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        (key.trim() == "model name").then(|| value.trim().to_string())
+    })
+}
+
+impl Environment {
+    pub(crate) fn capture() -> Self {
+        Self {
+            hostname: read_trimmed("/proc/sys/kernel/hostname"),
+            os: os_release("PRETTY_NAME"),
+            kernel: read_trimmed("/proc/sys/kernel/osrelease"),
+            cpu_model: cpu_model(),
+            cpus: std::thread::available_parallelism().map_or(0, |cpus| cpus.get()),
+            rmem_max: read_trimmed("/proc/sys/net/core/rmem_max").and_then(|max| max.parse().ok()),
+            wmem_max: read_trimmed("/proc/sys/net/core/wmem_max").and_then(|max| max.parse().ok()),
+            interfaces: network_interfaces(),
+            tool_version: env!("CARGO_PKG_VERSION"),
+            git_commit: env!("GIT_COMMIT"),
+            quinn_version: env!("QUINN_VERSION"),
+            rustls_version: env!("RUSTLS_VERSION"),
+        }
+    }
+
+    /// Log the environment as the header of the report.
+    pub(crate) fn report(&self) {
+        let unknown = || "unknown".to_string();
+        info!(
+            "Tool {} ({}), quinn {}, rustls {}",
+            self.tool_version, self.git_commit, self.quinn_version, self.rustls_version
+        );
+        info!(
+            "Host {}: {}, kernel {}, {} x {}",
+            self.hostname.clone().unwrap_or_else(unknown),
+            self.os.clone().unwrap_or_else(unknown),
+            self.kernel.clone().unwrap_or_else(unknown),
+            self.cpus,
+            self.cpu_model.clone().unwrap_or_else(unknown),
+        );
+        info!(
+            "Socket buffer limits: rmem_max {}, wmem_max {}",
+            self.rmem_max.map_or_else(unknown, |max| max.to_string()),
+            self.wmem_max.map_or_else(unknown, |max| max.to_string()),
+        );
+    }
+}
+
+/// `secs` since the epoch as a compact UTC timestamp, `20240102T030405Z`.
+fn format_utc(secs: u64) -> String {
+    // Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    let time = secs % 86400;
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Snapshot of the results accumulated so far, a JSON object.
+type Snapshot = Arc<dyn Fn() -> Value + Send + Sync>;
+
+/// Runs still accumulating results, flushed by the panic hook.
+static IN_PROGRESS: Mutex<Vec<(u64, RunDirectory, Snapshot)>> = Mutex::new(Vec::new());
+static NEXT_PROGRESS_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Flush the results of the runs in progress as incomplete on a panic which
+/// aborts the run, after the default report of the panic. The runs are
+/// driven on the main thread, panics of spawned tasks on the runtime threads
+/// are caught by tokio while the run goes on.
+pub(crate) fn install_panic_hook() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        previous(info);
+        if thread::current().name() != Some("main") {
+            return;
+        }
+        // The panicking thread may hold the lock, flushing is best effort.
+        let Ok(runs) = IN_PROGRESS.try_lock() else {
+            return;
+        };
+        for (_, run_directory, snapshot) in runs.iter() {
+            run_directory.write_incomplete(snapshot(), &format!("panic: {info}"));
+        }
+    }));
+}
+
+/// Registration of a run accumulating results, see
+/// `RunDirectory::track_progress`.
+pub(crate) struct Progress {
+    id: u64,
+    run_directory: RunDirectory,
+    snapshot: Snapshot,
+    finished: bool,
+}
+
+impl Progress {
+    /// The run completed, its full results are recorded by the caller.
+    pub(crate) fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        let mut runs = IN_PROGRESS.lock().unwrap_or_else(|err| err.into_inner());
+        runs.retain(|(id, _, _)| *id != self.id);
+        drop(runs);
+        // On a panic the hook already recorded the results, with the panic
+        // message.
+        if !self.finished && !thread::panicking() {
+            self.run_directory
+                .write_incomplete((self.snapshot)(), "error");
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct RunDirectory {
+    path: PathBuf,
+}
+
+impl RunDirectory {
+    /// Create the folder of run `run_id` in `results_dir` and record
+    /// `config` and the environment in it.
+    pub(crate) fn create(
+        results_dir: &Path,
+        run_id: &str,
+        config: &impl Serialize,
+        environment: &Environment,
+    ) -> Result<Self> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let path = results_dir.join(format!("{}-{run_id}", format_utc(now)));
+        fs::create_dir_all(&path).with_context(|| format!("creating {}", path.display()))?;
+        let run_directory = Self { path };
+        run_directory.write_json("config.json", config)?;
+        run_directory.write_json("environment.json", environment)?;
+        info!("Recording run {run_id} in {}", run_directory.path.display());
+        Ok(run_directory)
+    }
+
+    pub(crate) fn write_json(&self, name: &str, value: &impl Serialize) -> Result<()> {
+        let path = self.path.join(name);
+        let json = serde_json::to_string_pretty(value)?;
+        fs::write(&path, json).with_context(|| format!("writing {}", path.display()))
+    }
+
+    /// Record `summary` as `summary.json`, and its scalar fields as a one
+    /// row `summary.csv` for spreadsheets.
+    pub(crate) fn write_summary(&self, summary: &impl Serialize) -> Result<()> {
+        self.write_json("summary.json", summary)?;
+        self.write_csv("summary.csv", std::slice::from_ref(summary))
+    }
+
+    /// Register a run accumulating results. Unless the returned `Progress` is
+    /// finished, `snapshot` is recorded as an incomplete summary when it is
+    /// dropped on an error path or unwinding, or on a panic elsewhere.
+    pub(crate) fn track_progress(
+        &self,
+        snapshot: impl Fn() -> Value + Send + Sync + 'static,
+    ) -> Progress {
+        let id = NEXT_PROGRESS_ID.fetch_add(1, Ordering::Relaxed);
+        let snapshot: Snapshot = Arc::new(snapshot);
+        IN_PROGRESS
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .push((id, self.clone(), snapshot.clone()));
+        Progress {
+            id,
+            run_directory: self.clone(),
+            snapshot,
+            finished: false,
+        }
+    }
+
+    fn write_incomplete(&self, mut partial: Value, reason: &str) {
+        if let Value::Object(fields) = &mut partial {
+            fields.insert("incomplete".to_string(), Value::Bool(true));
+            fields.insert(
+                "incomplete_reason".to_string(),
+                Value::String(reason.to_string()),
+            );
+        }
+        match self.write_summary(&partial) {
+            Ok(()) => warn!(
+                "Recorded incomplete results after {reason} in {}",
+                self.path.display()
+            ),
+            Err(err) => error!("Failed to record incomplete results: {err:#}"),
+        }
+    }
+
+    /// Write `records` as CSV rows under a header of their field names.
+    /// Fields holding arrays or objects are left out.
+    pub(crate) fn write_csv(&self, name: &str, records: &[impl Serialize]) -> Result<()> {
+        let mut csv = String::new();
+        for (index, record) in records.iter().enumerate() {
+            let Value::Object(fields) = serde_json::to_value(record)? else {
+                bail!("{name} rows are not records");
+            };
+            let scalars: Vec<_> = fields
+                .iter()
+                .filter(|(_, value)| !value.is_array() && !value.is_object())
+                .collect();
+            if index == 0 {
+                let header: Vec<_> = scalars.iter().map(|(key, _)| key.as_str()).collect();
+                csv.push_str(&header.join(","));
+                csv.push('\n');
+            }
+            let row: Vec<_> = scalars
+                .iter()
+                .map(|(_, value)| match value {
+                    Value::Null => String::new(),
+                    Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                })
+                .collect();
+            csv.push_str(&row.join(","));
+            csv.push('\n');
+        }
+        let path = self.path.join(name);
+        fs::write(&path, csv).with_context(|| format!("writing {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_utc_timestamps() {
+        assert_eq!(format_utc(0), "19700101T000000Z");
+        assert_eq!(format_utc(1_704_164_645), "20240102T030405Z");
+        assert_eq!(format_utc(951_868_799), "20000229T235959Z");
+    }
+}
@@ -0,0 +1,120 @@
+//! Tokio runtime metrics sampled alongside the periodic reports, to tell a
+//! run limited by the network from one limited by the scheduler.
+//!
+//! Worker utilization and the blocking pool are only exposed by tokio when
+//! built with `RUSTFLAGS="--cfg tokio_unstable"`.
+
+use {
+    std::fmt,
+    tokio::runtime::{Handle, RuntimeMetrics},
+};
+
+#[cfg(tokio_unstable)]
+use std::time::{Duration, Instant};
+
+/// Samples the metrics of the runtime it was created on.
+pub(crate) struct RuntimeSampler {
+    metrics: RuntimeMetrics,
+    #[cfg(tokio_unstable)]
+    last_busy: Vec<Duration>,
+    #[cfg(tokio_unstable)]
+    last_sample: Instant,
+}
+
+pub(crate) struct RuntimeSample {
+    workers: usize,
+    alive_tasks: usize,
+    global_queue_depth: usize,
+    /// Busy fraction of every worker since the previous sample.
+    #[cfg(tokio_unstable)]
+    worker_utilization: Vec<f64>,
+    #[cfg(tokio_unstable)]
+    local_queue_depth: usize,
+    #[cfg(tokio_unstable)]
+    blocking_threads: usize,
+    #[cfg(tokio_unstable)]
+    idle_blocking_threads: usize,
+    #[cfg(tokio_unstable)]
+    blocking_queue_depth: usize,
+}
+
+impl RuntimeSampler {
+    /// Must be called from within the runtime to sample.
+    pub(crate) fn current() -> Self {
+        let metrics = Handle::current().metrics();
+        Self {
+            #[cfg(tokio_unstable)]
+            last_busy: (0..metrics.num_workers())
+                .map(|worker| metrics.worker_total_busy_duration(worker))
+                .collect(),
+            #[cfg(tokio_unstable)]
+            last_sample: Instant::now(),
+            metrics,
+        }
+    }
+
+    pub(crate) fn sample(&mut self) -> RuntimeSample {
+        let metrics = &self.metrics;
+        #[cfg(tokio_unstable)]
+        let worker_utilization = {
+            let elapsed = self.last_sample.elapsed().as_secs_f64();
+            self.last_sample = Instant::now();
+            self.last_busy
+                .iter_mut()
+                .enumerate()
+                .map(|(worker, last_busy)| {
+                    let busy = metrics.worker_total_busy_duration(worker);
+                    let delta = busy.saturating_sub(*last_busy);
+                    *last_busy = busy;
+                    (delta.as_secs_f64() / elapsed).min(1.0)
+                })
+                .collect()
+        };
+        RuntimeSample {
+            workers: metrics.num_workers(),
+            alive_tasks: metrics.num_alive_tasks(),
+            global_queue_depth: metrics.global_queue_depth(),
+            #[cfg(tokio_unstable)]
+            worker_utilization,
+            #[cfg(tokio_unstable)]
+            local_queue_depth: (0..metrics.num_workers())
+                .map(|worker| metrics.worker_local_queue_depth(worker))
+                .sum(),
+            #[cfg(tokio_unstable)]
+            blocking_threads: metrics.num_blocking_threads(),
+            #[cfg(tokio_unstable)]
+            idle_blocking_threads: metrics.num_idle_blocking_threads(),
+            #[cfg(tokio_unstable)]
+            blocking_queue_depth: metrics.blocking_queue_depth(),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeSample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} workers, {} alive tasks, global queue {}",
+            self.workers, self.alive_tasks, self.global_queue_depth
+        )?;
+        #[cfg(tokio_unstable)]
+        {
+            let utilization: Vec<_> = self
+                .worker_utilization
+                .iter()
+                .map(|busy| format!("{:.0}%", busy * 100.0))
+                .collect();
+            write!(
+                f,
+                ", local queues {}, worker busy [{}], blocking threads {} ({} idle), \
+                 blocking queue {}",
+                self.local_queue_depth,
+                utilization.join(" "),
+                self.blocking_threads,
+                self.idle_blocking_threads,
+                self.blocking_queue_depth,
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+//! The `selftest` subcommand: a short loopback exchange, client and server in
+//! this process, in every traffic mode and every response mode of the
+//! streams workload, checking the invariants any working build and
+//! environment keeps: every request sent and answered, nothing lost, expired
+//! or corrupted, the server counting what the client sent. A quick check
+//! before launching long benchmarks, and for CI.
+
+use {
+    crate::{
+        identity::IdentityClass, results::Environment, run_client_with_id, Asymmetry, Mode, Opt,
+        Server,
+    },
+    anyhow::{bail, Result},
+    std::{
+        net::{Ipv4Addr, SocketAddr},
+        time::{Duration, Instant},
+    },
+    tokio::time,
+    tracing::*,
+};
+
+/// Requests per sender of the streams cases.
+const PACKETS: usize = 500;
+const SENDERS: usize = 2;
+
+struct Case {
+    name: &'static str,
+    configure: fn(&mut Opt),
+}
+
+const CASES: &[Case] = &[
+    Case {
+        name: "streams, datagram responses",
+        configure: |_| {},
+    },
+    Case {
+        name: "streams, stream responses",
+        configure: |opt| opt.asymmetry = Some(Asymmetry::Query),
+    },
+    Case {
+        name: "streams, large requests",
+        configure: |opt| opt.asymmetry = Some(Asymmetry::Upload),
+    },
+    Case {
+        name: "streams, alternating response modes",
+        configure: |opt| opt.ab_response_modes = true,
+    },
+    Case {
+        name: "streams, echo",
+        configure: |opt| opt.echo = true,
+    },
+    Case {
+        name: "streams, echo prefix",
+        configure: |opt| opt.echo_prefix = Some(16),
+    },
+    Case {
+        name: "streams, framed messages",
+        configure: |opt| opt.messages_per_stream = Some(4),
+    },
+    Case {
+        name: "stream-open",
+        configure: |opt| opt.mode = Mode::StreamOpen,
+    },
+    Case {
+        name: "datagram-flood",
+        configure: |opt| opt.mode = Mode::DatagramFlood,
+    },
+    Case {
+        name: "idle",
+        configure: |opt| opt.mode = Mode::Idle,
+    },
+    Case {
+        name: "connect-storm",
+        configure: |opt| opt.mode = Mode::ConnectStorm,
+    },
+];
+
+/// Run every case against one loopback server, each within `timeout`.
+/// Returns whether all of them passed.
+pub(crate) async fn run(opt: &Opt, environment: &Environment, timeout: Duration) -> bool {
+    // The cases choose the workload, flags changing it or the server's
+    // behaviour are reset.
+    let clean = Opt {
+        listener: false,
+        no_response_expected: false,
+        start_at: None,
+        checkpoint: None,
+        resume: None,
+        num_threads: SENDERS,
+        num_packets: PACKETS,
+        mode: Mode::Streams,
+        echo: false,
+        echo_prefix: None,
+        asymmetry: None,
+        ab_response_modes: false,
+        messages_per_stream: None,
+        duration: 1,
+        idle_duration: 1,
+        rtt_sample_interval: 1,
+        concurrency: 4,
+        ..opt.clone()
+    };
+    let mut server = Server::create_server(&clean, SocketAddr::from((Ipv4Addr::LOCALHOST, 0)));
+    server.wait_ready().await;
+    let base = Opt {
+        server_address: server.local_address.to_string(),
+        ..clean
+    };
+
+    let mut failures = Vec::new();
+    for (index, case) in CASES.iter().enumerate() {
+        let mut opt = base.clone();
+        (case.configure)(&mut opt);
+        let start = Instant::now();
+        match run_case(&opt, environment, &server, index, timeout).await {
+            Ok(()) => info!("Self-test {}: passed in {:?}", case.name, start.elapsed()),
+            Err(err) => {
+                error!("Self-test {}: FAILED: {err:#}", case.name);
+                failures.push(case.name);
+            }
+        }
+    }
+
+    server.stats.drain.start("the end of the self-test");
+    server.join().await;
+    if failures.is_empty() {
+        info!("Self-test passed, {} cases", CASES.len());
+    } else {
+        error!(
+            "Self-test failed, {} of {} cases: {}",
+            failures.len(),
+            CASES.len(),
+            failures.join(", ")
+        );
+    }
+    failures.is_empty()
+}
+
+async fn run_case(
+    opt: &Opt,
+    environment: &Environment,
+    server: &Server,
+    index: usize,
+    timeout: Duration,
+) -> Result<()> {
+    let (server_received, _) = server.stats.connections.stream_totals();
+    let run_id = format!("selftest-{index}");
+    let run = run_client_with_id(opt, environment, None, run_id, IdentityClass::Shared);
+    let Ok(summary) = time::timeout(timeout, run).await else {
+        bail!("not done within {timeout:?}");
+    };
+    let Some(summary) = summary? else {
+        // Other workloads than streams have no invariants beyond success.
+        return Ok(());
+    };
+
+    let expected = opt.num_threads * opt.num_packets;
+    if summary.sent != expected {
+        bail!("sent {} of {expected} requests", summary.sent);
+    }
+    if summary.received != summary.sent {
+        bail!(
+            "{} responses to {} requests",
+            summary.received,
+            summary.sent
+        );
+    }
+    if summary.lost + summary.expired + summary.late + summary.corrupted > 0 {
+        bail!(
+            "{} lost, {} expired, {} late, {} corrupted",
+            summary.lost,
+            summary.expired,
+            summary.late,
+            summary.corrupted
+        );
+    }
+    let (received, _) = server.stats.connections.stream_totals();
+    if received - server_received != summary.sent {
+        bail!(
+            "server received {} of {} requests",
+            received - server_received,
+            summary.sent
+        );
+    }
+    Ok(())
+}
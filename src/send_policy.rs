@@ -0,0 +1,176 @@
+//! Assignment of the requests of the streams workload to connections with
+//! `--send-policy`. By default every sender keeps to its own connection; the
+//! other policies pick a connection for every request, to see whether
+//! steering requests away from a congested connection shortens the latency
+//! tail. The requests and latency of every connection are reported under the
+//! policy.
+
+use {
+    crate::requests::Outstanding,
+    anyhow::{bail, Error, Result},
+    serde::Serialize,
+    std::{
+        fmt,
+        str::FromStr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    },
+    tracing::*,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SendPolicy {
+    /// Every sender sends on its own connection.
+    Pinned,
+    /// The connections take turns.
+    RoundRobin,
+    /// The connection with the fewest requests awaiting a response.
+    LeastOutstanding,
+    /// A connection picked at random.
+    Random,
+}
+
+impl fmt::Display for SendPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SendPolicy::Pinned => "pinned",
+            SendPolicy::RoundRobin => "round-robin",
+            SendPolicy::LeastOutstanding => "least-outstanding",
+            SendPolicy::Random => "random",
+        })
+    }
+}
+
+impl FromStr for SendPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pinned" => Ok(SendPolicy::Pinned),
+            "round-robin" => Ok(SendPolicy::RoundRobin),
+            "least-outstanding" => Ok(SendPolicy::LeastOutstanding),
+            "random" => Ok(SendPolicy::Random),
+            _ => bail!(
+                "unknown send policy {s:?}, expected \"pinned\", \"round-robin\", \
+                 \"least-outstanding\" or \"random\""
+            ),
+        }
+    }
+}
+
+/// Picks the connection of every request according to the policy and counts
+/// the requests per connection.
+pub(crate) struct ConnectionSelector {
+    policy: SendPolicy,
+    next: AtomicUsize,
+    assigned: Vec<AtomicUsize>,
+}
+
+impl ConnectionSelector {
+    pub(crate) fn new(policy: SendPolicy, connections: usize) -> Self {
+        Self {
+            policy,
+            next: AtomicUsize::new(0),
+            assigned: (0..connections).map(|_| AtomicUsize::new(0)).collect(),
+        }
+    }
+
+    /// The index of the connection the next request of `sender` goes to,
+    /// given the requests `outstanding` per connection.
+    pub(crate) fn select(&self, sender: usize, outstanding: &[Arc<Outstanding>]) -> usize {
+        let connection = match self.policy {
+            SendPolicy::Pinned => sender,
+            SendPolicy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % outstanding.len(),
+            SendPolicy::LeastOutstanding => (0..outstanding.len())
+                .min_by_key(|index| outstanding[*index].len())
+                .unwrap_or(sender),
+            SendPolicy::Random => rand::random_range(0..outstanding.len()),
+        };
+        self.assigned[connection].fetch_add(1, Ordering::Relaxed);
+        connection
+    }
+
+    /// Log how the policy spread the requests and how the latency of the
+    /// connections compares.
+    pub(crate) fn report(&self, outstanding: &[Arc<Outstanding>]) {
+        let assigned: Vec<_> = self
+            .assigned
+            .iter()
+            .map(|assigned| assigned.load(Ordering::Relaxed))
+            .collect();
+        let mean = assigned.iter().sum::<usize>() as f64 / assigned.len().max(1) as f64;
+        let busiest = assigned.iter().copied().max().unwrap_or_default();
+        info!(
+            "Send policy {}: requests per connection {assigned:?}, busiest {:.2}x the mean",
+            self.policy,
+            busiest as f64 / mean.max(1.0),
+        );
+        let mut p99s = Vec::with_capacity(outstanding.len());
+        for (index, outstanding) in outstanding.iter().enumerate() {
+            let latency = outstanding.latency.lock().unwrap();
+            debug!(
+                "Send policy {}: connection {index} latency {latency}",
+                self.policy
+            );
+            if latency.count() > 0 {
+                p99s.push(latency.percentile(99.0));
+            }
+        }
+        if let (Some(best), Some(worst)) = (p99s.iter().min(), p99s.iter().max()) {
+            info!(
+                "Send policy {}: p99 latency per connection from {best}us to {worst}us",
+                self.policy
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            clock::{Clock, ClockSource},
+            requests::InFlightGauge,
+        },
+    };
+
+    #[test]
+    fn parse_send_policy() {
+        for policy in [
+            SendPolicy::Pinned,
+            SendPolicy::RoundRobin,
+            SendPolicy::LeastOutstanding,
+            SendPolicy::Random,
+        ] {
+            assert_eq!(policy.to_string().parse::<SendPolicy>().unwrap(), policy);
+        }
+        assert!("round_robin".parse::<SendPolicy>().is_err());
+    }
+
+    #[test]
+    fn select_connection() {
+        let clock = Clock::new(ClockSource::Instant).unwrap();
+        let gauge = Arc::new(InFlightGauge::default());
+        let outstanding: Vec<_> = (0..3)
+            .map(|_| Arc::new(Outstanding::new(gauge.clone(), None, clock)))
+            .collect();
+
+        let pinned = ConnectionSelector::new(SendPolicy::Pinned, 3);
+        assert_eq!(pinned.select(2, &outstanding), 2);
+
+        let round_robin = ConnectionSelector::new(SendPolicy::RoundRobin, 3);
+        let picks: Vec<_> = (0..4)
+            .map(|_| round_robin.select(0, &outstanding))
+            .collect();
+        assert_eq!(picks, [0, 1, 2, 0]);
+
+        outstanding[0].start();
+        outstanding[2].start();
+        let least = ConnectionSelector::new(SendPolicy::LeastOutstanding, 3);
+        assert_eq!(least.select(0, &outstanding), 1);
+    }
+}
@@ -0,0 +1,152 @@
+//! Run segmentation for the long-running `--service` server.
+//!
+//! Connections are grouped by the run id the client announces on the control
+//! stream. Once the last connection of a run closes, one summary record for
+//! that run is written to the results directory.
+
+use {
+    anyhow::{Context, Result},
+    serde::Serialize,
+    std::{
+        collections::HashMap,
+        fs,
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+        time::{Instant, SystemTime, UNIX_EPOCH},
+    },
+    tracing::*,
+};
+
+/// Counters kept for a single server-side connection.
+#[derive(Default)]
+pub(crate) struct ConnectionCounters {
+    pub(crate) streams_received: AtomicUsize,
+    pub(crate) bytes_received: AtomicUsize,
+    pub(crate) responses_sent: AtomicUsize,
+    /// Bytes of the responses sent.
+    pub(crate) bytes_sent: AtomicUsize,
+    /// Streams finished without any data.
+    pub(crate) zero_byte_streams: AtomicUsize,
+    /// Streams with data, but too short to carry a request id.
+    pub(crate) short_streams: AtomicUsize,
+    pub(crate) datagrams_received: AtomicUsize,
+    /// Streams stopped after stalling past the stream read timeout.
+    pub(crate) streams_timed_out: AtomicUsize,
+    /// Responses sent on a stream because the client takes no datagrams.
+    pub(crate) datagram_fallbacks: AtomicUsize,
+}
+
+#[derive(Serialize)]
+pub(crate) struct RunSummary {
+    pub(crate) run_id: String,
+    pub(crate) connections: usize,
+    pub(crate) streams_received: usize,
+    pub(crate) bytes_received: usize,
+    pub(crate) responses_sent: usize,
+    pub(crate) streams_timed_out: usize,
+    /// Unix timestamp of the first connection of the run, in seconds.
+    pub(crate) start_unix_secs: u64,
+    pub(crate) duration_secs: f64,
+    /// Whether the client announced the end of the run, rather than the run
+    /// ending with its connections.
+    pub(crate) ended_by_client: bool,
+    /// Whether server endpoints failed while the run was going.
+    pub(crate) degraded: bool,
+}
+
+struct RunState {
+    active_connections: usize,
+    summary: RunSummary,
+    started: Instant,
+}
+
+#[derive(Default)]
+pub(crate) struct RunTracker {
+    runs: Mutex<HashMap<String, RunState>>,
+}
+
+impl RunTracker {
+    /// Account a newly identified connection to `run_id`.
+    pub(crate) fn connection_opened(&self, run_id: &str) {
+        let mut runs = self.runs.lock().unwrap();
+        let run = runs.entry(run_id.to_string()).or_insert_with(|| RunState {
+            active_connections: 0,
+            summary: RunSummary {
+                run_id: run_id.to_string(),
+                connections: 0,
+                streams_received: 0,
+                bytes_received: 0,
+                responses_sent: 0,
+                streams_timed_out: 0,
+                start_unix_secs: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default(),
+                duration_secs: 0.0,
+                ended_by_client: false,
+                degraded: false,
+            },
+            started: Instant::now(),
+        });
+        run.active_connections += 1;
+        run.summary.connections += 1;
+    }
+
+    /// Fold the counters of a connection whose client announced the end of
+    /// the run, like `connection_closed`.
+    pub(crate) fn connection_ended(
+        &self,
+        run_id: &str,
+        counters: &ConnectionCounters,
+    ) -> Option<RunSummary> {
+        if let Some(run) = self.runs.lock().unwrap().get_mut(run_id) {
+            run.summary.ended_by_client = true;
+        }
+        self.connection_closed(run_id, counters)
+    }
+
+    /// Fold the counters of a closed connection into its run. Returns the
+    /// summary once the last connection of the run has closed.
+    pub(crate) fn connection_closed(
+        &self,
+        run_id: &str,
+        counters: &ConnectionCounters,
+    ) -> Option<RunSummary> {
+        let mut runs = self.runs.lock().unwrap();
+        let run = runs.get_mut(run_id)?;
+        run.summary.streams_received += counters.streams_received.load(Ordering::Relaxed);
+        run.summary.bytes_received += counters.bytes_received.load(Ordering::Relaxed);
+        run.summary.responses_sent += counters.responses_sent.load(Ordering::Relaxed);
+        run.summary.streams_timed_out += counters.streams_timed_out.load(Ordering::Relaxed);
+        run.active_connections -= 1;
+        if run.active_connections > 0 {
+            return None;
+        }
+        let mut run = runs.remove(run_id)?;
+        run.summary.duration_secs = run.started.elapsed().as_secs_f64();
+        Some(run.summary)
+    }
+}
+
+/// Write `summary` as `<run_id>.json` into `dir`, never overwriting the record
+/// of an earlier run which reused the same id.
+pub(crate) fn write_run_summary(dir: &Path, summary: &RunSummary) -> Result<PathBuf> {
+    fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    let mut path = dir.join(format!("{}.json", summary.run_id));
+    let mut index = 1;
+    while path.exists() {
+        path = dir.join(format!("{}-{index}.json", summary.run_id));
+        index += 1;
+    }
+    let json = serde_json::to_string_pretty(summary)?;
+    fs::write(&path, json).with_context(|| format!("writing {}", path.display()))?;
+    info!(
+        "Wrote summary of run {} to {}",
+        summary.run_id,
+        path.display()
+    );
+    Ok(path)
+}
@@ -0,0 +1,60 @@
+//! Latency attribution by stage with `--stage-sample`: for every nth request
+//! the round trip is split into the wait for `open_uni`, the write of the
+//! request and the gap from the finished write, when the stream's FIN is
+//! queued, to the response. Aggregate latency says something is slow, the
+//! stages say where.
+
+use {
+    crate::histogram::Histogram,
+    std::{sync::Mutex, time::Duration},
+    tracing::*,
+};
+
+pub(crate) struct StageLatency {
+    every: u64,
+    open_wait: Mutex<Histogram>,
+    write: Mutex<Histogram>,
+    fin_to_response: Mutex<Histogram>,
+}
+
+impl StageLatency {
+    pub(crate) fn new(every: u64) -> Self {
+        Self {
+            every: every.max(1),
+            open_wait: Mutex::default(),
+            write: Mutex::default(),
+            fin_to_response: Mutex::default(),
+        }
+    }
+
+    /// Whether request `id` is attributed.
+    pub(crate) fn sampled(&self, id: u64) -> bool {
+        id.is_multiple_of(self.every)
+    }
+
+    /// Record the sending stages of a sampled request.
+    pub(crate) fn sent(&self, open_wait: Duration, write: Duration) {
+        self.open_wait.lock().unwrap().record_duration(open_wait);
+        self.write.lock().unwrap().record_duration(write);
+    }
+
+    pub(crate) fn responded(&self, fin_to_response: Duration) {
+        self.fin_to_response
+            .lock()
+            .unwrap()
+            .record_duration(fin_to_response);
+    }
+
+    pub(crate) fn report(&self) {
+        info!(
+            "Latency by stage of every {}th request: open_uni wait {}",
+            self.every,
+            self.open_wait.lock().unwrap()
+        );
+        info!("Latency by stage: write {}", self.write.lock().unwrap());
+        info!(
+            "Latency by stage: FIN to response {}",
+            self.fin_to_response.lock().unwrap()
+        );
+    }
+}
@@ -0,0 +1,254 @@
+//! Warm standby connections with `--standby-connections`: every connection of
+//! the streams workload keeps spare connections to the same target, connected
+//! and joined to the run up front. Once the active connection degrades, its
+//! packet loss over a check interval above `--failover-loss` or its RTT above
+//! `--failover-rtt`, its requests move to a spare and a new spare is connected
+//! in the background, like the connection cache of a transaction forwarder.
+//! The failovers and the time from detecting the degradation to the first
+//! request sent on the spare are reported.
+
+use {
+    crate::{
+        configure_control,
+        control::{ControlMessage, ControlStream},
+        histogram::Histogram,
+        join_all, join_run, Opt,
+    },
+    anyhow::Result,
+    quinn::{Connection, Endpoint},
+    std::{
+        collections::VecDeque,
+        mem,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, Instant},
+    },
+    tokio::time,
+    tracing::*,
+};
+
+/// How often the active connections are checked for degradation.
+const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+/// Packets an active connection must have sent in a check interval for its
+/// loss to count.
+const MIN_PACKETS: u64 = 50;
+
+/// An active connection and the spares of its target.
+struct Slot {
+    endpoint: Endpoint,
+    addr: SocketAddr,
+    active: Mutex<Connection>,
+    spares: Mutex<VecDeque<Connection>>,
+    /// When the slot switched to a spare no request was sent on yet.
+    switched_at: Mutex<Option<Instant>>,
+}
+
+pub(crate) struct StandbyPool {
+    opt: Opt,
+    run_id: String,
+    slots: Vec<Slot>,
+    /// Every connection the pool connected, with its control stream to
+    /// announce the end of the run on.
+    established: Mutex<Vec<(Connection, ControlStream)>>,
+    failovers: AtomicUsize,
+    /// Degradations without a spare to switch to.
+    no_spare: AtomicUsize,
+    replenish_failures: AtomicUsize,
+    switch_latency: Mutex<Histogram>,
+}
+
+impl StandbyPool {
+    /// Connect `--standby-connections` spares for each of the active
+    /// `connections`, made by the endpoint at the same index.
+    pub(crate) async fn establish(
+        opt: &Opt,
+        run_id: &str,
+        endpoints: &[Endpoint],
+        connections: &[Connection],
+    ) -> Result<Arc<Self>> {
+        let start = Instant::now();
+        let pool = Self {
+            opt: opt.clone(),
+            run_id: run_id.to_string(),
+            slots: endpoints
+                .iter()
+                .zip(connections)
+                .map(|(endpoint, connection)| Slot {
+                    endpoint: endpoint.clone(),
+                    addr: connection.remote_address(),
+                    active: Mutex::new(connection.clone()),
+                    spares: Mutex::default(),
+                    switched_at: Mutex::default(),
+                })
+                .collect(),
+            established: Mutex::default(),
+            failovers: AtomicUsize::new(0),
+            no_spare: AtomicUsize::new(0),
+            replenish_failures: AtomicUsize::new(0),
+            switch_latency: Mutex::default(),
+        };
+        for slot in &pool.slots {
+            let spares = (0..opt.standby_connections)
+                .map(|_| pool.connect_spare(slot))
+                .collect();
+            for spare in join_all(spares).await {
+                slot.spares.lock().unwrap().push_back(spare?);
+            }
+        }
+        info!(
+            "Established {} standby connections for {} targets in {:?}",
+            opt.standby_connections * pool.slots.len(),
+            pool.slots.len(),
+            start.elapsed()
+        );
+        Ok(Arc::new(pool))
+    }
+
+    async fn connect_spare(&self, slot: &Slot) -> Result<Connection> {
+        let connection = slot
+            .endpoint
+            .connect(slot.addr, &self.opt.server_name)?
+            .await?;
+        let (mut control, _) = join_run(&connection, &self.run_id).await?;
+        configure_control(&self.opt, &mut control).await?;
+        self.established
+            .lock()
+            .unwrap()
+            .push((connection.clone(), control));
+        Ok(connection)
+    }
+
+    /// The connection requests of slot `index` are currently sent on.
+    pub(crate) fn connection(&self, index: usize) -> Connection {
+        self.slots[index].active.lock().unwrap().clone()
+    }
+
+    /// Note a request sent on slot `index`, completing a pending switch.
+    pub(crate) fn request_sent(&self, index: usize) {
+        if let Some(switched_at) = self.slots[index].switched_at.lock().unwrap().take() {
+            self.switch_latency
+                .lock()
+                .unwrap()
+                .record_duration(switched_at.elapsed());
+        }
+    }
+
+    /// Check the active connections every interval and switch degraded ones
+    /// to a spare, calling `on_switch` with the slot and its new connection.
+    /// Never returns, the caller aborts it once the workload is over.
+    pub(crate) async fn monitor(self: Arc<Self>, on_switch: impl Fn(usize, Connection)) {
+        let mut last_path: Vec<_> = (0..self.slots.len())
+            .map(|index| path_counters(&self.connection(index)))
+            .collect();
+        let mut interval = time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            for (index, slot) in self.slots.iter().enumerate() {
+                let active = self.connection(index);
+                let Some(reason) = self.degradation(&active, &mut last_path[index]) else {
+                    continue;
+                };
+                let detected = Instant::now();
+                let spare = {
+                    let mut spares = slot.spares.lock().unwrap();
+                    spares.retain(|spare| spare.close_reason().is_none());
+                    spares.pop_front()
+                };
+                let Some(spare) = spare else {
+                    self.no_spare.fetch_add(1, Ordering::Relaxed);
+                    debug!(
+                        "Connection {} degraded ({reason}), no spare",
+                        active.stable_id()
+                    );
+                    continue;
+                };
+                warn!(
+                    "Connection {} to {} degraded ({reason}), failing over to {}",
+                    active.stable_id(),
+                    slot.addr,
+                    spare.stable_id()
+                );
+                last_path[index] = path_counters(&spare);
+                // The degraded connection stays open for its responses in
+                // flight.
+                *slot.active.lock().unwrap() = spare.clone();
+                *slot.switched_at.lock().unwrap() = Some(detected);
+                self.failovers.fetch_add(1, Ordering::Relaxed);
+                on_switch(index, spare);
+                let pool = self.clone();
+                tokio::spawn(async move {
+                    let slot = &pool.slots[index];
+                    match pool.connect_spare(slot).await {
+                        Ok(spare) => slot.spares.lock().unwrap().push_back(spare),
+                        Err(err) => {
+                            pool.replenish_failures.fetch_add(1, Ordering::Relaxed);
+                            warn!("Failed to replace the standby connection: {err:#}");
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    /// Why `connection` counts as degraded since the counters in `last`, if
+    /// it does. Updates `last`.
+    fn degradation(&self, connection: &Connection, last: &mut (u64, u64)) -> Option<String> {
+        if let Some(reason) = connection.close_reason() {
+            return Some(format!("closed: {reason}"));
+        }
+        let (sent, lost) = path_counters(connection);
+        let (sent_since, lost_since) = (sent - last.0, lost - last.1);
+        *last = (sent, lost);
+        if sent_since >= MIN_PACKETS {
+            let loss = lost_since as f64 * 100.0 / sent_since as f64;
+            if loss > self.opt.failover_loss {
+                return Some(format!("{loss:.1}% loss"));
+            }
+        }
+        let rtt = connection.rtt();
+        if rtt > Duration::from_millis(self.opt.failover_rtt) {
+            return Some(format!("RTT {rtt:?}"));
+        }
+        None
+    }
+
+    /// Announce the end of the run on every connection of the pool and close
+    /// them.
+    pub(crate) async fn finish(&self) {
+        let established = mem::take(&mut *self.established.lock().unwrap());
+        let run_ends = established
+            .into_iter()
+            .map(|(connection, mut control)| async move {
+                let result = async {
+                    control.send(&ControlMessage::RunEnd).await?;
+                    control.ping().await
+                };
+                if let Err(err) = result.await {
+                    debug!("Failed to announce the end of the run: {err:#}");
+                }
+                connection.close(0u32.into(), b"done");
+            })
+            .collect();
+        join_all(run_ends).await;
+    }
+
+    pub(crate) fn report(&self) {
+        info!(
+            "Standby connections: {} failovers, {} degradations without a spare, {} spares \
+             failed to connect, switch latency {}",
+            self.failovers.load(Ordering::Relaxed),
+            self.no_spare.load(Ordering::Relaxed),
+            self.replenish_failures.load(Ordering::Relaxed),
+            self.switch_latency.lock().unwrap(),
+        );
+    }
+}
+
+/// Packets sent and lost on the path of `connection`.
+fn path_counters(connection: &Connection) -> (u64, u64) {
+    let path = connection.stats().path;
+    (path.sent_packets, path.lost_packets)
+}
@@ -0,0 +1,264 @@
+//! HTTP JSON API of a standing server with `--control-addr`: `GET /stats`
+//! returns the live per-connection counters and transport statistics along
+//! with the server totals, `POST /reset` restarts the totals so the next run
+//! starts from zero, `POST /drain` shuts the server down gracefully and
+//! `POST /sample-rate/<n>` changes the per-packet log sampling.
+
+use {
+    crate::{identity, packet_log, pinning, service::ConnectionCounters, ServerStats},
+    anyhow::Result,
+    quinn::Connection,
+    serde::Serialize,
+    std::{
+        collections::HashMap,
+        net::SocketAddr,
+        sync::{atomic::Ordering, Arc, Mutex},
+        time::Instant,
+    },
+    tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    },
+    tracing::*,
+};
+
+/// Counts over many connections.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+struct Totals {
+    connections: usize,
+    streams_received: usize,
+    bytes_received: usize,
+    responses_sent: usize,
+    streams_timed_out: usize,
+}
+
+impl Totals {
+    fn add(&mut self, counters: &ConnectionCounters) {
+        self.connections += 1;
+        self.streams_received += counters.streams_received.load(Ordering::Relaxed);
+        self.bytes_received += counters.bytes_received.load(Ordering::Relaxed);
+        self.responses_sent += counters.responses_sent.load(Ordering::Relaxed);
+        self.streams_timed_out += counters.streams_timed_out.load(Ordering::Relaxed);
+    }
+
+    fn since(&self, baseline: &Totals) -> Totals {
+        Totals {
+            connections: self.connections - baseline.connections,
+            streams_received: self.streams_received - baseline.streams_received,
+            bytes_received: self.bytes_received - baseline.bytes_received,
+            responses_sent: self.responses_sent - baseline.responses_sent,
+            streams_timed_out: self.streams_timed_out - baseline.streams_timed_out,
+        }
+    }
+}
+
+struct LiveConnection {
+    connection: Connection,
+    counters: Arc<ConnectionCounters>,
+    run_id: Option<String>,
+    /// Fingerprint of the client certificate, with --request-client-certs.
+    client_identity: Option<String>,
+    /// Public key of the client certificate, pinned with --expected-pubkeys.
+    client_pubkey: Option<String>,
+    connected_at: Instant,
+}
+
+#[derive(Serialize)]
+struct ConnectionReport {
+    id: usize,
+    remote: SocketAddr,
+    run_id: Option<String>,
+    client_identity: Option<String>,
+    client_pubkey: Option<String>,
+    age_secs: f64,
+    streams_received: usize,
+    bytes_received: usize,
+    responses_sent: usize,
+    streams_timed_out: usize,
+    datagram_fallbacks: usize,
+    rtt_us: u128,
+    cwnd: u64,
+    lost_packets: u64,
+    udp_tx_bytes: u64,
+    udp_rx_bytes: u64,
+}
+
+#[derive(Serialize)]
+struct StatsReport {
+    uptime_secs: f64,
+    since_reset_secs: f64,
+    /// Counts since the last reset, of closed and open connections.
+    totals: Totals,
+    connections: Vec<ConnectionReport>,
+}
+
+/// The open connections of the server and the totals of the closed ones.
+pub(crate) struct ConnectionRegistry {
+    live: Mutex<HashMap<usize, LiveConnection>>,
+    closed: Mutex<Totals>,
+    /// Time of the last reset and the totals at that time.
+    reset: Mutex<(Instant, Totals)>,
+}
+
+impl Default for ConnectionRegistry {
+    fn default() -> Self {
+        Self {
+            live: Mutex::default(),
+            closed: Mutex::default(),
+            reset: Mutex::new((Instant::now(), Totals::default())),
+        }
+    }
+}
+
+impl ConnectionRegistry {
+    pub(crate) fn opened(&self, connection: &Connection, counters: Arc<ConnectionCounters>) {
+        self.live.lock().unwrap().insert(
+            connection.stable_id(),
+            LiveConnection {
+                connection: connection.clone(),
+                counters,
+                run_id: None,
+                client_identity: identity::client_fingerprint(connection),
+                client_pubkey: pinning::client_pubkey(connection),
+                connected_at: Instant::now(),
+            },
+        );
+    }
+
+    pub(crate) fn joined_run(&self, connection: &Connection, run_id: &str) {
+        if let Some(live) = self.live.lock().unwrap().get_mut(&connection.stable_id()) {
+            live.run_id = Some(run_id.to_string());
+        }
+    }
+
+    pub(crate) fn closed(&self, connection: &Connection) {
+        if let Some(live) = self.live.lock().unwrap().remove(&connection.stable_id()) {
+            self.closed.lock().unwrap().add(&live.counters);
+        }
+    }
+
+    /// Totals since startup, of closed and open connections.
+    fn totals(&self, live: &HashMap<usize, LiveConnection>) -> Totals {
+        let mut totals = *self.closed.lock().unwrap();
+        for connection in live.values() {
+            totals.add(&connection.counters);
+        }
+        totals
+    }
+
+    /// Request streams received and responses sent since startup.
+    pub(crate) fn stream_totals(&self) -> (usize, usize) {
+        let live = self.live.lock().unwrap();
+        let totals = self.totals(&live);
+        (totals.streams_received, totals.responses_sent)
+    }
+
+    fn report(&self, start_time: Instant) -> StatsReport {
+        let live = self.live.lock().unwrap();
+        let (reset_at, baseline) = *self.reset.lock().unwrap();
+        let mut connections: Vec<_> = live
+            .iter()
+            .map(|(id, live)| {
+                let transport = live.connection.stats();
+                ConnectionReport {
+                    id: *id,
+                    remote: live.connection.remote_address(),
+                    run_id: live.run_id.clone(),
+                    client_identity: live.client_identity.clone(),
+                    client_pubkey: live.client_pubkey.clone(),
+                    age_secs: live.connected_at.elapsed().as_secs_f64(),
+                    streams_received: live.counters.streams_received.load(Ordering::Relaxed),
+                    bytes_received: live.counters.bytes_received.load(Ordering::Relaxed),
+                    responses_sent: live.counters.responses_sent.load(Ordering::Relaxed),
+                    streams_timed_out: live.counters.streams_timed_out.load(Ordering::Relaxed),
+                    datagram_fallbacks: live.counters.datagram_fallbacks.load(Ordering::Relaxed),
+                    rtt_us: transport.path.rtt.as_micros(),
+                    cwnd: transport.path.cwnd,
+                    lost_packets: transport.path.lost_packets,
+                    udp_tx_bytes: transport.udp_tx.bytes,
+                    udp_rx_bytes: transport.udp_rx.bytes,
+                }
+            })
+            .collect();
+        connections.sort_by_key(|connection| connection.id);
+        StatsReport {
+            uptime_secs: start_time.elapsed().as_secs_f64(),
+            since_reset_secs: reset_at.elapsed().as_secs_f64(),
+            totals: self.totals(&live).since(&baseline),
+            connections,
+        }
+    }
+
+    fn reset(&self) {
+        let live = self.live.lock().unwrap();
+        *self.reset.lock().unwrap() = (Instant::now(), self.totals(&live));
+    }
+}
+
+/// Serve the API on `addr`.
+pub(crate) async fn run_stats_api(addr: SocketAddr, stats: Arc<ServerStats>) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Stats API listening on {}", listener.local_addr()?);
+
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_request(socket, &stats).await {
+                debug!("Stats API request from {peer} failed: {err:?}");
+            }
+        });
+    }
+}
+
+async fn handle_request(mut socket: TcpStream, stats: &ServerStats) -> Result<()> {
+    let mut buf = [0u8; 1024];
+    let len = socket.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let mut request_line = request
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split_whitespace();
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/stats")) => (
+            "200 OK",
+            serde_json::to_string_pretty(&stats.connections.report(stats.start_time))?,
+        ),
+        (Some("POST"), Some("/reset")) => {
+            stats.connections.reset();
+            info!("Server totals reset through the stats API");
+            ("200 OK", "{\"reset\": true}".to_string())
+        }
+        (Some("POST"), Some("/drain")) => {
+            stats.drain.start("POST /drain");
+            ("200 OK", "{\"draining\": true}".to_string())
+        }
+        (Some("POST"), Some(path)) if path.starts_with("/sample-rate/") => {
+            match path["/sample-rate/".len()..].parse::<u64>() {
+                Ok(rate) => {
+                    packet_log::set_sample_rate(rate);
+                    ("200 OK", format!("{{\"sample_rate\": {rate}}}"))
+                }
+                Err(_) => (
+                    "400 Bad Request",
+                    "{\"error\": \"expected POST /sample-rate/<n>\"}".to_string(),
+                ),
+            }
+        }
+        _ => (
+            "404 Not Found",
+            "{\"error\": \"expected GET /stats, POST /reset, POST /drain or POST \
+             /sample-rate/<n>\"}"
+                .to_string(),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len(),
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
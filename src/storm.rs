@@ -0,0 +1,214 @@
+//! Connection establishment stress: keep a fixed number of handshakes in
+//! flight against the server for a duration, measuring the handshake success
+//! rate and latency distribution. With `--send-0rtt` every connection sends a
+//! first request, as 0-RTT early data whenever a session can be resumed, and
+//! the time to its answer is compared with connections doing a full
+//! handshake first.
+
+use {
+    crate::{control::ControlStream, histogram::Histogram},
+    anyhow::Result,
+    quinn::{Connection, ConnectionError, Endpoint},
+    std::{
+        net::SocketAddr,
+        time::{Duration, Instant},
+    },
+    tokio::task::JoinSet,
+    tracing::*,
+};
+
+/// A successful connection attempt.
+struct Attempt {
+    handshake: Duration,
+    /// Time from the start of the attempt to the answer of the first request,
+    /// with --send-0rtt.
+    first_response: Option<Duration>,
+    /// Whether the server accepted the first request as 0-RTT data, None when
+    /// no session could be resumed.
+    zero_rtt_accepted: Option<bool>,
+}
+
+#[derive(Default)]
+struct StormStats {
+    succeeded: u64,
+    timed_out: u64,
+    refused: u64,
+    version_mismatches: u64,
+    other_errors: u64,
+    latency: Histogram,
+    zero_rtt_accepted: u64,
+    zero_rtt_rejected: u64,
+    /// Connections without a session to resume.
+    full_handshakes: u64,
+    /// Time to the first answer of connections whose 0-RTT data was accepted.
+    first_response_0rtt: Histogram,
+    /// Time to the first answer of the other connections.
+    first_response_1rtt: Histogram,
+}
+
+impl StormStats {
+    fn record(&mut self, result: Result<Attempt, ConnectionError>) {
+        match result {
+            Ok(attempt) => {
+                self.succeeded += 1;
+                self.latency.record_duration(attempt.handshake);
+                match attempt.zero_rtt_accepted {
+                    Some(true) => self.zero_rtt_accepted += 1,
+                    Some(false) => self.zero_rtt_rejected += 1,
+                    None => self.full_handshakes += 1,
+                }
+                if let Some(first_response) = attempt.first_response {
+                    match attempt.zero_rtt_accepted {
+                        Some(true) => &mut self.first_response_0rtt,
+                        _ => &mut self.first_response_1rtt,
+                    }
+                    .record_duration(first_response);
+                }
+            }
+            Err(ConnectionError::TimedOut) => self.timed_out += 1,
+            Err(ConnectionError::VersionMismatch) => self.version_mismatches += 1,
+            Err(ConnectionError::ConnectionClosed(close))
+                if close.error_code == quinn::TransportErrorCode::CONNECTION_REFUSED =>
+            {
+                self.refused += 1
+            }
+            Err(err) => {
+                debug!("Handshake failed: {err}");
+                self.other_errors += 1
+            }
+        }
+    }
+
+    fn attempts(&self) -> u64 {
+        self.succeeded + self.timed_out + self.refused + self.version_mismatches + self.other_errors
+    }
+}
+
+/// Hold `concurrency` connection attempts in flight for `duration`, spreading
+/// them over `endpoints`. Every established connection is closed right away,
+/// or after its first request with `zero_rtt`.
+pub(crate) async fn run_connect_storm(
+    endpoints: &[Endpoint],
+    server_addr: SocketAddr,
+    server_name: &str,
+    concurrency: usize,
+    duration: Duration,
+    zero_rtt: bool,
+) -> Result<()> {
+    info!("Starting connect storm with {concurrency} attempts in flight for {duration:?}");
+    let start = Instant::now();
+    let mut stats = StormStats::default();
+    let mut in_flight = JoinSet::new();
+    let mut next_endpoint = 0;
+
+    loop {
+        while start.elapsed() < duration && in_flight.len() < concurrency {
+            let endpoint = &endpoints[next_endpoint % endpoints.len()];
+            next_endpoint += 1;
+            let connecting = endpoint.connect(server_addr, server_name)?;
+            in_flight.spawn(async move {
+                let attempt_start = Instant::now();
+                if !zero_rtt {
+                    let connection = connecting.await?;
+                    let handshake = attempt_start.elapsed();
+                    connection.close(0u32.into(), b"storm");
+                    return Ok(Attempt {
+                        handshake,
+                        first_response: None,
+                        zero_rtt_accepted: None,
+                    });
+                }
+                let attempt = match connecting.into_0rtt() {
+                    Ok((connection, accepted)) => {
+                        let handshake = async {
+                            let accepted = accepted.await;
+                            (accepted, attempt_start.elapsed())
+                        };
+                        let (first_response, (accepted, handshake)) = tokio::join!(
+                            time_first_response(&connection, attempt_start),
+                            handshake
+                        );
+                        // Rejected early data is not retransmitted, ask again.
+                        let first_response = match first_response {
+                            None if !accepted => {
+                                time_first_response(&connection, attempt_start).await
+                            }
+                            first_response => first_response,
+                        };
+                        connection.close(0u32.into(), b"storm");
+                        Attempt {
+                            handshake,
+                            first_response,
+                            zero_rtt_accepted: Some(accepted),
+                        }
+                    }
+                    Err(connecting) => {
+                        let connection = connecting.await?;
+                        let handshake = attempt_start.elapsed();
+                        let first_response = time_first_response(&connection, attempt_start).await;
+                        connection.close(0u32.into(), b"storm");
+                        Attempt {
+                            handshake,
+                            first_response,
+                            zero_rtt_accepted: None,
+                        }
+                    }
+                };
+                Ok::<_, ConnectionError>(attempt)
+            });
+        }
+        match in_flight.join_next().await {
+            Some(result) => stats.record(result?),
+            None => break,
+        }
+    }
+
+    let elapsed = start.elapsed().as_secs_f64();
+    let attempts = stats.attempts();
+    info!(
+        "Connect storm: {attempts} attempts in {elapsed:.2}s ({:.2} handshakes/sec), \
+         {} succeeded ({:.2}%), {} timed out, {} refused, {} version mismatches, \
+         {} other errors",
+        stats.succeeded as f64 / elapsed,
+        stats.succeeded,
+        stats.succeeded as f64 * 100.0 / attempts.max(1) as f64,
+        stats.timed_out,
+        stats.refused,
+        stats.version_mismatches,
+        stats.other_errors,
+    );
+    info!("Handshake latency: {}", stats.latency);
+    if zero_rtt {
+        info!(
+            "0-RTT: {} accepted, {} rejected, {} full handshakes without a session",
+            stats.zero_rtt_accepted, stats.zero_rtt_rejected, stats.full_handshakes,
+        );
+        info!(
+            "Time to the first answer: {} with 0-RTT, {} without",
+            stats.first_response_0rtt, stats.first_response_1rtt,
+        );
+        if stats.first_response_0rtt.count() > 0 && stats.first_response_1rtt.count() > 0 {
+            let advantage = stats.first_response_1rtt.percentile(50.0) as f64
+                - stats.first_response_0rtt.percentile(50.0) as f64;
+            info!("0-RTT advantage at p50: {advantage:.0}us");
+        }
+    }
+    Ok(())
+}
+
+/// Send a first request on `connection`, a control ping the server answers
+/// before any other setup, returning the time since `start` to its answer.
+async fn time_first_response(connection: &Connection, start: Instant) -> Option<Duration> {
+    let result = async {
+        let mut control = ControlStream::open(connection).await?;
+        control.ping().await?;
+        anyhow::Ok(start.elapsed())
+    };
+    match result.await {
+        Ok(elapsed) => Some(elapsed),
+        Err(err) => {
+            debug!("First request failed: {err:#}");
+            None
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Stream setup and teardown throughput: open uni streams carrying no or a
+//! tiny payload and finish them right away. The payload is shorter than a
+//! request id, so the server does not answer and only its accept loop is
+//! exercised.
+
+use {
+    crate::{histogram::Histogram, requests::REQUEST_ID_LEN},
+    anyhow::{bail, Result},
+    quinn::Connection,
+    std::time::{Duration, Instant},
+    tokio::task::JoinSet,
+    tracing::*,
+};
+
+struct ConnectionResult {
+    opened: usize,
+    /// Time spent waiting in `open_uni` for stream credit from the server.
+    open_wait: Histogram,
+    /// Until the server acknowledged the last stream.
+    completed_after: Option<Duration>,
+}
+
+/// Open and finish `num_streams` streams with `payload_len` bytes on every
+/// connection and report the stream rate.
+pub(crate) async fn run_stream_open(
+    conns: &[(Connection, Span)],
+    num_streams: usize,
+    payload_len: usize,
+) -> Result<()> {
+    if payload_len >= REQUEST_ID_LEN {
+        bail!("stream-open payloads must be shorter than {REQUEST_ID_LEN} bytes");
+    }
+    let payload = vec![0u8; payload_len];
+    let start = Instant::now();
+    let mut tasks = JoinSet::new();
+    for (conn, span) in conns {
+        let conn = conn.clone();
+        let payload = payload.clone();
+        tasks.spawn(
+            async move {
+                let mut result = ConnectionResult {
+                    opened: 0,
+                    open_wait: Histogram::default(),
+                    completed_after: None,
+                };
+                let mut last_stream = None;
+                for _ in 0..num_streams {
+                    let open_start = Instant::now();
+                    let mut stream = match conn.open_uni().await {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            error!("Open stream error {err:?}");
+                            break;
+                        }
+                    };
+                    result.open_wait.record_duration(open_start.elapsed());
+                    if !payload.is_empty() {
+                        if let Err(err) = stream.write_all(&payload).await {
+                            error!("Send stream error {err:?}");
+                            continue;
+                        }
+                    }
+                    let _ = stream.finish();
+                    result.opened += 1;
+                    last_stream = Some(stream);
+                }
+                if let Some(mut stream) = last_stream {
+                    if stream.stopped().await.is_ok() {
+                        result.completed_after = Some(start.elapsed());
+                    }
+                }
+                result
+            }
+            .instrument(span.clone()),
+        );
+    }
+
+    let mut opened = 0;
+    let mut open_wait = Histogram::default();
+    let mut completed_after = Duration::ZERO;
+    let mut incomplete = 0;
+    while let Some(result) = tasks.join_next().await {
+        let result = result?;
+        opened += result.opened;
+        open_wait.merge(&result.open_wait);
+        match result.completed_after {
+            Some(after) => completed_after = completed_after.max(after),
+            None => incomplete += 1,
+        }
+    }
+    let elapsed = start.elapsed();
+    info!(
+        "Opened and finished {opened} streams of {payload_len} bytes in {elapsed:?} \
+         ({:.2} streams/sec)",
+        opened as f64 / elapsed.as_secs_f64()
+    );
+    info!(
+        "All streams acknowledged after {completed_after:?} ({:.2} streams/sec), \
+         {incomplete} connections incomplete",
+        opened as f64 / completed_after.as_secs_f64().max(f64::EPSILON)
+    );
+    info!("Stream open wait: {open_wait}");
+    Ok(())
+}
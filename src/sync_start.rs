@@ -0,0 +1,27 @@
+//! Synchronized start with `--start-at`: independently launched clients and
+//! servers, on one host or many, wait for the same wall clock instant before
+//! their measured windows begin, so their interval time series line up
+//! without shifting them afterwards. The alignment is as good as the clock
+//! synchronization of the hosts.
+
+use {
+    std::time::{Duration, SystemTime, UNIX_EPOCH},
+    tokio::time,
+    tracing::*,
+};
+
+/// Sleep until `unix_secs` seconds after the epoch, right away if that has
+/// passed.
+pub(crate) async fn wait_until(unix_secs: f64, role: &str) {
+    let at = UNIX_EPOCH + Duration::from_secs_f64(unix_secs.max(0.0));
+    match at.duration_since(SystemTime::now()) {
+        Ok(wait) => {
+            info!("{role} starting at {unix_secs:.3}, in {wait:?}");
+            time::sleep(wait).await;
+        }
+        Err(err) => warn!(
+            "{role} start time {unix_secs:.3} passed {:?} ago, starting now",
+            err.duration()
+        ),
+    }
+}
@@ -0,0 +1,99 @@
+//! Per destination concurrency caps with `--max-inflight-per-target`: the
+//! requests awaiting a response over all connections to one server address
+//! are held below the cap, so a single aggressive client can not pile onto a
+//! struggling server. Senders wait for the cap before starting a request, the
+//! cap is soft by at most one request per sender. The utilization of the cap
+//! is sampled like the in flight gauge and reported per target.
+
+use {
+    crate::requests::InFlightGauge,
+    std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    },
+    tracing::*,
+};
+
+struct Target {
+    addr: SocketAddr,
+    in_flight: Arc<InFlightGauge>,
+    /// Requests which had to wait for the cap.
+    waited: AtomicUsize,
+}
+
+pub(crate) struct TargetLimits {
+    limit: usize,
+    targets: Vec<Target>,
+    /// Index into `targets` of every connection.
+    of_connection: Vec<usize>,
+}
+
+impl TargetLimits {
+    /// Cap the requests in flight to each of the addresses of `connections`
+    /// at `limit`.
+    pub(crate) fn new(limit: usize, connections: impl IntoIterator<Item = SocketAddr>) -> Self {
+        let mut targets: Vec<Target> = Vec::new();
+        let of_connection = connections
+            .into_iter()
+            .map(
+                |addr| match targets.iter().position(|target| target.addr == addr) {
+                    Some(index) => index,
+                    None => {
+                        targets.push(Target {
+                            addr,
+                            in_flight: Arc::default(),
+                            waited: AtomicUsize::new(0),
+                        });
+                        targets.len() - 1
+                    }
+                },
+            )
+            .collect();
+        Self {
+            limit,
+            targets,
+            of_connection,
+        }
+    }
+
+    /// The in flight gauge of the target of connection `connection`.
+    pub(crate) fn gauge(&self, connection: usize) -> Arc<InFlightGauge> {
+        self.targets[self.of_connection[connection]]
+            .in_flight
+            .clone()
+    }
+
+    /// Wait until the target of connection `connection` is below the cap.
+    pub(crate) async fn wait(&self, connection: usize) {
+        let target = &self.targets[self.of_connection[connection]];
+        if target.in_flight.current() >= self.limit {
+            target.waited.fetch_add(1, Ordering::Relaxed);
+            target.in_flight.wait_below(self.limit).await;
+        }
+    }
+
+    pub(crate) fn sample(&self) {
+        self.targets
+            .iter()
+            .for_each(|target| target.in_flight.sample());
+    }
+
+    pub(crate) fn report(&self) {
+        for target in &self.targets {
+            let average = target.in_flight.average();
+            info!(
+                "Target {}: {:.1} requests in flight on average of the cap of {} ({:.1}% \
+                 utilization), high-water mark {}, {} requests waited for the cap",
+                target.addr,
+                average,
+                self.limit,
+                average * 100.0 / self.limit as f64,
+                target.in_flight.high_water_mark(),
+                target.waited.load(Ordering::Relaxed),
+            );
+        }
+    }
+}
@@ -0,0 +1,326 @@
+//! Negative path TLS checks with `--tls-negative`: a local server presents a
+//! deliberately broken certificate to a client verifying it against a test
+//! CA, and every handshake has to fail with the error category the case
+//! expects. A valid certificate from the same CA is tried first, so a failing
+//! setup is not mistaken for the expected failure. The failure categories of
+//! all handshakes are counted.
+
+use {
+    anyhow::{bail, Context, Error, Result},
+    quinn::{
+        crypto::rustls::{QuicClientConfig, QuicServerConfig},
+        ClientConfig, ConnectionError, Endpoint, ServerConfig, TransportErrorCode,
+    },
+    rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair},
+    rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+        AlertDescription, RootCertStore,
+    },
+    serde::Serialize,
+    std::{
+        fmt,
+        net::{Ipv4Addr, SocketAddr},
+        str::FromStr,
+        sync::Arc,
+        time::{Duration, Instant},
+    },
+    tokio::time,
+    tracing::*,
+};
+
+/// Handshakes attempted per case.
+const ATTEMPTS: usize = 3;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const SERVER_NAME: &str = "localhost";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum TlsNegativeCase {
+    /// A certificate from the trusted CA whose validity ended.
+    Expired,
+    /// A certificate from the trusted CA for another name.
+    WrongSan,
+    /// A self-signed certificate while the client verifies against the CA.
+    Untrusted,
+}
+
+impl TlsNegativeCase {
+    fn expected(self) -> HandshakeFailure {
+        match self {
+            TlsNegativeCase::Expired => HandshakeFailure::Expired,
+            TlsNegativeCase::WrongSan => HandshakeFailure::BadCertificate,
+            TlsNegativeCase::Untrusted => HandshakeFailure::UnknownIssuer,
+        }
+    }
+}
+
+impl fmt::Display for TlsNegativeCase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            TlsNegativeCase::Expired => "expired",
+            TlsNegativeCase::WrongSan => "wrong-san",
+            TlsNegativeCase::Untrusted => "untrusted",
+        })
+    }
+}
+
+impl FromStr for TlsNegativeCase {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "expired" => Ok(TlsNegativeCase::Expired),
+            "wrong-san" => Ok(TlsNegativeCase::WrongSan),
+            "untrusted" => Ok(TlsNegativeCase::Untrusted),
+            _ => bail!(
+                "unknown TLS negative case {s:?}, expected \"expired\", \"wrong-san\" or \
+                 \"untrusted\""
+            ),
+        }
+    }
+}
+
+/// Outcome category of a handshake, from the TLS alert the client raised.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HandshakeFailure {
+    /// The handshake succeeded.
+    None,
+    Expired,
+    /// Rejected certificate, as for a name it is not valid for.
+    BadCertificate,
+    UnknownIssuer,
+    /// Another TLS alert.
+    OtherAlert(u8),
+    TimedOut,
+    Other,
+}
+
+impl HandshakeFailure {
+    fn of(err: &ConnectionError) -> Self {
+        let code = match err {
+            ConnectionError::TransportError(err) => err.code,
+            ConnectionError::ConnectionClosed(close) => close.error_code,
+            ConnectionError::TimedOut => return HandshakeFailure::TimedOut,
+            _ => return HandshakeFailure::Other,
+        };
+        let alert = |description: AlertDescription| {
+            TransportErrorCode::crypto(u8::from(description)) == code
+        };
+        if alert(AlertDescription::CertificateExpired) {
+            HandshakeFailure::Expired
+        } else if alert(AlertDescription::BadCertificate) {
+            HandshakeFailure::BadCertificate
+        } else if alert(AlertDescription::UnknownCA) {
+            HandshakeFailure::UnknownIssuer
+        } else {
+            match u64::from(code).checked_sub(0x100) {
+                Some(alert) if alert <= 0xff => HandshakeFailure::OtherAlert(alert as u8),
+                _ => HandshakeFailure::Other,
+            }
+        }
+    }
+}
+
+impl fmt::Display for HandshakeFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HandshakeFailure::None => f.write_str("succeeded"),
+            HandshakeFailure::Expired => f.write_str("certificate expired"),
+            HandshakeFailure::BadCertificate => f.write_str("bad certificate"),
+            HandshakeFailure::UnknownIssuer => f.write_str("unknown issuer"),
+            HandshakeFailure::OtherAlert(alert) => write!(f, "TLS alert {alert}"),
+            HandshakeFailure::TimedOut => f.write_str("timed out"),
+            HandshakeFailure::Other => f.write_str("other error"),
+        }
+    }
+}
+
+/// The test CA trusted by the client.
+struct TestCa {
+    cert: rcgen::Certificate,
+    key: KeyPair,
+}
+
+impl TestCa {
+    fn generate() -> Result<Self> {
+        let mut params = CertificateParams::new(Vec::<String>::new())?;
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "quic_bidir_test negative path CA");
+        let key = KeyPair::generate()?;
+        let cert = params.self_signed(&key)?;
+        Ok(Self { cert, key })
+    }
+
+    fn roots(&self) -> Result<RootCertStore> {
+        let mut roots = RootCertStore::empty();
+        roots.add(self.cert.der().clone())?;
+        Ok(roots)
+    }
+
+    /// A certificate for `name`, issued by the CA unless `self_signed`.
+    fn issue(
+        &self,
+        name: &str,
+        expired: bool,
+        self_signed: bool,
+    ) -> Result<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+        let mut params = CertificateParams::new(vec![name.to_string()])?;
+        if expired {
+            params.not_before = rcgen::date_time_ymd(2000, 1, 1);
+            params.not_after = rcgen::date_time_ymd(2001, 1, 1);
+        }
+        let key = KeyPair::generate()?;
+        let cert = if self_signed {
+            params.self_signed(&key)?
+        } else {
+            params.signed_by(&key, &self.cert, &self.key)?
+        };
+        Ok((
+            cert.der().clone(),
+            PrivatePkcs8KeyDer::from(key.serialize_der()).into(),
+        ))
+    }
+}
+
+/// Count handshakes per failure category.
+#[derive(Default)]
+struct FailureCounts(Vec<(HandshakeFailure, usize)>);
+
+impl FailureCounts {
+    fn add(&mut self, failure: HandshakeFailure) {
+        match self.0.iter_mut().find(|(known, _)| *known == failure) {
+            Some((_, count)) => *count += 1,
+            None => self.0.push((failure, 1)),
+        }
+    }
+}
+
+/// Run the baseline and every case in `cases`, logging whether each failed
+/// as expected. Returns an error if any did not.
+pub(crate) async fn run_cases(cases: &[TlsNegativeCase]) -> Result<()> {
+    let ca = TestCa::generate()?;
+    let client_config = client_config(ca.roots()?)?;
+    let mut counts = FailureCounts::default();
+    let mut unexpected = 0;
+
+    let baseline = ca.issue(SERVER_NAME, false, false)?;
+    let outcomes = attempt(baseline, &client_config).await?;
+    outcomes.iter().for_each(|outcome| counts.add(*outcome));
+    if outcomes
+        .iter()
+        .any(|outcome| *outcome != HandshakeFailure::None)
+    {
+        bail!(
+            "Handshakes with a valid certificate failed: {outcomes:?}, the negative cases \
+             would prove nothing"
+        );
+    }
+    info!("TLS baseline: {ATTEMPTS} handshakes with a valid certificate succeeded");
+
+    for case in cases {
+        let (cert, key) = match case {
+            TlsNegativeCase::Expired => ca.issue(SERVER_NAME, true, false)?,
+            TlsNegativeCase::WrongSan => ca.issue("wrong-name.invalid", false, false)?,
+            TlsNegativeCase::Untrusted => ca.issue(SERVER_NAME, false, true)?,
+        };
+        let expected = case.expected();
+        let outcomes = attempt((cert, key), &client_config).await?;
+        outcomes.iter().for_each(|outcome| counts.add(*outcome));
+        let mismatches = outcomes
+            .iter()
+            .filter(|outcome| **outcome != expected)
+            .count();
+        if mismatches == 0 {
+            info!("TLS negative case {case}: all {ATTEMPTS} handshakes failed with {expected}");
+        } else {
+            unexpected += mismatches;
+            error!(
+                "TLS negative case {case}: expected {expected}, got {}",
+                outcomes
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+    }
+
+    for (failure, count) in &counts.0 {
+        info!("Handshakes {failure}: {count}");
+    }
+    if unexpected > 0 {
+        bail!("{unexpected} handshakes did not fail as expected");
+    }
+    Ok(())
+}
+
+fn client_config(roots: RootCertStore) -> Result<ClientConfig> {
+    let mut crypto = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(&[&rustls::version::TLS13])?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    crypto.alpn_protocols = vec![b"perf".to_vec()];
+    Ok(ClientConfig::new(Arc::new(QuicClientConfig::try_from(
+        crypto,
+    )?)))
+}
+
+/// Serve `cert` on a local endpoint and attempt `ATTEMPTS` handshakes against
+/// it, returning how each ended.
+async fn attempt(
+    (cert, key): (CertificateDer<'static>, PrivateKeyDer<'static>),
+    client_config: &ClientConfig,
+) -> Result<Vec<HandshakeFailure>> {
+    let mut crypto = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(&[&rustls::version::TLS13])?
+    .with_no_client_auth()
+    .with_single_cert(vec![cert], key)?;
+    crypto.alpn_protocols = vec![b"perf".to_vec()];
+    let server_config = ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?));
+    let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    let server = Endpoint::server(server_config, localhost)?;
+    let server_addr = server.local_addr()?;
+    let acceptor = {
+        let server = server.clone();
+        tokio::spawn(async move {
+            while let Some(incoming) = server.accept().await {
+                tokio::spawn(async move {
+                    if let Ok(connection) = incoming.await {
+                        connection.closed().await;
+                    }
+                });
+            }
+        })
+    };
+
+    let client = Endpoint::client(localhost)?;
+    let mut outcomes = Vec::with_capacity(ATTEMPTS);
+    for _ in 0..ATTEMPTS {
+        let start = Instant::now();
+        let connecting = client
+            .connect_with(client_config.clone(), server_addr, SERVER_NAME)
+            .context("starting a handshake")?;
+        let outcome = match time::timeout(HANDSHAKE_TIMEOUT, connecting).await {
+            Ok(Ok(connection)) => {
+                connection.close(0u32.into(), b"done");
+                HandshakeFailure::None
+            }
+            Ok(Err(err)) => {
+                debug!("Handshake failed after {:?}: {err}", start.elapsed());
+                HandshakeFailure::of(&err)
+            }
+            Err(_) => HandshakeFailure::TimedOut,
+        };
+        outcomes.push(outcome);
+    }
+    client.close(0u32.into(), b"done");
+    server.close(0u32.into(), b"done");
+    acceptor.abort();
+    Ok(outcomes)
+}
@@ -0,0 +1,110 @@
+//! Per-message trace log: for a sampled subset of requests, record when the
+//! send started, when the write completed and when the response arrived, as
+//! JSON lines with wall clock timestamps for offline analysis.
+
+use {
+    anyhow::{bail, Result},
+    std::{
+        fs::File,
+        io::{BufWriter, Write},
+        path::Path,
+        sync::mpsc::{self, RecvTimeoutError},
+        thread,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+    tokio::sync::oneshot,
+    tracing::*,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum TraceEvent {
+    SendStart,
+    WriteComplete,
+    Response,
+}
+
+impl TraceEvent {
+    fn name(self) -> &'static str {
+        match self {
+            TraceEvent::SendStart => "send_start",
+            TraceEvent::WriteComplete => "write_complete",
+            TraceEvent::Response => "response",
+        }
+    }
+}
+
+enum Command {
+    Line(String),
+    Flush(oneshot::Sender<()>),
+}
+
+pub(crate) struct MessageTracer {
+    sender: mpsc::Sender<Command>,
+    /// Every `every`th request id is traced.
+    every: u64,
+}
+
+impl MessageTracer {
+    /// Trace roughly `fraction` of the requests into `path`. The file is
+    /// written by a dedicated thread so the hot path only pays for a channel
+    /// send on sampled requests.
+    pub(crate) fn create(path: &Path, fraction: f64) -> Result<Self> {
+        if !(fraction > 0.0 && fraction <= 1.0) {
+            bail!("trace sample fraction {fraction} is not in (0, 1]");
+        }
+        let mut writer = BufWriter::new(File::create(path)?);
+        let (sender, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("trace-writer".to_string())
+            .spawn(move || loop {
+                match receiver.recv_timeout(Duration::from_millis(100)) {
+                    Ok(Command::Line(line)) => {
+                        if let Err(err) = writer.write_all(line.as_bytes()) {
+                            error!("Failed to write trace file: {err}");
+                            return;
+                        }
+                    }
+                    Ok(Command::Flush(done)) => {
+                        let _ = writer.flush();
+                        let _ = done.send(());
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        let _ = writer.flush();
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        let _ = writer.flush();
+                        return;
+                    }
+                }
+            })?;
+        info!("Tracing {fraction} of the requests to {}", path.display());
+        Ok(Self {
+            sender,
+            every: (1.0 / fraction).round() as u64,
+        })
+    }
+
+    pub(crate) fn sampled(&self, request_id: u64) -> bool {
+        request_id.is_multiple_of(self.every)
+    }
+
+    pub(crate) fn record(&self, connection_id: usize, request_id: u64, event: TraceEvent) {
+        let timestamp_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros();
+        let line = format!(
+            "{{\"ts_us\":{timestamp_us},\"conn\":{connection_id},\"req\":{request_id},\"event\":\"{}\"}}\n",
+            event.name()
+        );
+        let _ = self.sender.send(Command::Line(line));
+    }
+
+    /// Wait until everything recorded so far is written out.
+    pub(crate) async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        if self.sender.send(Command::Flush(done)).is_ok() {
+            let _ = flushed.await;
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! Per interval deltas of the quinn transport counters of every client
+//! connection, so transient loss episodes line up with the latency spikes of
+//! the interval time series. quinn does not count probe timeouts; the PING
+//! frames sent, which PTO probes and keep-alives consist of, stand in for
+//! them.
+//!
+//! Congestion events include the reactions to ECN-CE marks: quinn negotiates
+//! ECN on its own wherever the socket supports it, but 0.11 neither exposes
+//! the CE counts separately nor allows turning ECN off.
+
+use {quinn::Connection, serde::Serialize, std::fmt, tracing::*};
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub(crate) struct TransportEvents {
+    packets_sent: u64,
+    packets_lost: u64,
+    congestion_events: u64,
+    black_holes: u64,
+    pings_sent: u64,
+}
+
+impl TransportEvents {
+    fn of(connection: &Connection) -> Self {
+        let stats = connection.stats();
+        Self {
+            packets_sent: stats.path.sent_packets,
+            packets_lost: stats.path.lost_packets,
+            congestion_events: stats.path.congestion_events,
+            black_holes: stats.path.black_holes_detected,
+            pings_sent: stats.frame_tx.ping,
+        }
+    }
+
+    fn since(&self, earlier: &Self) -> Self {
+        Self {
+            packets_sent: self.packets_sent - earlier.packets_sent,
+            packets_lost: self.packets_lost - earlier.packets_lost,
+            congestion_events: self.congestion_events - earlier.congestion_events,
+            black_holes: self.black_holes - earlier.black_holes,
+            pings_sent: self.pings_sent - earlier.pings_sent,
+        }
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.packets_sent += other.packets_sent;
+        self.packets_lost += other.packets_lost;
+        self.congestion_events += other.congestion_events;
+        self.black_holes += other.black_holes;
+        self.pings_sent += other.pings_sent;
+    }
+}
+
+impl fmt::Display for TransportEvents {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} packets sent, {} lost, {} congestion events, {} black holes, {} PINGs sent",
+            self.packets_sent,
+            self.packets_lost,
+            self.congestion_events,
+            self.black_holes,
+            self.pings_sent,
+        )
+    }
+}
+
+/// The counters of every connection at the end of the previous interval.
+pub(crate) struct TransportEventTracker {
+    last: Vec<TransportEvents>,
+}
+
+impl TransportEventTracker {
+    pub(crate) fn new(connections: &[Connection]) -> Self {
+        Self {
+            last: connections.iter().map(TransportEvents::of).collect(),
+        }
+    }
+
+    /// Log the events of every connection of `connections` since the last
+    /// interval and return their sum.
+    pub(crate) fn interval(&mut self, connections: &[Connection]) -> TransportEvents {
+        let mut total = TransportEvents::default();
+        for (connection, last) in connections.iter().zip(&mut self.last) {
+            let now = TransportEvents::of(connection);
+            let events = now.since(last);
+            *last = now;
+            info!("Connection {}: {events}", connection.stable_id());
+            total.add(&events);
+        }
+        total
+    }
+}
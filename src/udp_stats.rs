@@ -0,0 +1,123 @@
+//! Receive batching statistics of the server sockets: how many datagrams
+//! every socket wakeup delivers, through recvmmsg batches of messages and GRO
+//! segments per message where the platform supports them.
+
+use {
+    quinn::{
+        udp::{RecvMeta, Transmit},
+        AsyncUdpSocket, UdpPoller,
+    },
+    std::{
+        fmt,
+        io::{self, IoSliceMut},
+        net::SocketAddr,
+        pin::Pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context, Poll},
+    },
+};
+
+/// Counts shared by the sockets of all server endpoints.
+#[derive(Debug, Default)]
+pub(crate) struct RecvBatchStats {
+    /// Receive calls which returned data.
+    wakeups: AtomicUsize,
+    /// Messages returned, more than one per wakeup with recvmmsg.
+    messages: AtomicUsize,
+    /// Datagrams in the messages, more than one per message with GRO.
+    datagrams: AtomicUsize,
+}
+
+impl RecvBatchStats {
+    /// Return the counts since the last call, resetting them.
+    pub(crate) fn take(&self) -> RecvBatchSnapshot {
+        RecvBatchSnapshot {
+            wakeups: self.wakeups.swap(0, Ordering::Relaxed),
+            messages: self.messages.swap(0, Ordering::Relaxed),
+            datagrams: self.datagrams.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+pub(crate) struct RecvBatchSnapshot {
+    wakeups: usize,
+    messages: usize,
+    datagrams: usize,
+}
+
+impl fmt::Display for RecvBatchSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} datagrams in {} messages over {} wakeups, {:.2} datagrams per wakeup, \
+             {:.2} per message",
+            self.datagrams,
+            self.messages,
+            self.wakeups,
+            self.datagrams as f64 / self.wakeups.max(1) as f64,
+            self.datagrams as f64 / self.messages.max(1) as f64,
+        )
+    }
+}
+
+/// Socket passing everything through to the runtime's socket while counting
+/// what each receive call returns.
+#[derive(Debug)]
+pub(crate) struct CountingSocket {
+    inner: Arc<dyn AsyncUdpSocket>,
+    stats: Arc<RecvBatchStats>,
+}
+
+impl CountingSocket {
+    pub(crate) fn new(inner: Arc<dyn AsyncUdpSocket>, stats: Arc<RecvBatchStats>) -> Self {
+        Self { inner, stats }
+    }
+}
+
+impl AsyncUdpSocket for CountingSocket {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+        self.inner.clone().create_io_poller()
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+        self.inner.try_send(transmit)
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let result = self.inner.poll_recv(cx, bufs, meta);
+        if let Poll::Ready(Ok(messages)) = &result {
+            let datagrams: usize = meta[..*messages]
+                .iter()
+                .map(|meta| meta.len.div_ceil(meta.stride.max(1)))
+                .sum();
+            self.stats.wakeups.fetch_add(1, Ordering::Relaxed);
+            self.stats.messages.fetch_add(*messages, Ordering::Relaxed);
+            self.stats.datagrams.fetch_add(datagrams, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn max_transmit_segments(&self) -> usize {
+        self.inner.max_transmit_segments()
+    }
+
+    fn max_receive_segments(&self) -> usize {
+        self.inner.max_receive_segments()
+    }
+
+    fn may_fragment(&self) -> bool {
+        self.inner.may_fragment()
+    }
+}
@@ -0,0 +1,62 @@
+//! Event loop watchdog: a task sleeping on a short period measures how late
+//! the runtime wakes it up. Large delays mean the executor stalled, which
+//! would otherwise show up as network latency.
+
+use {
+    crate::histogram::Histogram,
+    std::{
+        mem,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, Instant},
+    },
+    tokio::{task::JoinHandle, time},
+    tracing::*,
+};
+
+const TICK: Duration = Duration::from_millis(10);
+
+/// Scheduling delays observed since the last `take`.
+pub(crate) struct SchedulerDelay {
+    delay: Mutex<Histogram>,
+    stalls: AtomicUsize,
+}
+
+impl SchedulerDelay {
+    /// Return the delay histogram and the number of stalls above the
+    /// threshold, resetting both.
+    pub(crate) fn take(&self) -> (Histogram, usize) {
+        let delay = mem::take(&mut *self.delay.lock().unwrap());
+        (delay, self.stalls.swap(0, Ordering::Relaxed))
+    }
+}
+
+/// Start the watchdog on the current runtime, warning about every wake up
+/// later than `threshold`. `role` names the runtime in the warnings.
+pub(crate) fn spawn(
+    role: &'static str,
+    threshold: Duration,
+) -> (Arc<SchedulerDelay>, JoinHandle<()>) {
+    let delay = Arc::new(SchedulerDelay {
+        delay: Mutex::default(),
+        stalls: AtomicUsize::default(),
+    });
+    let task = tokio::spawn({
+        let delay = delay.clone();
+        async move {
+            loop {
+                let deadline = Instant::now() + TICK;
+                time::sleep(TICK).await;
+                let late = Instant::now().saturating_duration_since(deadline);
+                delay.delay.lock().unwrap().record_duration(late);
+                if late > threshold {
+                    delay.stalls.fetch_add(1, Ordering::Relaxed);
+                    warn!("{role} runtime stalled: timer fired {late:?} late");
+                }
+            }
+        }
+    });
+    (delay, task)
+}